@@ -0,0 +1,752 @@
+//! Derive macros for the `Stashable`, `Unstashable` and `UnstashableInplace`
+//! traits of the `hashstash` crate. These are re-exported by `hashstash`
+//! when its `derive` feature is enabled.
+//!
+//! Each field is stashed and unstashed in declaration order using the
+//! [Stasher] method that best matches its type:
+//!
+//! - `bool`, `u8`, `i8`, `u16`, `i16`, `u32`, `i32`, `u64`, `i64`, `u128`,
+//!   `i128`, `usize`, `isize`, `f32`, `f64` and `char` are stashed as
+//!   primitives
+//! - `String` is stashed as a string
+//! - `Vec`s of numeric primitives are stashed as arrays of primitives
+//! - Other `Vec`s are stashed as ordered arrays of objects
+//! - Everything else is stashed as a single object
+//!
+//! Enums are stashed as a `u32` tag holding the variant's index, followed
+//! by the variant's fields.
+//!
+//! The following attributes are supported:
+//!
+//! - `#[stash(context = Type)]` on the struct or enum implements the traits
+//!   for the context type `Type` instead of `()`
+//! - `#[stash(type_name = "name")]` on the struct or enum stashes it
+//!   with the given type name and checks the name when unstashing. See
+//!   `Stasher::type_name`.
+//! - `#[stash(skip)]` on a field excludes it from stashing. Unstashing
+//!   creates it using [Default] and unstashing in place leaves it untouched.
+//! - `#[stash(unordered)]` on a field stashes it as an unordered array of
+//!   objects. This can be used with `Vec`, or any other collection which can
+//!   be iterated over with `.iter()` and created with [FromIterator],
+//!   except for `HashMap` and `BTreeMap`.
+//! - `#[stash(context = expr)]` on a field stashes and unstashes its objects
+//!   using the `_with_context` variants, with the given expression as the
+//!   context. Within the expression, `context` refers to the current context.
+//!
+//! [Stasher]: https://docs.rs/hashstash/latest/hashstash/struct.Stasher.html
+
+use proc_macro2::{Span, TokenStream};
+use quote::{format_ident, quote};
+use syn::{
+    parse_macro_input, spanned::Spanned, Data, DeriveInput, Expr, Fields, GenericArgument,
+    Generics, Ident, Index, PathArguments, Type,
+};
+
+/// Derive `hashstash::Stashable`. See the crate documentation for details.
+#[proc_macro_derive(Stashable, attributes(stash))]
+pub fn derive_stashable(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_stashable(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Derive `hashstash::Unstashable`. See the crate documentation for details.
+#[proc_macro_derive(Unstashable, attributes(stash))]
+pub fn derive_unstashable(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_unstashable(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Derive `hashstash::UnstashableInplace`. See the crate documentation for details.
+#[proc_macro_derive(UnstashableInplace, attributes(stash))]
+pub fn derive_unstashable_inplace(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand_unstashable_inplace(&input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Attributes on the struct or enum itself
+struct ContainerAttributes {
+    /// The context type to implement the traits for
+    context: Type,
+
+    /// The type name to stash and check, if any
+    type_name: Option<syn::LitStr>,
+}
+
+impl ContainerAttributes {
+    fn parse(input: &DeriveInput) -> syn::Result<ContainerAttributes> {
+        let mut context: Type = syn::parse_quote!(());
+        let mut type_name = None;
+        for attr in &input.attrs {
+            if !attr.path().is_ident("stash") {
+                continue;
+            }
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("context") {
+                    context = meta.value()?.parse()?;
+                    Ok(())
+                } else if meta.path.is_ident("type_name") {
+                    type_name = Some(meta.value()?.parse()?);
+                    Ok(())
+                } else {
+                    Err(meta.error("unsupported stash attribute"))
+                }
+            })?;
+        }
+        Ok(ContainerAttributes { context, type_name })
+    }
+
+    /// Generate the statement which stashes the type name, if any
+    fn stash_type_name(&self) -> TokenStream {
+        match &self.type_name {
+            Some(type_name) => quote! { stasher.type_name(#type_name); },
+            None => quote! {},
+        }
+    }
+
+    /// Generate the statement which checks the type name, if any
+    fn check_type_name(&self) -> TokenStream {
+        match &self.type_name {
+            Some(type_name) => quote! { unstasher.type_name(#type_name)?; },
+            None => quote! {},
+        }
+    }
+}
+
+/// How a field's value is stashed, as determined from its type and attributes
+enum FieldKind {
+    /// A primitive, with the name of the matching Stasher method
+    Primitive(Ident),
+
+    /// A String
+    String,
+
+    /// A Vec of primitives, with the name of the primitive type
+    PrimitiveVec(Ident),
+
+    /// A Vec of objects
+    ObjectVec,
+
+    /// Any collection of objects which is stashed in no particular order,
+    /// with the type of its items
+    UnorderedCollection(Type),
+
+    /// Any other object
+    Object,
+}
+
+/// A single field of a struct or enum variant
+struct FieldInfo {
+    /// The name of the field, or its index for tuple structs
+    member: syn::Member,
+
+    /// The name of the binding used when matching on enum variants
+    binding: Ident,
+
+    kind: FieldKind,
+    skip: bool,
+    ordered: bool,
+    context: Option<Expr>,
+}
+
+/// Get the last path segment's identifier and generic arguments
+/// of a type, if it is a plain path
+fn last_segment(ty: &Type) -> Option<&syn::PathSegment> {
+    match ty {
+        Type::Path(type_path) if type_path.qself.is_none() => type_path.path.segments.last(),
+        _ => None,
+    }
+}
+
+/// Get the first generic type argument of a type, e.g. `T` for `Vec<T>`
+fn first_type_argument(ty: &Type) -> Option<&Type> {
+    let segment = last_segment(ty)?;
+    let PathArguments::AngleBracketed(args) = &segment.arguments else {
+        return None;
+    };
+    args.args.iter().find_map(|arg| match arg {
+        GenericArgument::Type(ty) => Some(ty),
+        _ => None,
+    })
+}
+
+/// Returns the name of the primitive type if the type is one
+fn primitive_name(ty: &Type) -> Option<Ident> {
+    const PRIMITIVES: [&str; 16] = [
+        "bool", "u8", "i8", "u16", "i16", "u32", "i32", "u64", "i64", "u128", "i128", "usize",
+        "isize", "f32", "f64", "char",
+    ];
+    let segment = last_segment(ty)?;
+    if !segment.arguments.is_empty() {
+        return None;
+    }
+    let name = segment.ident.to_string();
+    if PRIMITIVES.contains(&name.as_str()) {
+        Some(segment.ident.clone())
+    } else {
+        None
+    }
+}
+
+/// Returns true if the type is a primitive which can be stored in a
+/// primitive array, i.e. not bool, usize or isize
+fn is_array_primitive(name: &Ident) -> bool {
+    !matches!(name.to_string().as_str(), "bool" | "usize" | "isize")
+}
+
+impl FieldInfo {
+    fn parse(index: usize, field: &syn::Field) -> syn::Result<FieldInfo> {
+        let member = match &field.ident {
+            Some(ident) => syn::Member::Named(ident.clone()),
+            None => syn::Member::Unnamed(Index::from(index)),
+        };
+        let binding = match &field.ident {
+            Some(ident) => format_ident!("__hashstash_{}", ident),
+            None => format_ident!("__hashstash_{}", index),
+        };
+
+        let mut skip = false;
+        let mut unordered = false;
+        let mut context: Option<Expr> = None;
+        for attr in &field.attrs {
+            if !attr.path().is_ident("stash") {
+                continue;
+            }
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("skip") {
+                    skip = true;
+                    Ok(())
+                } else if meta.path.is_ident("unordered") {
+                    unordered = true;
+                    Ok(())
+                } else if meta.path.is_ident("context") {
+                    context = Some(meta.value()?.parse()?);
+                    Ok(())
+                } else {
+                    Err(meta.error("unsupported stash attribute"))
+                }
+            })?;
+        }
+
+        let ty = &field.ty;
+        let is_vec = last_segment(ty).is_some_and(|s| s.ident == "Vec");
+        let is_string = last_segment(ty).is_some_and(|s| s.ident == "String");
+        let is_map =
+            last_segment(ty).is_some_and(|s| s.ident == "HashMap" || s.ident == "BTreeMap");
+
+        let kind = if unordered {
+            if is_map {
+                return Err(syn::Error::new(
+                    ty.span(),
+                    "unordered can't be used with maps, whose items are key-value pairs",
+                ));
+            }
+            match first_type_argument(ty) {
+                Some(item) => FieldKind::UnorderedCollection(item.clone()),
+                None => {
+                    return Err(syn::Error::new(
+                        ty.span(),
+                        "unordered fields must be collections with an item type argument",
+                    ))
+                }
+            }
+        } else if let Some(name) = primitive_name(ty) {
+            FieldKind::Primitive(name)
+        } else if is_string {
+            FieldKind::String
+        } else if is_vec {
+            match first_type_argument(ty) {
+                Some(item) => match primitive_name(item) {
+                    Some(name) if is_array_primitive(&name) => FieldKind::PrimitiveVec(name),
+                    _ => FieldKind::ObjectVec,
+                },
+                None => FieldKind::Object,
+            }
+        } else {
+            FieldKind::Object
+        };
+
+        let takes_context = matches!(
+            kind,
+            FieldKind::ObjectVec | FieldKind::UnorderedCollection(_) | FieldKind::Object
+        );
+        if context.is_some() && !takes_context {
+            return Err(syn::Error::new(
+                ty.span(),
+                "context can only be used with fields containing objects",
+            ));
+        }
+        if unordered && !takes_context {
+            return Err(syn::Error::new(
+                ty.span(),
+                "unordered can only be used with fields containing objects",
+            ));
+        }
+
+        Ok(FieldInfo {
+            member,
+            binding,
+            kind,
+            skip,
+            ordered: !unordered,
+            context,
+        })
+    }
+
+    /// Code for stashing the field, given an expression evaluating
+    /// to a shared reference to it
+    fn stash(&self, value: &TokenStream) -> TokenStream {
+        if self.skip {
+            return quote! {};
+        }
+        let order = if self.ordered {
+            quote! { ::hashstash::Order::Ordered }
+        } else {
+            quote! { ::hashstash::Order::Unordered }
+        };
+        match (&self.kind, &self.context) {
+            (FieldKind::Primitive(name), _) => {
+                quote! { stasher.#name(*#value); }
+            }
+            (FieldKind::String, _) => quote! { stasher.string(#value); },
+            (FieldKind::PrimitiveVec(name), _) => {
+                let method = format_ident!("array_of_{}_slice", name);
+                quote! { stasher.#method(#value); }
+            }
+            (FieldKind::ObjectVec, None) => {
+                quote! { stasher.array_of_objects_slice(#value, #order); }
+            }
+            (FieldKind::ObjectVec, Some(context)) => quote! {
+                #[allow(unused_variables)]
+                let context = stasher.context();
+                stasher.array_of_objects_slice_with_context(#value, #order, #context);
+            },
+            (FieldKind::UnorderedCollection(_), None) => {
+                quote! { stasher.array_of_objects_iter((#value).iter(), #order); }
+            }
+            (FieldKind::UnorderedCollection(_), Some(context)) => quote! {
+                #[allow(unused_variables)]
+                let context = stasher.context();
+                stasher.array_of_objects_iter_with_context((#value).iter(), #order, #context);
+            },
+            (FieldKind::Object, None) => quote! { stasher.object(#value); },
+            (FieldKind::Object, Some(context)) => quote! {
+                #[allow(unused_variables)]
+                let context = stasher.context();
+                stasher.object_with_context(#value, #context);
+            },
+        }
+    }
+
+    /// An expression which unstashes a new value of the field from
+    /// an [Unstasher] named `unstasher`
+    fn unstash(&self) -> TokenStream {
+        if self.skip {
+            return quote! { ::std::default::Default::default() };
+        }
+        let with_context = |method: Ident, context: &Expr| {
+            quote! {{
+                #[allow(unused_variables)]
+                let context = unstasher.context();
+                unstasher.#method(#context)
+            }}
+        };
+        match (&self.kind, &self.context) {
+            (FieldKind::Primitive(name), _) => quote! { unstasher.#name()? },
+            (FieldKind::String, _) => quote! { unstasher.string()? },
+            (FieldKind::PrimitiveVec(name), _) => {
+                let method = format_ident!("array_of_{}_vec", name);
+                quote! { unstasher.#method()? }
+            }
+            (FieldKind::ObjectVec, None) => quote! { unstasher.array_of_objects_vec()? },
+            (FieldKind::ObjectVec, Some(context)) => {
+                let call =
+                    with_context(format_ident!("array_of_objects_vec_with_context"), context);
+                quote! { #call? }
+            }
+            (FieldKind::UnorderedCollection(_), None) => quote! {
+                unstasher
+                    .array_of_objects_iter()?
+                    .collect::<::std::result::Result<_, ::hashstash::UnstashError>>()?
+            },
+            (FieldKind::UnorderedCollection(_), Some(context)) => quote! {{
+                #[allow(unused_variables)]
+                let context = unstasher.context();
+                unstasher
+                    .array_of_objects_iter_with_context(#context)?
+                    .collect::<::std::result::Result<_, ::hashstash::UnstashError>>()?
+            }},
+            (FieldKind::Object, None) => quote! { unstasher.object()? },
+            (FieldKind::Object, Some(context)) => {
+                let call = with_context(format_ident!("object_with_context"), context);
+                quote! { #call? }
+            }
+        }
+    }
+
+    /// Code for unstashing the field in place using an [InplaceUnstasher]
+    /// named `unstasher`, given an expression evaluating to a mutable
+    /// reference to it
+    fn unstash_inplace(&self, value: &TokenStream) -> TokenStream {
+        if self.skip {
+            return quote! {};
+        }
+        match (&self.kind, &self.context) {
+            (FieldKind::Primitive(name), _) => {
+                let method = format_ident!("{}_inplace", name);
+                quote! { unstasher.#method(#value)?; }
+            }
+            (FieldKind::String, _) => quote! { unstasher.string_inplace(#value)?; },
+            (FieldKind::PrimitiveVec(name), _) => {
+                let method = format_ident!("array_of_{}_vec_inplace", name);
+                quote! { unstasher.#method(#value)?; }
+            }
+            (FieldKind::ObjectVec, None) => {
+                quote! { unstasher.array_of_objects_vec_inplace(#value)?; }
+            }
+            (FieldKind::ObjectVec, Some(context)) => quote! {
+                #[allow(unused_variables)]
+                let context = unstasher.context();
+                unstasher.array_of_objects_vec_inplace_with_context(#value, #context)?;
+            },
+            (FieldKind::UnorderedCollection(item), context) => {
+                let read = match context {
+                    None => quote! { unstasher.array_of_objects_vec_inplace(&mut items)?; },
+                    Some(context) => quote! {
+                        #[allow(unused_variables)]
+                        let context = unstasher.context();
+                        unstasher.array_of_objects_vec_inplace_with_context(&mut items, #context)?;
+                    },
+                };
+                quote! {{
+                    let mut items = ::std::vec::Vec::<#item>::new();
+                    #read
+                    if unstasher.time_to_write() {
+                        *#value = items.into_iter().collect();
+                    }
+                }}
+            }
+            (FieldKind::Object, None) => quote! { unstasher.object_inplace(#value)?; },
+            (FieldKind::Object, Some(context)) => quote! {
+                #[allow(unused_variables)]
+                let context = unstasher.context();
+                unstasher.object_inplace_with_context(#value, #context)?;
+            },
+        }
+    }
+
+    /// An expression which unstashes a new value of the field from
+    /// an [InplaceUnstasher] named `unstasher`, during both phases
+    fn unstash_always(&self) -> TokenStream {
+        if self.skip {
+            return quote! { ::std::default::Default::default() };
+        }
+        match (&self.kind, &self.context) {
+            (FieldKind::Primitive(name), _) => {
+                let method = format_ident!("{}_always", name);
+                quote! { unstasher.#method()? }
+            }
+            (FieldKind::String, _) => quote! { unstasher.string_always()? },
+            (FieldKind::PrimitiveVec(name), _) => {
+                let method = format_ident!("array_of_{}_iter", name);
+                quote! { unstasher.#method()?.collect() }
+            }
+            (FieldKind::ObjectVec, _) | (FieldKind::UnorderedCollection(_), _) => {
+                // Objects are only written to the temporary vector during
+                // the write phase, which is the only phase in which the new
+                // value is kept
+                let item = match &self.kind {
+                    FieldKind::UnorderedCollection(item) => quote! { #item },
+                    _ => quote! { _ },
+                };
+                let read = match &self.context {
+                    None => quote! { unstasher.array_of_objects_vec_inplace(&mut items)?; },
+                    Some(context) => quote! {
+                        #[allow(unused_variables)]
+                        let context = unstasher.context();
+                        unstasher.array_of_objects_vec_inplace_with_context(&mut items, #context)?;
+                    },
+                };
+                quote! {{
+                    let mut items = ::std::vec::Vec::<#item>::new();
+                    #read
+                    items.into_iter().collect()
+                }}
+            }
+            (FieldKind::Object, None) => quote! { unstasher.object_always()? },
+            (FieldKind::Object, Some(context)) => quote! {{
+                #[allow(unused_variables)]
+                let context = unstasher.context();
+                unstasher.object_always_with_context(#context)?
+            }},
+        }
+    }
+}
+
+/// Parse all fields of a struct or enum variant
+fn parse_fields(fields: &Fields) -> syn::Result<Vec<FieldInfo>> {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| FieldInfo::parse(i, field))
+        .collect()
+}
+
+/// A pattern matching all fields of a variant, binding non-skipped
+/// fields by their binding names
+fn variant_pattern(variant: &Ident, fields: &Fields, infos: &[FieldInfo]) -> TokenStream {
+    let bindings = infos.iter().map(|info| {
+        let member = &info.member;
+        let binding = &info.binding;
+        if info.skip {
+            quote! { #member: _ }
+        } else {
+            quote! { #member: #binding }
+        }
+    });
+    match fields {
+        Fields::Unit => quote! { Self::#variant },
+        _ => quote! { Self::#variant { #(#bindings),* } },
+    }
+}
+
+/// An expression constructing a struct or enum variant from the given
+/// per-field value expressions
+fn construct(path: TokenStream, fields: &Fields, values: Vec<TokenStream>) -> TokenStream {
+    match fields {
+        Fields::Named(named) => {
+            let names = named.named.iter().map(|f| f.ident.as_ref().unwrap());
+            quote! { #path { #(#names: #values),* } }
+        }
+        Fields::Unnamed(_) => quote! { #path ( #(#values),* ) },
+        Fields::Unit => path,
+    }
+}
+
+/// Add the given bound to every type parameter
+fn add_bounds(generics: &Generics, bound: TokenStream) -> Generics {
+    let mut generics = generics.clone();
+    let params: Vec<Ident> = generics
+        .type_params()
+        .map(|param| param.ident.clone())
+        .collect();
+    let where_clause = generics.make_where_clause();
+    for param in params {
+        where_clause
+            .predicates
+            .push(syn::parse_quote!(#param: #bound));
+    }
+    generics
+}
+
+/// Make sure that the input is a struct or an enum with variants to be tagged with
+fn check_supported(input: &DeriveInput) -> syn::Result<()> {
+    match &input.data {
+        Data::Enum(data) if data.variants.is_empty() => Err(syn::Error::new(
+            Span::call_site(),
+            "stashing empty enums is not supported",
+        )),
+        Data::Union(_) => Err(syn::Error::new(
+            Span::call_site(),
+            "stashing unions is not supported",
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn expand_stashable(input: &DeriveInput) -> syn::Result<TokenStream> {
+    check_supported(input)?;
+    let attributes = ContainerAttributes::parse(input)?;
+    let context = &attributes.context;
+    let name = &input.ident;
+    let stash_type_name = attributes.stash_type_name();
+    let generics = add_bounds(&input.generics, quote! { ::hashstash::Stashable<#context> });
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let infos = parse_fields(&data.fields)?;
+            let stash_fields = infos.iter().map(|info| {
+                let member = &info.member;
+                info.stash(&quote! { &self.#member })
+            });
+            quote! { #(#stash_fields)* }
+        }
+        Data::Enum(data) => {
+            let mut arms = Vec::new();
+            for (index, variant) in data.variants.iter().enumerate() {
+                let infos = parse_fields(&variant.fields)?;
+                let pattern = variant_pattern(&variant.ident, &variant.fields, &infos);
+                let tag = index as u32;
+                let stash_fields = infos.iter().map(|info| {
+                    let binding = &info.binding;
+                    info.stash(&quote! { #binding })
+                });
+                arms.push(quote! {
+                    #pattern => {
+                        stasher.u32(#tag);
+                        #(#stash_fields)*
+                    }
+                });
+            }
+            quote! {
+                match self {
+                    #(#arms)*
+                }
+            }
+        }
+        Data::Union(_) => unreachable!(),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::hashstash::Stashable<#context> for #name #ty_generics #where_clause {
+            fn stash(&self, stasher: &mut ::hashstash::Stasher<#context>) {
+                #stash_type_name
+                #body
+            }
+        }
+    })
+}
+
+fn expand_unstashable(input: &DeriveInput) -> syn::Result<TokenStream> {
+    check_supported(input)?;
+    let attributes = ContainerAttributes::parse(input)?;
+    let context = &attributes.context;
+    let name = &input.ident;
+    let label = name.to_string();
+    let check_type_name = attributes.check_type_name();
+    let generics = add_bounds(
+        &input.generics,
+        quote! { 'static + ::hashstash::Unstashable<#context> },
+    );
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let infos = parse_fields(&data.fields)?;
+            let values = infos.iter().map(|info| info.unstash()).collect();
+            let value = construct(quote! { Self }, &data.fields, values);
+            quote! { Ok(#value) }
+        }
+        Data::Enum(data) => {
+            let mut arms = Vec::new();
+            for (index, variant) in data.variants.iter().enumerate() {
+                let infos = parse_fields(&variant.fields)?;
+                let values = infos.iter().map(|info| info.unstash()).collect();
+                let variant_name = &variant.ident;
+                let value = construct(quote! { Self::#variant_name }, &variant.fields, values);
+                let tag = index as u32;
+                arms.push(quote! { #tag => Ok(#value), });
+            }
+            quote! {
+                match unstasher.u32()? {
+                    #(#arms)*
+                    _ => Err(::hashstash::UnstashErrorKind::BadValue.into()),
+                }
+            }
+        }
+        Data::Union(_) => unreachable!(),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::hashstash::Unstashable<#context> for #name #ty_generics #where_clause {
+            fn unstash(
+                unstasher: &mut ::hashstash::Unstasher<#context>,
+            ) -> ::std::result::Result<Self, ::hashstash::UnstashError> {
+                unstasher.label(#label);
+                #check_type_name
+                #body
+            }
+        }
+    })
+}
+
+fn expand_unstashable_inplace(input: &DeriveInput) -> syn::Result<TokenStream> {
+    check_supported(input)?;
+    let attributes = ContainerAttributes::parse(input)?;
+    let context = &attributes.context;
+    let name = &input.ident;
+    let label = name.to_string();
+    let check_type_name = attributes.check_type_name();
+    let generics = add_bounds(
+        &input.generics,
+        quote! {
+            'static + ::hashstash::Unstashable<#context> + ::hashstash::UnstashableInplace<#context>
+        },
+    );
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let infos = parse_fields(&data.fields)?;
+            let unstash_fields = infos.iter().map(|info| {
+                let member = &info.member;
+                info.unstash_inplace(&quote! { &mut self.#member })
+            });
+            quote! {
+                #(#unstash_fields)*
+                Ok(())
+            }
+        }
+        Data::Enum(data) => {
+            // If the variant matches, its fields are unstashed in place.
+            // Otherwise, a new variant is unstashed and replaces the
+            // current one during the write phase.
+            let mut inplace_arms = Vec::new();
+            let mut new_arms = Vec::new();
+            for (index, variant) in data.variants.iter().enumerate() {
+                let infos = parse_fields(&variant.fields)?;
+                let tag = index as u32;
+                let pattern = variant_pattern(&variant.ident, &variant.fields, &infos);
+                let unstash_fields = infos.iter().map(|info| {
+                    let binding = &info.binding;
+                    info.unstash_inplace(&quote! { #binding })
+                });
+                inplace_arms.push(quote! {
+                    #pattern if tag == #tag => {
+                        #(#unstash_fields)*
+                    }
+                });
+                let values = infos.iter().map(|info| info.unstash_always()).collect();
+                let variant_name = &variant.ident;
+                let value = construct(quote! { Self::#variant_name }, &variant.fields, values);
+                new_arms.push(quote! { #tag => #value, });
+            }
+            quote! {
+                let tag = unstasher.u32_always()?;
+                match self {
+                    #(#inplace_arms)*
+                    _ => {
+                        let new_value = match tag {
+                            #(#new_arms)*
+                            _ => return Err(::hashstash::UnstashErrorKind::BadValue.into()),
+                        };
+                        if unstasher.time_to_write() {
+                            *self = new_value;
+                        }
+                    }
+                }
+                Ok(())
+            }
+        }
+        Data::Union(_) => unreachable!(),
+    };
+
+    Ok(quote! {
+        impl #impl_generics ::hashstash::UnstashableInplace<#context> for #name #ty_generics #where_clause {
+            fn unstash_inplace(
+                &mut self,
+                unstasher: &mut ::hashstash::InplaceUnstasher<#context>,
+            ) -> ::std::result::Result<(), ::hashstash::UnstashError> {
+                unstasher.label(#label);
+                #check_type_name
+                #body
+            }
+        }
+    })
+}
@@ -0,0 +1,321 @@
+use std::collections::HashSet;
+
+use hashstash::{
+    test_stash_roundtrip, test_stash_roundtrip_inplace, InplaceUnstasher, ObjectHash, Stash,
+    Stashable, Stasher, UnstashError, UnstashErrorKind, Unstashable, UnstashableInplace, Unstasher,
+    Value,
+};
+
+#[derive(Stashable, Unstashable, UnstashableInplace, Clone, Debug, PartialEq, Eq, Hash)]
+struct StructA {
+    i: i32,
+    x: u64,
+    s: String,
+}
+
+#[derive(Stashable, Unstashable, UnstashableInplace, Clone, Debug, PartialEq)]
+struct StructB {
+    a: StructA,
+    flag: bool,
+    values: Vec<f32>,
+    objects: Vec<StructA>,
+    #[stash(unordered)]
+    set: HashSet<StructA>,
+    #[stash(skip)]
+    cache: Option<usize>,
+}
+
+#[derive(Stashable, Unstashable, UnstashableInplace, Clone, Debug, PartialEq)]
+struct Wrapper(StructA, u8);
+
+#[derive(Stashable, Unstashable, UnstashableInplace, Clone, Debug, PartialEq)]
+struct Tags {
+    #[stash(unordered)]
+    names: Vec<String>,
+    #[stash(unordered)]
+    ids: Vec<u32>,
+}
+
+#[derive(Stashable, Unstashable, UnstashableInplace, Clone, Debug, PartialEq)]
+enum Shape {
+    Empty,
+    Circle { radius: f64 },
+    Polygon(Vec<i64>, StructA),
+}
+
+#[derive(Copy, Clone)]
+struct Scale(i32);
+
+/// A value that is stored multiplied by the scale in the context
+#[derive(Clone, Debug, PartialEq)]
+struct Length(i32);
+
+impl Stashable<Scale> for Length {
+    fn stash(&self, stasher: &mut Stasher<Scale>) {
+        stasher.i32(self.0 * stasher.context().0);
+    }
+}
+
+impl Unstashable<Scale> for Length {
+    fn unstash(unstasher: &mut Unstasher<Scale>) -> Result<Self, UnstashError> {
+        Ok(Length(unstasher.i32()? / unstasher.context().0))
+    }
+}
+
+impl UnstashableInplace<Scale> for Length {
+    fn unstash_inplace(
+        &mut self,
+        unstasher: &mut InplaceUnstasher<Scale>,
+    ) -> Result<(), UnstashError> {
+        let scale = unstasher.context().0;
+        let value = unstasher.i32_always()?;
+        if unstasher.time_to_write() {
+            self.0 = value / scale;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Stashable, Unstashable, UnstashableInplace, Clone, Debug, PartialEq)]
+#[stash(context = Scale)]
+struct Scaled {
+    length: Length,
+}
+
+#[derive(Stashable, Unstashable, UnstashableInplace, Clone, Debug, PartialEq)]
+struct WithContext {
+    #[stash(context = Scale(2))]
+    scaled: Scaled,
+}
+
+#[derive(Stashable, Unstashable, UnstashableInplace, Clone, Debug, PartialEq)]
+#[stash(type_name = "Point")]
+struct Point {
+    x: f32,
+    y: f32,
+}
+
+#[derive(Stashable, Unstashable, UnstashableInplace, Clone, Debug, PartialEq)]
+#[stash(type_name = "Size")]
+struct Size {
+    w: f32,
+    h: f32,
+}
+
+#[derive(Stashable, Unstashable, UnstashableInplace, Clone, Debug, PartialEq)]
+struct Glyph {
+    code: char,
+    id: u128,
+    alternates: Vec<char>,
+}
+
+fn make_a(i: i32) -> StructA {
+    StructA {
+        i,
+        x: 0x0123456789abcdef,
+        s: format!("a{}", i),
+    }
+}
+
+fn make_b() -> StructB {
+    StructB {
+        a: make_a(1),
+        flag: true,
+        values: vec![1.0, 2.5],
+        objects: vec![make_a(2), make_a(3)],
+        set: [make_a(4), make_a(5), make_a(6)].into_iter().collect(),
+        cache: None,
+    }
+}
+
+#[test]
+fn test_derive_struct() {
+    let b = make_b();
+
+    let stash = Stash::new();
+    let handle = stash.stash(&b);
+    assert_eq!(stash.unstash(&handle).unwrap(), b);
+
+    // Skipped fields don't affect the hash and are left alone in place
+    let mut b2 = b.clone();
+    b2.cache = Some(5);
+    assert_eq!(ObjectHash::from_stashable(&b2), handle.object_hash());
+    b2.flag = false;
+    stash.unstash_inplace(&handle, &mut b2).unwrap();
+    assert!(b2.flag);
+    assert_eq!(b2.cache, Some(5));
+
+    // Unordered fields hash the same regardless of order
+    let mut b3 = b.clone();
+    b3.set = b.set.iter().cloned().collect();
+    assert_eq!(ObjectHash::from_stashable(&b3), handle.object_hash());
+
+    // Vecs can be unordered too
+    let tags = Tags {
+        names: vec!["x".to_string(), "y".to_string()],
+        ids: vec![1, 2, 3],
+    };
+    let reversed = Tags {
+        names: vec!["y".to_string(), "x".to_string()],
+        ids: vec![3, 2, 1],
+    };
+    assert_eq!(
+        ObjectHash::from_stashable(&tags),
+        ObjectHash::from_stashable(&reversed)
+    );
+    let handle = stash.stash(&reversed);
+    let unstashed = stash.unstash(&handle).unwrap();
+    assert_eq!(unstashed.ids.len(), 3);
+    assert_eq!(unstashed.names.len(), 2);
+}
+
+#[test]
+fn test_derive_roundtrip() {
+    let modify_a = |b: &mut StructB| b.a.s.push('!');
+    let modify_values = |b: &mut StructB| b.values.push(3.0);
+    let modify_objects = |b: &mut StructB| b.objects.reverse();
+    let modify_set = |b: &mut StructB| {
+        b.set.insert(make_a(7));
+    };
+
+    assert_eq!(test_stash_roundtrip(make_b, modify_a, (), ()), Ok(()));
+    assert_eq!(test_stash_roundtrip(make_b, modify_values, (), ()), Ok(()));
+    assert_eq!(test_stash_roundtrip(make_b, modify_objects, (), ()), Ok(()));
+    assert_eq!(test_stash_roundtrip(make_b, modify_set, (), ()), Ok(()));
+    assert_eq!(
+        test_stash_roundtrip_inplace(make_b, modify_a, (), ()),
+        Ok(())
+    );
+    assert_eq!(
+        test_stash_roundtrip_inplace(make_b, modify_values, (), ()),
+        Ok(())
+    );
+    assert_eq!(
+        test_stash_roundtrip_inplace(make_b, modify_objects, (), ()),
+        Ok(())
+    );
+    assert_eq!(
+        test_stash_roundtrip_inplace(make_b, modify_set, (), ()),
+        Ok(())
+    );
+
+    let create_wrapper = || Wrapper(make_a(1), 2);
+    let modify_wrapper = |w: &mut Wrapper| w.1 += 1;
+    assert_eq!(
+        test_stash_roundtrip(create_wrapper, modify_wrapper, (), ()),
+        Ok(())
+    );
+    assert_eq!(
+        test_stash_roundtrip_inplace(create_wrapper, modify_wrapper, (), ()),
+        Ok(())
+    );
+}
+
+#[test]
+fn test_derive_enum() {
+    let shapes = [
+        Shape::Empty,
+        Shape::Circle { radius: 1.5 },
+        Shape::Circle { radius: 2.5 },
+        Shape::Polygon(vec![0, 1, 2], make_a(1)),
+    ];
+
+    for original in &shapes {
+        for modified in &shapes {
+            if original == modified {
+                continue;
+            }
+            let create = || original.clone();
+            let modify = |s: &mut Shape| *s = modified.clone();
+            assert_eq!(test_stash_roundtrip(create, modify, (), ()), Ok(()));
+            assert_eq!(test_stash_roundtrip_inplace(create, modify, (), ()), Ok(()));
+        }
+    }
+
+    let stash = Stash::new();
+    let handle = stash.stash(&shapes[3]);
+    let mut shape = Shape::Circle { radius: 0.0 };
+    stash.unstash_inplace(&handle, &mut shape).unwrap();
+    assert_eq!(shape, shapes[3]);
+}
+
+#[test]
+fn test_derive_context() {
+    let with_context = WithContext {
+        scaled: Scaled { length: Length(3) },
+    };
+    let scaled_directly = Scaled { length: Length(3) };
+
+    let stash = Stash::new();
+    let handle = stash.stash(&with_context);
+    let handle_scaled = stash.stash_with_context(&scaled_directly, Scale(2));
+
+    // The nested object was stashed with the context given by the attribute
+    let Ok(Value::Object(object)) = stash.inspect(&handle) else {
+        panic!("expected an object");
+    };
+    assert!(matches!(
+        &object.values[0],
+        Value::Object(nested) if nested.hash == handle_scaled.object_hash()
+    ));
+
+    let unstashed = stash.unstash(&handle).unwrap();
+    assert_eq!(unstashed, with_context);
+
+    // A different context produces a different object
+    let handle_tripled = stash.stash_with_context(&scaled_directly, Scale(3));
+    assert_ne!(handle_tripled.object_hash(), handle_scaled.object_hash());
+    let mut unstashed = Scaled { length: Length(0) };
+    stash
+        .unstash_inplace_with_context(&handle_tripled, &mut unstashed, Scale(3))
+        .unwrap();
+    assert_eq!(unstashed, scaled_directly);
+}
+
+#[test]
+fn test_derive_type_name() {
+    let point = Point { x: 1.0, y: 2.0 };
+    let size = Size { w: 1.0, h: 2.0 };
+    assert_ne!(
+        ObjectHash::from_stashable(&point),
+        ObjectHash::from_stashable(&size)
+    );
+    assert_eq!(
+        test_stash_roundtrip(|| point.clone(), |p| p.x = 0.0, (), ()),
+        Ok(())
+    );
+
+    let stash = Stash::new();
+    let handle = stash.stash(&point).cast::<Size>();
+    assert_eq!(
+        stash.unstash(&handle).err().map(|err| err.kind()),
+        Some(UnstashErrorKind::TypeMismatch)
+    );
+    let mut unstashed = size.clone();
+    assert_eq!(
+        stash
+            .unstash_inplace(&handle, &mut unstashed)
+            .err()
+            .map(|err| err.kind()),
+        Some(UnstashErrorKind::TypeMismatch)
+    );
+    assert_eq!(unstashed, size);
+}
+
+#[test]
+fn test_derive_wide_primitives() {
+    let make_glyph = || Glyph {
+        code: 'ß',
+        id: 1 << 100,
+        alternates: vec!['s', 'S'],
+    };
+    let modify_alternates = |g: &mut Glyph| g.alternates.push('ẞ');
+    assert_eq!(
+        test_stash_roundtrip(make_glyph, modify_alternates, (), ()),
+        Ok(())
+    );
+    assert_eq!(
+        test_stash_roundtrip_inplace(make_glyph, modify_alternates, (), ()),
+        Ok(())
+    );
+}
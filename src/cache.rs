@@ -1,21 +1,21 @@
 use std::{
     cell::Cell,
-    hash::Hasher,
     ops::{Deref, DerefMut},
 };
 
 use crate::{
+    hasher::{DefaultObjectHasher, ObjectHasher},
     InplaceUnstasher, ObjectHash, Stashable, Stasher, UnstashError, Unstashable,
     UnstashableInplace, Unstasher,
 };
 
 /// Helper method to combine multiple hashes
 fn combine_hashes(hashes: &[ObjectHash]) -> ObjectHash {
-    let mut hasher = seahash::SeaHasher::new();
+    let mut hasher = DefaultObjectHasher::new();
     for hash in hashes {
-        hasher.write_u64(hash.0);
+        hasher.write_hash(*hash);
     }
-    ObjectHash(hasher.finish())
+    hasher.finish()
 }
 
 /// Cache entry for storing pre-computed object hashes
@@ -90,7 +90,7 @@ where
             for (i, entry) in self.entries.iter().enumerate() {
                 if let Some(entry) = entry.get() {
                     if entry.context_hash == context_hash {
-                        stasher.u64(entry.object_hash.0);
+                        entry.object_hash.stash_bits(stasher);
                         return;
                     }
                 } else if next_empty_entry.is_none() {
@@ -109,7 +109,7 @@ where
                 object_hash,
             }));
 
-            stasher.u64(object_hash.0);
+            object_hash.stash_bits(stasher);
         } else {
             // Otherwise, if serializing, just serialize
             self.deref().stash(stasher);
@@ -0,0 +1,568 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    marker::PhantomData,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    ObjectHash, Stash, StashHandle, Stashable, Stasher, UnstashError, Unstashable,
+    UnstashableInplace, Unstasher,
+};
+
+/// The name of the branch that a new [CommitGraph] starts on
+const DEFAULT_BRANCH: &str = "main";
+
+/// Errors that can happen while working with a [CommitGraph]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum CommitGraphError {
+    /// The given commit does not exist in the graph
+    UnknownCommit,
+
+    /// No branch or tag with the given name exists
+    UnknownName,
+
+    /// A branch or tag with the given name already exists
+    NameInUse,
+
+    /// The current branch can't be deleted
+    CurrentBranch,
+
+    /// Unstashing a commit's contents failed
+    Unstash(UnstashError),
+}
+
+impl From<UnstashError> for CommitGraphError {
+    fn from(err: UnstashError) -> Self {
+        CommitGraphError::Unstash(err)
+    }
+}
+
+impl std::fmt::Display for CommitGraphError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommitGraphError::UnknownCommit => f.write_str("unknown commit"),
+            CommitGraphError::UnknownName => f.write_str("unknown branch or tag"),
+            CommitGraphError::NameInUse => f.write_str("branch or tag name already in use"),
+            CommitGraphError::CurrentBranch => f.write_str("can't delete the current branch"),
+            CommitGraphError::Unstash(err) => write!(f, "failed to unstash commit: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for CommitGraphError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CommitGraphError::Unstash(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Identifies a single commit in a [CommitGraph]. This is
+/// the hash of the commit's stashed object, and so depends
+/// on the commit's contents, parents, timestamp and message.
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub struct CommitId(ObjectHash);
+
+impl CommitId {
+    /// Get the hash of the commit's stashed object
+    pub fn object_hash(&self) -> ObjectHash {
+        self.0
+    }
+}
+
+/// The details of a single commit in a [CommitGraph]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Commit {
+    id: CommitId,
+    root: ObjectHash,
+    parents: Vec<CommitId>,
+    timestamp: u64,
+    message: String,
+}
+
+impl Commit {
+    /// Get the id of the commit
+    pub fn id(&self) -> CommitId {
+        self.id
+    }
+
+    /// Get the hash of the object that was committed
+    pub fn root_hash(&self) -> ObjectHash {
+        self.root
+    }
+
+    /// Get the commits that this commit was based on. This is
+    /// empty for the first commit, and has more than one entry
+    /// for merges.
+    pub fn parents(&self) -> &[CommitId] {
+        &self.parents
+    }
+
+    /// Get the time of the commit, in seconds since the Unix epoch
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Get the message of the commit
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+/// The stashed contents of a commit. The root object and parent
+/// commits are referenced as dependencies, so that holding a handle
+/// to a commit keeps its entire history alive.
+struct CommitContents {
+    root: ObjectHash,
+    parents: Vec<ObjectHash>,
+    timestamp: u64,
+    message: String,
+}
+
+impl Stashable for CommitContents {
+    fn stash(&self, stasher: &mut Stasher) {
+        stasher.existing_object(self.root);
+        stasher.usize(self.parents.len());
+        for parent in &self.parents {
+            stasher.existing_object(*parent);
+        }
+        stasher.u64(self.timestamp);
+        stasher.string(&self.message);
+    }
+}
+
+impl Unstashable for CommitContents {
+    fn unstash(unstasher: &mut Unstasher) -> Result<Self, UnstashError> {
+        let root = unstasher.object_hash()?;
+        let num_parents = unstasher.usize()?;
+        let mut parents = Vec::new();
+        for _ in 0..num_parents {
+            parents.push(unstasher.object_hash()?);
+        }
+        Ok(CommitContents {
+            root,
+            parents,
+            timestamp: unstasher.u64()?,
+            message: unstasher.string()?,
+        })
+    }
+}
+
+/// What the graph currently has checked out
+enum Head {
+    /// A branch, which may not have any commits yet
+    Branch(String),
+
+    /// A single commit that is not on any branch
+    Detached(StashHandle<CommitContents>),
+}
+
+/// A git-like graph of commits of the states of an object, stored in
+/// a [Stash] that the graph owns. Each commit refers to the stashed
+/// state of the object, its parent commits, a timestamp and a message,
+/// and is itself stashed as an object in the same stash. Branches and
+/// tags give names to commits, and commits which can't be reached from
+/// any branch, tag or the head are removed from the stash.
+pub struct CommitGraph<T> {
+    /// The stash holding every commit and committed state
+    stash: Stash,
+
+    /// Branches by name, which move forward with each commit
+    branches: BTreeMap<String, StashHandle<CommitContents>>,
+
+    /// Tags by name, which always refer to the same commit
+    tags: BTreeMap<String, StashHandle<CommitContents>>,
+
+    /// The currently checked-out branch or commit
+    head: Head,
+
+    _phantom_data: PhantomData<fn() -> T>,
+}
+
+impl<T> CommitGraph<T> {
+    /// Create a new CommitGraph without any commits, on a branch
+    /// named "main"
+    pub fn new() -> CommitGraph<T> {
+        CommitGraph {
+            stash: Stash::new(),
+            branches: BTreeMap::new(),
+            tags: BTreeMap::new(),
+            head: Head::Branch(DEFAULT_BRANCH.to_string()),
+            _phantom_data: PhantomData,
+        }
+    }
+
+    /// Get the stash in which all commits are stored
+    pub fn stash(&self) -> &Stash {
+        &self.stash
+    }
+
+    /// Get the currently checked-out commit, if there is one
+    pub fn head(&self) -> Option<CommitId> {
+        self.head_handle().map(|h| CommitId(h.object_hash()))
+    }
+
+    /// Get the name of the currently checked-out branch, or None
+    /// if a commit was checked out directly
+    pub fn current_branch(&self) -> Option<&str> {
+        match &self.head {
+            Head::Branch(name) => Some(name),
+            Head::Detached(_) => None,
+        }
+    }
+
+    /// Commit the given object's state on top of the current head,
+    /// advancing the current branch if there is one
+    pub fn commit(&mut self, object: &T, message: &str) -> CommitId
+    where
+        T: Stashable,
+    {
+        self.commit_with_context(object, message, ())
+    }
+
+    pub fn commit_with_context<C: Copy>(
+        &mut self,
+        object: &T,
+        message: &str,
+        context: C,
+    ) -> CommitId
+    where
+        T: Stashable<C>,
+    {
+        let parents = self.head().map(|id| id.0).into_iter().collect();
+        self.add_commit(object, message, parents, context)
+    }
+
+    /// Commit the given object's state with the current head and the
+    /// given additional commits as parents, such as after merging
+    /// other branches into the current one
+    pub fn commit_merge(
+        &mut self,
+        object: &T,
+        message: &str,
+        other_parents: &[CommitId],
+    ) -> Result<CommitId, CommitGraphError>
+    where
+        T: Stashable,
+    {
+        self.commit_merge_with_context(object, message, other_parents, ())
+    }
+
+    pub fn commit_merge_with_context<C: Copy>(
+        &mut self,
+        object: &T,
+        message: &str,
+        other_parents: &[CommitId],
+        context: C,
+    ) -> Result<CommitId, CommitGraphError>
+    where
+        T: Stashable<C>,
+    {
+        let mut parents: Vec<ObjectHash> = self.head().map(|id| id.0).into_iter().collect();
+        for parent in other_parents {
+            self.get(*parent)?;
+            if !parents.contains(&parent.0) {
+                parents.push(parent.0);
+            }
+        }
+        Ok(self.add_commit(object, message, parents, context))
+    }
+
+    /// Create a new branch pointing at the given commit. This does not
+    /// check out the new branch.
+    pub fn create_branch(&mut self, name: &str, commit: CommitId) -> Result<(), CommitGraphError> {
+        if self.branches.contains_key(name) || self.current_branch() == Some(name) {
+            return Err(CommitGraphError::NameInUse);
+        }
+        let handle = self.commit_handle(commit)?;
+        self.branches.insert(name.to_string(), handle);
+        Ok(())
+    }
+
+    /// Delete the branch with the given name. Commits that are only
+    /// reachable from that branch are removed from the stash.
+    pub fn delete_branch(&mut self, name: &str) -> Result<(), CommitGraphError> {
+        if self.current_branch() == Some(name) {
+            return Err(CommitGraphError::CurrentBranch);
+        }
+        self.branches
+            .remove(name)
+            .map(|_| ())
+            .ok_or(CommitGraphError::UnknownName)
+    }
+
+    /// Get the commit that the branch with the given name points at
+    pub fn branch(&self, name: &str) -> Option<CommitId> {
+        self.branches.get(name).map(|h| CommitId(h.object_hash()))
+    }
+
+    /// Get the names of all branches which have commits, in sorted order
+    pub fn branches(&self) -> impl Iterator<Item = &str> {
+        self.branches.keys().map(|n| n.as_str())
+    }
+
+    /// Create a new tag pointing at the given commit
+    pub fn create_tag(&mut self, name: &str, commit: CommitId) -> Result<(), CommitGraphError> {
+        if self.tags.contains_key(name) {
+            return Err(CommitGraphError::NameInUse);
+        }
+        let handle = self.commit_handle(commit)?;
+        self.tags.insert(name.to_string(), handle);
+        Ok(())
+    }
+
+    /// Delete the tag with the given name
+    pub fn delete_tag(&mut self, name: &str) -> Result<(), CommitGraphError> {
+        self.tags
+            .remove(name)
+            .map(|_| ())
+            .ok_or(CommitGraphError::UnknownName)
+    }
+
+    /// Get the commit that the tag with the given name points at
+    pub fn tag(&self, name: &str) -> Option<CommitId> {
+        self.tags.get(name).map(|h| CommitId(h.object_hash()))
+    }
+
+    /// Get the names of all tags, in sorted order
+    pub fn tags(&self) -> impl Iterator<Item = &str> {
+        self.tags.keys().map(|n| n.as_str())
+    }
+
+    /// Find the commit with the given branch or tag name. Branches
+    /// take precedence over tags with the same name.
+    pub fn resolve(&self, name: &str) -> Option<CommitId> {
+        self.branch(name).or_else(|| self.tag(name))
+    }
+
+    /// Switch to the branch with the given name and restore the given
+    /// object in place to the state of the branch's latest commit. If
+    /// the branch has no commits yet, the object is left unmodified.
+    pub fn checkout_branch(&mut self, name: &str, object: &mut T) -> Result<(), CommitGraphError>
+    where
+        T: UnstashableInplace,
+    {
+        self.checkout_branch_with_context(name, object, ())
+    }
+
+    pub fn checkout_branch_with_context<C: Copy>(
+        &mut self,
+        name: &str,
+        object: &mut T,
+        context: C,
+    ) -> Result<(), CommitGraphError>
+    where
+        T: UnstashableInplace<C>,
+    {
+        let Some(handle) = self.branches.get(name) else {
+            if self.current_branch() == Some(name) {
+                return Ok(());
+            }
+            return Err(CommitGraphError::UnknownName);
+        };
+        self.restore(CommitId(handle.object_hash()), object, context)?;
+        self.head = Head::Branch(name.to_string());
+        Ok(())
+    }
+
+    /// Check out the given commit directly, without being on any branch,
+    /// and restore the given object in place to the commit's state.
+    /// Subsequent commits only move the head forward.
+    pub fn checkout(&mut self, commit: CommitId, object: &mut T) -> Result<(), CommitGraphError>
+    where
+        T: UnstashableInplace,
+    {
+        self.checkout_with_context(commit, object, ())
+    }
+
+    pub fn checkout_with_context<C: Copy>(
+        &mut self,
+        commit: CommitId,
+        object: &mut T,
+        context: C,
+    ) -> Result<(), CommitGraphError>
+    where
+        T: UnstashableInplace<C>,
+    {
+        let handle = self.commit_handle(commit)?;
+        self.restore(commit, object, context)?;
+        self.head = Head::Detached(handle);
+        Ok(())
+    }
+
+    /// Unstash a new object with the state of the given commit
+    pub fn unstash(&self, commit: CommitId) -> Result<T, CommitGraphError>
+    where
+        T: Unstashable,
+    {
+        self.unstash_with_context(commit, ())
+    }
+
+    pub fn unstash_with_context<C: Copy>(
+        &self,
+        commit: CommitId,
+        context: C,
+    ) -> Result<T, CommitGraphError>
+    where
+        T: Unstashable<C>,
+    {
+        let root = self.root_handle(commit)?;
+        Ok(self.stash.unstash_with_context(&root, context)?)
+    }
+
+    /// Get the details of the given commit
+    pub fn get(&self, commit: CommitId) -> Result<Commit, CommitGraphError> {
+        let handle = self.commit_handle(commit)?;
+        let contents = self.stash.unstash(&handle)?;
+        Ok(Commit {
+            id: commit,
+            root: contents.root,
+            parents: contents.parents.into_iter().map(CommitId).collect(),
+            timestamp: contents.timestamp,
+            message: contents.message,
+        })
+    }
+
+    /// Get the given commit and all of its ancestors, most recent first.
+    /// Commits with equal timestamps are listed in the order they're
+    /// found by following parents breadth-first.
+    pub fn log(&self, commit: CommitId) -> Result<Vec<Commit>, CommitGraphError> {
+        let mut commits = self.ancestors(commit)?;
+        commits.sort_by_key(|c| std::cmp::Reverse(c.timestamp));
+        Ok(commits)
+    }
+
+    /// Find the best common ancestor of two commits, which is a
+    /// common ancestor that is not itself an ancestor of any other
+    /// common ancestor. If several such commits exist, the most
+    /// recent one is chosen. Returns None if the commits don't
+    /// share any history.
+    pub fn merge_base(
+        &self,
+        a: CommitId,
+        b: CommitId,
+    ) -> Result<Option<CommitId>, CommitGraphError> {
+        let ancestors_of_a: HashSet<CommitId> =
+            self.ancestors(a)?.into_iter().map(|c| c.id).collect();
+        let common: Vec<Commit> = self
+            .ancestors(b)?
+            .into_iter()
+            .filter(|c| ancestors_of_a.contains(&c.id))
+            .collect();
+
+        let mut redundant = HashSet::new();
+        for commit in &common {
+            for parent in &commit.parents {
+                if !redundant.contains(parent) {
+                    redundant.extend(self.ancestors(*parent)?.into_iter().map(|c| c.id));
+                }
+            }
+        }
+
+        Ok(common
+            .into_iter()
+            .filter(|c| !redundant.contains(&c.id))
+            .max_by_key(|c| c.timestamp)
+            .map(|c| c.id))
+    }
+
+    /// Stash the object and a new commit with the given parents, which
+    /// must exist, and move the head to the new commit
+    fn add_commit<C: Copy>(
+        &mut self,
+        object: &T,
+        message: &str,
+        parents: Vec<ObjectHash>,
+        context: C,
+    ) -> CommitId
+    where
+        T: Stashable<C>,
+    {
+        let root = self.stash.stash_with_context(object, context);
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let handle = self.stash.stash(&CommitContents {
+            root: root.object_hash(),
+            parents,
+            timestamp,
+            message: message.to_string(),
+        });
+        let id = CommitId(handle.object_hash());
+
+        match &mut self.head {
+            Head::Branch(name) => {
+                self.branches.insert(name.clone(), handle);
+            }
+            Head::Detached(head) => *head = handle,
+        }
+
+        id
+    }
+
+    /// Get the given commit and all of its ancestors, breadth-first
+    fn ancestors(&self, commit: CommitId) -> Result<Vec<Commit>, CommitGraphError> {
+        let mut visited = HashSet::from([commit]);
+        let mut commits = vec![self.get(commit)?];
+        let mut i = 0;
+        while i < commits.len() {
+            for parent in commits[i].parents.clone() {
+                if visited.insert(parent) {
+                    commits.push(self.get(parent)?);
+                }
+            }
+            i += 1;
+        }
+        Ok(commits)
+    }
+
+    /// Restore the given object in place to the state of a commit
+    fn restore<C: Copy>(
+        &self,
+        commit: CommitId,
+        object: &mut T,
+        context: C,
+    ) -> Result<(), CommitGraphError>
+    where
+        T: UnstashableInplace<C>,
+    {
+        let root = self.root_handle(commit)?;
+        Ok(self
+            .stash
+            .unstash_inplace_with_context(&root, object, context)?)
+    }
+
+    /// Get a handle to the currently checked-out commit, if any
+    fn head_handle(&self) -> Option<&StashHandle<CommitContents>> {
+        match &self.head {
+            Head::Branch(name) => self.branches.get(name),
+            Head::Detached(handle) => Some(handle),
+        }
+    }
+
+    /// Get a new handle to the given commit, if it exists
+    fn commit_handle(
+        &self,
+        commit: CommitId,
+    ) -> Result<StashHandle<CommitContents>, CommitGraphError> {
+        self.stash
+            .handle_for(commit.0)
+            .ok_or(CommitGraphError::UnknownCommit)
+    }
+
+    /// Get a new handle to the object that was committed
+    fn root_handle(&self, commit: CommitId) -> Result<StashHandle<T>, CommitGraphError> {
+        let handle = self.commit_handle(commit)?;
+        let contents = self.stash.unstash(&handle)?;
+        self.stash
+            .handle_for(contents.root)
+            .ok_or(CommitGraphError::UnknownCommit)
+    }
+}
+
+impl<T> Default for CommitGraph<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,277 @@
+use crate::{
+    unstasher::{ObjectRef, RawValue},
+    ObjectHash, PrimitiveValue, StashMap, UnstashError,
+};
+
+/// A single step along the path from the root object being
+/// compared to the location of a [Difference]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PathSegment {
+    /// The value at the given position among an object's stashed values
+    Value(usize),
+
+    /// The element at the given position within an array
+    Element(usize),
+}
+
+/// A single difference between two stashed objects, as found by
+/// [crate::Stash::diff]. Values which were added have no old value,
+/// and values which were removed have no new value.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Difference {
+    /// A primitive value or an element of an array of primitives
+    Primitive {
+        path: Vec<PathSegment>,
+        old: Option<PrimitiveValue>,
+        new: Option<PrimitiveValue>,
+    },
+
+    /// A string
+    String {
+        path: Vec<PathSegment>,
+        old: Option<String>,
+        new: Option<String>,
+    },
+
+    /// A stashed object, or an element of an array of objects. If
+    /// both the old and new object are present, the differences
+    /// between their contents are listed separately.
+    Object {
+        path: Vec<PathSegment>,
+        old: Option<ObjectHash>,
+        new: Option<ObjectHash>,
+    },
+}
+
+impl Difference {
+    /// Get the path from the root object to the difference
+    pub fn path(&self) -> &[PathSegment] {
+        match self {
+            Difference::Primitive { path, .. } => path,
+            Difference::String { path, .. } => path,
+            Difference::Object { path, .. } => path,
+        }
+    }
+}
+
+/// Whether a value is only present in the old or the new object
+#[derive(Copy, Clone)]
+enum Side {
+    Old,
+    New,
+}
+
+/// Find the differences between two stashed objects, walking their
+/// dependencies in parallel but skipping any pair of objects with
+/// the same hash. Values are compared by their position within each
+/// object, and elements by their position within each array.
+pub(crate) fn diff(
+    stashmap: &StashMap,
+    old: ObjectHash,
+    new: ObjectHash,
+) -> Result<Vec<Difference>, UnstashError> {
+    let mut differences = Vec::new();
+    diff_objects(
+        stashmap,
+        ObjectRef::Stashed(old),
+        ObjectRef::Stashed(new),
+        &mut Vec::new(),
+        &mut differences,
+    )?;
+    Ok(differences)
+}
+
+/// Compare the contents of two objects
+fn diff_objects(
+    stashmap: &StashMap,
+    old: ObjectRef,
+    new: ObjectRef,
+    path: &mut Vec<PathSegment>,
+    differences: &mut Vec<Difference>,
+) -> Result<(), UnstashError> {
+    if old.hash() == new.hash() {
+        return Ok(());
+    }
+
+    let (_, _, old_values) = stashmap.read_raw_values(old)?;
+    let (_, _, new_values) = stashmap.read_raw_values(new)?;
+
+    for i in 0..old_values.len().max(new_values.len()) {
+        path.push(PathSegment::Value(i));
+        diff_values(
+            stashmap,
+            old_values.get(i),
+            new_values.get(i),
+            path,
+            differences,
+        )?;
+        path.pop();
+    }
+
+    Ok(())
+}
+
+/// Compare two values at the same position, either of which may be missing
+fn diff_values(
+    stashmap: &StashMap,
+    old: Option<&RawValue>,
+    new: Option<&RawValue>,
+    path: &mut Vec<PathSegment>,
+    differences: &mut Vec<Difference>,
+) -> Result<(), UnstashError> {
+    match (old, new) {
+        (Some(RawValue::Primitive(a)), Some(RawValue::Primitive(b))) => {
+            if !a.identical(b) {
+                differences.push(Difference::Primitive {
+                    path: path.clone(),
+                    old: Some(*a),
+                    new: Some(*b),
+                });
+            }
+        }
+        (Some(RawValue::Array(_, a)), Some(RawValue::Array(_, b))) => {
+            for j in 0..a.len().max(b.len()) {
+                let (old, new) = (a.get(j), b.get(j));
+                if let (Some(x), Some(y)) = (old, new) {
+                    if x.identical(y) {
+                        continue;
+                    }
+                }
+                path.push(PathSegment::Element(j));
+                differences.push(Difference::Primitive {
+                    path: path.clone(),
+                    old: old.copied(),
+                    new: new.copied(),
+                });
+                path.pop();
+            }
+        }
+        (Some(RawValue::String(a)), Some(RawValue::String(b))) => {
+            if a != b {
+                differences.push(Difference::String {
+                    path: path.clone(),
+                    old: Some(a.to_string()),
+                    new: Some(b.to_string()),
+                });
+            }
+        }
+        (Some(RawValue::Object(a)), Some(RawValue::Object(b))) => {
+            diff_object_pair(stashmap, Some(*a), Some(*b), path, differences)?;
+        }
+        (Some(RawValue::ArrayOfObjects(a)), Some(RawValue::ArrayOfObjects(b))) => {
+            let a: Vec<ObjectRef> = a.collect();
+            let b: Vec<ObjectRef> = b.collect();
+            for j in 0..a.len().max(b.len()) {
+                path.push(PathSegment::Element(j));
+                diff_object_pair(
+                    stashmap,
+                    a.get(j).copied(),
+                    b.get(j).copied(),
+                    path,
+                    differences,
+                )?;
+                path.pop();
+            }
+        }
+        (old, new) => {
+            // Values of different types, or values that are only present
+            // on one side, are reported as a removal and/or an addition
+            if let Some(old) = old {
+                report_whole_value(old, Side::Old, path, differences);
+            }
+            if let Some(new) = new {
+                report_whole_value(new, Side::New, path, differences);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Compare two objects at the same position, either of which may be missing
+fn diff_object_pair(
+    stashmap: &StashMap,
+    old: Option<ObjectRef>,
+    new: Option<ObjectRef>,
+    path: &mut Vec<PathSegment>,
+    differences: &mut Vec<Difference>,
+) -> Result<(), UnstashError> {
+    let (old_hash, new_hash) = (old.map(|o| o.hash()), new.map(|o| o.hash()));
+    if old_hash == new_hash {
+        return Ok(());
+    }
+    differences.push(Difference::Object {
+        path: path.clone(),
+        old: old_hash,
+        new: new_hash,
+    });
+    if let (Some(old), Some(new)) = (old, new) {
+        diff_objects(stashmap, old, new, path, differences)?;
+    }
+    Ok(())
+}
+
+/// Report an entire value as having been removed or added
+fn report_whole_value(
+    value: &RawValue,
+    side: Side,
+    path: &mut Vec<PathSegment>,
+    differences: &mut Vec<Difference>,
+) {
+    fn sided<T>(side: Side, value: T) -> (Option<T>, Option<T>) {
+        match side {
+            Side::Old => (Some(value), None),
+            Side::New => (None, Some(value)),
+        }
+    }
+
+    match value {
+        RawValue::Primitive(x) => {
+            let (old, new) = sided(side, *x);
+            differences.push(Difference::Primitive {
+                path: path.clone(),
+                old,
+                new,
+            });
+        }
+        RawValue::Array(_, xs) => {
+            for (j, x) in xs.iter().enumerate() {
+                path.push(PathSegment::Element(j));
+                let (old, new) = sided(side, *x);
+                differences.push(Difference::Primitive {
+                    path: path.clone(),
+                    old,
+                    new,
+                });
+                path.pop();
+            }
+        }
+        RawValue::String(s) => {
+            let (old, new) = sided(side, s.to_string());
+            differences.push(Difference::String {
+                path: path.clone(),
+                old,
+                new,
+            });
+        }
+        RawValue::Object(object) => {
+            let (old, new) = sided(side, object.hash());
+            differences.push(Difference::Object {
+                path: path.clone(),
+                old,
+                new,
+            });
+        }
+        RawValue::ArrayOfObjects(objects) => {
+            for (j, object) in objects.enumerate() {
+                path.push(PathSegment::Element(j));
+                let (old, new) = sided(side, object.hash());
+                differences.push(Difference::Object {
+                    path: path.clone(),
+                    old,
+                    new,
+                });
+                path.pop();
+            }
+        }
+    }
+}
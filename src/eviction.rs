@@ -0,0 +1,230 @@
+use std::{
+    collections::BTreeMap,
+    marker::PhantomData,
+    sync::{
+        atomic::{self, AtomicU64},
+        Arc,
+    },
+};
+
+use crate::{
+    read_map, release_or_defer, write_map, ObjectHash, Release, SharedStashMap, StashHandle,
+    StashMap,
+};
+
+/// Which evictable root is released first when a stash exceeds
+/// its limits. See [crate::Stash::make_evictable].
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum EvictionPolicy {
+    /// Release the root that was made evictable longest ago
+    #[default]
+    Oldest,
+
+    /// Release the root that was least recently made evictable
+    /// or accessed using [EvictableHandle::get]
+    LeastRecentlyUsed,
+}
+
+/// A function that is called with the hash of every evicted root
+pub(crate) type EvictionCallback = Arc<dyn Fn(ObjectHash) + Send + Sync>;
+
+/// A root object which the stashmap holds a reference to on behalf of
+/// an [EvictableHandle], and which may be released at any time
+struct EvictableRoot {
+    hash: ObjectHash,
+
+    /// The value of [Eviction::clock] when the root was last used
+    last_used: AtomicU64,
+}
+
+/// The limits on the size of a stashmap, and the evictable roots
+/// which may be released in order to stay within them
+#[derive(Default)]
+pub(crate) struct Eviction {
+    max_bytes: Option<usize>,
+    max_objects: Option<usize>,
+    policy: EvictionPolicy,
+
+    /// Every evictable root which hasn't yet been released, by id.
+    /// Ids are assigned in increasing order, so the first entry is
+    /// always the oldest root.
+    roots: BTreeMap<u64, EvictableRoot>,
+    next_id: u64,
+
+    /// A counter which increases every time a root is used
+    clock: AtomicU64,
+
+    callback: Option<EvictionCallback>,
+}
+
+impl Eviction {
+    /// Stop tracking the root with the given id, returning false if
+    /// it was already evicted
+    pub(crate) fn remove_root(&mut self, id: u64) -> bool {
+        self.roots.remove(&id).is_some()
+    }
+
+    /// Find the id of the next root to release, if any remain
+    fn next_victim(&self) -> Option<u64> {
+        match self.policy {
+            EvictionPolicy::Oldest => self.roots.keys().next().copied(),
+            EvictionPolicy::LeastRecentlyUsed => self
+                .roots
+                .iter()
+                .min_by_key(|(id, root)| (root.last_used.load(atomic::Ordering::Relaxed), **id))
+                .map(|(id, _)| *id),
+        }
+    }
+
+    /// Record that the given root was just used
+    fn touch(&self, root: &EvictableRoot) {
+        let now = self.clock.fetch_add(1, atomic::Ordering::Relaxed);
+        root.last_used.store(now, atomic::Ordering::Relaxed);
+    }
+}
+
+impl StashMap {
+    /// Does the stashmap exceed either of its limits?
+    fn is_over_limits(&self) -> bool {
+        self.eviction
+            .max_bytes
+            .is_some_and(|max_bytes| self.total_bytes > max_bytes)
+            || self
+                .eviction
+                .max_objects
+                .is_some_and(|max_objects| self.objects.len() > max_objects)
+    }
+
+    /// Release evictable roots according to the eviction policy until
+    /// the stashmap is within its limits or no evictable roots remain,
+    /// returning the hashes of the evicted roots in the order they were
+    /// evicted. Objects are only removed once nothing else refers to them.
+    fn evict_over_limits(&mut self) -> Vec<ObjectHash> {
+        let mut evicted = Vec::new();
+        while self.is_over_limits() {
+            let Some(id) = self.eviction.next_victim() else {
+                break;
+            };
+            let root = self.eviction.roots.remove(&id).unwrap();
+            self.remove_reference(root.hash);
+            evicted.push(root.hash);
+        }
+        evicted
+    }
+}
+
+/// Release evictable roots in the shared stashmap until it is within
+/// its limits, and then notify the eviction callback of each evicted
+/// root. The callback is called after the stashmap is unlocked, so that
+/// it may freely use the stash.
+pub(crate) fn evict_over_limits(map: &SharedStashMap) {
+    let (evicted, callback) = {
+        let mut map = write_map(map);
+        if !map.is_over_limits() {
+            return;
+        }
+        (map.evict_over_limits(), map.eviction.callback.clone())
+    };
+    if let Some(callback) = callback {
+        for hash in evicted {
+            callback(hash);
+        }
+    }
+}
+
+/// Set the maximum total size of serialized objects in a stashmap
+pub(crate) fn set_max_bytes(map: &SharedStashMap, max_bytes: Option<usize>) {
+    write_map(map).eviction.max_bytes = max_bytes;
+    evict_over_limits(map);
+}
+
+/// Set the maximum number of objects in a stashmap
+pub(crate) fn set_max_objects(map: &SharedStashMap, max_objects: Option<usize>) {
+    write_map(map).eviction.max_objects = max_objects;
+    evict_over_limits(map);
+}
+
+/// Set the policy for choosing which root to evict first
+pub(crate) fn set_policy(map: &SharedStashMap, policy: EvictionPolicy) {
+    write_map(map).eviction.policy = policy;
+}
+
+/// Set the function which is called for every evicted root
+pub(crate) fn set_callback(map: &SharedStashMap, callback: Option<EvictionCallback>) {
+    write_map(map).eviction.callback = callback;
+}
+
+/// A handle to a stashed root object which the stash may release
+/// when it exceeds its limits, as created by
+/// [crate::Stash::make_evictable]. Unlike a [StashHandle], holding
+/// an EvictableHandle does not keep the object alive, and a regular
+/// handle must be retrieved using [EvictableHandle::get] before the
+/// object can be unstashed.
+pub struct EvictableHandle<T> {
+    map: SharedStashMap,
+    id: u64,
+    hash: ObjectHash,
+    _phantom_data: PhantomData<fn() -> T>,
+}
+
+impl<T> EvictableHandle<T> {
+    /// Register the object referred to by the given handle as an
+    /// evictable root, and evict roots as needed to stay within
+    /// the stash's limits
+    pub(crate) fn new(handle: StashHandle<T>) -> EvictableHandle<T> {
+        let map = Arc::clone(&handle.map);
+        let hash = handle.hash;
+        let id = {
+            let mut stashmap = write_map(&map);
+            stashmap.add_reference(hash);
+            let eviction = &mut stashmap.eviction;
+            let id = eviction.next_id;
+            eviction.next_id += 1;
+            let root = EvictableRoot {
+                hash,
+                last_used: AtomicU64::new(0),
+            };
+            eviction.touch(&root);
+            eviction.roots.insert(id, root);
+            id
+        };
+        std::mem::drop(handle);
+        evict_over_limits(&map);
+        EvictableHandle {
+            map,
+            id,
+            hash,
+            _phantom_data: PhantomData,
+        }
+    }
+
+    /// Get the hash of the object, whether or not it was evicted
+    pub fn object_hash(&self) -> ObjectHash {
+        self.hash
+    }
+
+    /// Has the object been evicted from the stash?
+    pub fn is_evicted(&self) -> bool {
+        !read_map(&self.map).eviction.roots.contains_key(&self.id)
+    }
+
+    /// Get a regular handle to the object, or None if it has been
+    /// evicted. This counts as a use of the object for the purposes
+    /// of [EvictionPolicy::LeastRecentlyUsed]. The returned handle
+    /// keeps the object alive as usual, even if it is evicted later.
+    pub fn get(&self) -> Option<StashHandle<T>> {
+        let map = read_map(&self.map);
+        let root = map.eviction.roots.get(&self.id)?;
+        map.eviction.touch(root);
+        map.add_reference(self.hash);
+        Some(StashHandle::new(Arc::clone(&self.map), self.hash))
+    }
+}
+
+/// Dropping an EvictableHandle releases its object if it has not
+/// already been evicted
+impl<T> Drop for EvictableHandle<T> {
+    fn drop(&mut self) {
+        release_or_defer(&self.map, Release::EvictableRoot(self.id, self.hash));
+    }
+}
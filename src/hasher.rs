@@ -0,0 +1,133 @@
+#[cfg(not(feature = "hash128"))]
+use std::hash::Hasher;
+
+use crate::{ObjectHash, Stasher};
+
+/// The integer type holding the bits of an [ObjectHash]
+#[cfg(not(feature = "hash128"))]
+pub(crate) type HashValue = u64;
+
+/// The integer type holding the bits of an [ObjectHash]
+#[cfg(feature = "hash128")]
+pub(crate) type HashValue = u128;
+
+/// The hash function used to compute every [ObjectHash], chosen
+/// by whether the `hash128` feature is enabled. Cargo unifies features
+/// across the whole dependency graph, so if any crate in a build enables
+/// `hash128`, every user of hashstash in that build gets 128-bit hashes.
+#[cfg(not(feature = "hash128"))]
+pub(crate) type DefaultObjectHasher = SeaHasher64;
+
+/// The hash function used to compute every [ObjectHash], chosen
+/// by whether the `hash128` feature is enabled
+#[cfg(feature = "hash128")]
+pub(crate) type DefaultObjectHasher = Xxh3Hasher128;
+
+/// Trait for the hash functions that compute [ObjectHash] values,
+/// used both when stashing objects and when combining hashes
+pub(crate) trait ObjectHasher {
+    /// Create a new hasher in its initial state
+    fn new() -> Self;
+
+    /// Hash a slice of raw bytes
+    fn write(&mut self, bytes: &[u8]);
+
+    /// Hash a single u32 value
+    fn write_u32(&mut self, x: u32);
+
+    /// Hash the hash of another object
+    fn write_hash(&mut self, hash: ObjectHash);
+
+    /// Get the hash of everything written so far
+    fn finish(&self) -> ObjectHash;
+}
+
+/// The original 64-bit hash function, based on SeaHash
+#[cfg(not(feature = "hash128"))]
+pub(crate) struct SeaHasher64(seahash::SeaHasher);
+
+#[cfg(not(feature = "hash128"))]
+impl ObjectHasher for SeaHasher64 {
+    fn new() -> Self {
+        SeaHasher64(seahash::SeaHasher::new())
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.write(bytes);
+    }
+
+    fn write_u32(&mut self, x: u32) {
+        self.0.write_u32(x);
+    }
+
+    fn write_hash(&mut self, hash: ObjectHash) {
+        self.0.write_u64(hash.0);
+    }
+
+    fn finish(&self) -> ObjectHash {
+        ObjectHash(self.0.finish())
+    }
+}
+
+/// A 128-bit hash function based on XXH3, which computes a full
+/// 128-bit digest rather than combining narrower ones. This is not
+/// a cryptographic hash, but it makes accidental collisions vastly
+/// less likely in very large stashes.
+#[cfg(feature = "hash128")]
+pub(crate) struct Xxh3Hasher128(xxhash_rust::xxh3::Xxh3);
+
+#[cfg(feature = "hash128")]
+impl ObjectHasher for Xxh3Hasher128 {
+    fn new() -> Self {
+        Xxh3Hasher128(xxhash_rust::xxh3::Xxh3::new())
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn write_u32(&mut self, x: u32) {
+        self.0.update(&x.to_be_bytes());
+    }
+
+    fn write_hash(&mut self, hash: ObjectHash) {
+        self.0.update(&hash.0.to_be_bytes());
+    }
+
+    fn finish(&self) -> ObjectHash {
+        ObjectHash(self.0.digest128())
+    }
+}
+
+impl ObjectHash {
+    /// The number of bytes in a hash
+    pub(crate) const SIZE: usize = std::mem::size_of::<HashValue>();
+
+    /// Get the bits of the hash as big-endian bytes
+    pub(crate) fn to_be_bytes(self) -> [u8; Self::SIZE] {
+        self.0.to_be_bytes()
+    }
+
+    /// Create a hash from big-endian bytes as returned by to_be_bytes()
+    pub(crate) fn from_be_bytes(bytes: [u8; Self::SIZE]) -> ObjectHash {
+        ObjectHash(HashValue::from_be_bytes(bytes))
+    }
+
+    /// Stash the bits of the hash itself as one or more primitives
+    pub(crate) fn stash_bits<C: Copy>(self, stasher: &mut Stasher<C>) {
+        #[cfg(not(feature = "hash128"))]
+        stasher.u64(self.0);
+
+        #[cfg(feature = "hash128")]
+        {
+            stasher.u64((self.0 >> 64) as u64);
+            stasher.u64(self.0 as u64);
+        }
+    }
+}
+
+impl std::fmt::Display for ObjectHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:0width$x}", self.0, width = 2 * Self::SIZE)
+    }
+}
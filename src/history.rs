@@ -0,0 +1,173 @@
+use crate::{ObjectHash, Stash, StashHandle, Stashable, UnstashError, UnstashableInplace};
+
+/// A linear undo/redo history of the states of an object, stored
+/// in a [Stash] that the History owns. States are recorded with
+/// [History::push] and restored in place with [History::undo]
+/// and [History::redo]. Since the stash deduplicates stashed
+/// contents, recording many similar states is cheap.
+pub struct History<T> {
+    /// The stash holding every recorded state
+    stash: Stash,
+
+    /// Handles to past states, oldest first. The last handle,
+    /// if any, is the current state.
+    undo_stack: Vec<StashHandle<T>>,
+
+    /// Handles to states that were undone, most recently undone last
+    redo_stack: Vec<StashHandle<T>>,
+
+    /// The maximum number of states kept in the undo stack
+    max_depth: usize,
+}
+
+impl<T> History<T> {
+    /// Create a new empty History without a limit on its depth
+    pub fn new() -> History<T> {
+        History::with_max_depth(usize::MAX)
+    }
+
+    /// Create a new empty History which keeps at most the given
+    /// number of states to undo to, including the current state.
+    /// Older states are dropped as new states are pushed.
+    pub fn with_max_depth(max_depth: usize) -> History<T> {
+        History {
+            stash: Stash::new(),
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            max_depth: max_depth.max(1),
+        }
+    }
+
+    /// Get the stash in which all states are stored
+    pub fn stash(&self) -> &Stash {
+        &self.stash
+    }
+
+    /// Get the maximum number of states kept in the undo stack
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    /// Get a handle to the current state, if any state has been pushed
+    pub fn head(&self) -> Option<&StashHandle<T>> {
+        self.undo_stack.last()
+    }
+
+    /// Is there a previous state to undo to?
+    pub fn can_undo(&self) -> bool {
+        self.undo_stack.len() > 1
+    }
+
+    /// Is there an undone state to redo?
+    pub fn can_redo(&self) -> bool {
+        !self.redo_stack.is_empty()
+    }
+
+    /// Remove all recorded states
+    pub fn clear(&mut self) {
+        self.undo_stack.clear();
+        self.redo_stack.clear();
+    }
+
+    /// Add a handle to a new current state, discarding the redo
+    /// stack and dropping the oldest states beyond the max depth
+    fn push_handle(&mut self, handle: StashHandle<T>) {
+        self.redo_stack.clear();
+        self.undo_stack.push(handle);
+        if self.undo_stack.len() > self.max_depth {
+            let excess = self.undo_stack.len() - self.max_depth;
+            self.undo_stack.drain(..excess);
+        }
+    }
+
+    /// Is the given hash the same as that of the current state?
+    fn is_head(&self, hash: ObjectHash) -> bool {
+        self.head().map(|h| h.object_hash()) == Some(hash)
+    }
+
+    /// Record the given object's state as the new current state,
+    /// discarding any states that were undone. Returns false and
+    /// does nothing if the object's hash is the same as that of
+    /// the current state.
+    pub fn push(&mut self, object: &T) -> bool
+    where
+        T: Stashable,
+    {
+        self.push_with_context(object, ())
+    }
+
+    pub fn push_with_context<C: Copy>(&mut self, object: &T, context: C) -> bool
+    where
+        T: Stashable<C>,
+    {
+        if self.is_head(ObjectHash::from_stashable_and_context(object, context)) {
+            return false;
+        }
+        let handle = self.stash.stash_with_context(object, context);
+        self.push_handle(handle);
+        true
+    }
+
+    /// Restore the given object to the state before the current
+    /// state. Returns false and leaves the object unmodified if
+    /// there is nothing to undo.
+    pub fn undo(&mut self, object: &mut T) -> Result<bool, UnstashError>
+    where
+        T: UnstashableInplace,
+    {
+        self.undo_with_context(object, ())
+    }
+
+    pub fn undo_with_context<C: Copy>(
+        &mut self,
+        object: &mut T,
+        context: C,
+    ) -> Result<bool, UnstashError>
+    where
+        T: UnstashableInplace<C>,
+    {
+        if !self.can_undo() {
+            return Ok(false);
+        }
+        let previous = &self.undo_stack[self.undo_stack.len() - 2];
+        self.stash
+            .unstash_inplace_with_context(previous, object, context)?;
+        let current = self.undo_stack.pop().unwrap();
+        self.redo_stack.push(current);
+        Ok(true)
+    }
+
+    /// Restore the given object to the state that was most recently
+    /// undone. Returns false and leaves the object unmodified if
+    /// there is nothing to redo.
+    pub fn redo(&mut self, object: &mut T) -> Result<bool, UnstashError>
+    where
+        T: UnstashableInplace,
+    {
+        self.redo_with_context(object, ())
+    }
+
+    pub fn redo_with_context<C: Copy>(
+        &mut self,
+        object: &mut T,
+        context: C,
+    ) -> Result<bool, UnstashError>
+    where
+        T: UnstashableInplace<C>,
+    {
+        let Some(next) = self.redo_stack.last() else {
+            return Ok(false);
+        };
+        self.stash
+            .unstash_inplace_with_context(next, object, context)?;
+        let next = self.redo_stack.pop().unwrap();
+        self.undo_stack.push(next);
+        Ok(true)
+    }
+}
+
+impl<T> Default for History<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
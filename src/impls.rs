@@ -0,0 +1,460 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    hash::{BuildHasher, Hash},
+};
+
+use crate::{
+    InplaceUnstasher, Order, Stashable, Stasher, UnstashError, UnstashErrorKind, Unstashable,
+    UnstashableInplace, Unstasher,
+};
+
+/// Implement the stashing traits for a primitive type using the
+/// given [Stasher], [Unstasher] and [InplaceUnstasher] methods
+macro_rules! impl_primitive {
+    ($t:ty, $method:ident, $method_inplace:ident) => {
+        impl<C: Copy> Stashable<C> for $t {
+            fn stash(&self, stasher: &mut Stasher<C>) {
+                stasher.$method(*self);
+            }
+        }
+
+        impl<C: Copy> Unstashable<C> for $t {
+            fn unstash(unstasher: &mut Unstasher<C>) -> Result<Self, UnstashError> {
+                unstasher.$method()
+            }
+        }
+
+        impl<C: Copy> UnstashableInplace<C> for $t {
+            fn unstash_inplace(
+                &mut self,
+                unstasher: &mut InplaceUnstasher<C>,
+            ) -> Result<(), UnstashError> {
+                unstasher.$method_inplace(self)
+            }
+        }
+    };
+}
+
+impl_primitive!(bool, bool, bool_inplace);
+impl_primitive!(u8, u8, u8_inplace);
+impl_primitive!(i8, i8, i8_inplace);
+impl_primitive!(u16, u16, u16_inplace);
+impl_primitive!(i16, i16, i16_inplace);
+impl_primitive!(u32, u32, u32_inplace);
+impl_primitive!(i32, i32, i32_inplace);
+impl_primitive!(u64, u64, u64_inplace);
+impl_primitive!(i64, i64, i64_inplace);
+impl_primitive!(usize, usize, usize_inplace);
+impl_primitive!(isize, isize, isize_inplace);
+impl_primitive!(f32, f32, f32_inplace);
+impl_primitive!(f64, f64, f64_inplace);
+impl_primitive!(u128, u128, u128_inplace);
+impl_primitive!(i128, i128, i128_inplace);
+impl_primitive!(char, char, char_inplace);
+
+impl<C: Copy> Stashable<C> for str {
+    fn stash(&self, stasher: &mut Stasher<C>) {
+        stasher.string(self);
+    }
+}
+
+impl<C: Copy> Stashable<C> for String {
+    fn stash(&self, stasher: &mut Stasher<C>) {
+        stasher.string(self);
+    }
+}
+
+impl<C: Copy> Unstashable<C> for String {
+    fn unstash(unstasher: &mut Unstasher<C>) -> Result<Self, UnstashError> {
+        unstasher.string()
+    }
+}
+
+impl<C: Copy> UnstashableInplace<C> for String {
+    fn unstash_inplace(&mut self, unstasher: &mut InplaceUnstasher<C>) -> Result<(), UnstashError> {
+        unstasher.string_inplace(self)
+    }
+}
+
+impl<C> Stashable<C> for () {
+    fn stash(&self, _stasher: &mut Stasher<C>) {}
+}
+
+impl<C> Unstashable<C> for () {
+    fn unstash(_unstasher: &mut Unstasher<C>) -> Result<Self, UnstashError> {
+        Ok(())
+    }
+}
+
+impl<C> UnstashableInplace<C> for () {
+    fn unstash_inplace(
+        &mut self,
+        _unstasher: &mut InplaceUnstasher<C>,
+    ) -> Result<(), UnstashError> {
+        Ok(())
+    }
+}
+
+/// Implement the stashing traits for a tuple type, with each
+/// element being stashed as a separate object
+macro_rules! impl_tuple {
+    ($($name:ident $index:tt),+) => {
+        impl<C: Copy, $($name: Stashable<C>),+> Stashable<C> for ($($name,)+) {
+            fn stash(&self, stasher: &mut Stasher<C>) {
+                $(stasher.object(&self.$index);)+
+            }
+        }
+
+        impl<C: Copy, $($name: 'static + Unstashable<C>),+> Unstashable<C> for ($($name,)+) {
+            fn unstash(unstasher: &mut Unstasher<C>) -> Result<Self, UnstashError> {
+                Ok(($(unstasher.object::<$name>()?,)+))
+            }
+        }
+
+        impl<C: Copy, $($name: UnstashableInplace<C>),+> UnstashableInplace<C> for ($($name,)+) {
+            fn unstash_inplace(
+                &mut self,
+                unstasher: &mut InplaceUnstasher<C>,
+            ) -> Result<(), UnstashError> {
+                $(unstasher.object_inplace(&mut self.$index)?;)+
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_tuple!(A 0);
+impl_tuple!(A 0, B 1);
+impl_tuple!(A 0, B 1, D 2);
+impl_tuple!(A 0, B 1, D 2, E 3);
+impl_tuple!(A 0, B 1, D 2, E 3, F 4);
+impl_tuple!(A 0, B 1, D 2, E 3, F 4, G 5);
+impl_tuple!(A 0, B 1, D 2, E 3, F 4, G 5, H 6);
+impl_tuple!(A 0, B 1, D 2, E 3, F 4, G 5, H 6, I 7);
+
+/// Boxes are transparent and stash the same contents as
+/// the value that they hold.
+impl<C, T: ?Sized + Stashable<C>> Stashable<C> for Box<T> {
+    fn stash(&self, stasher: &mut Stasher<C>) {
+        T::stash(self, stasher);
+    }
+}
+
+impl<C, T: Unstashable<C>> Unstashable<C> for Box<T> {
+    fn unstash(unstasher: &mut Unstasher<C>) -> Result<Self, UnstashError> {
+        Ok(Box::new(T::unstash(unstasher)?))
+    }
+}
+
+impl<C, T: ?Sized + UnstashableInplace<C>> UnstashableInplace<C> for Box<T> {
+    fn unstash_inplace(&mut self, unstasher: &mut InplaceUnstasher<C>) -> Result<(), UnstashError> {
+        T::unstash_inplace(self, unstasher)
+    }
+}
+
+impl<C: Copy, T: Stashable<C>> Stashable<C> for Vec<T> {
+    fn stash(&self, stasher: &mut Stasher<C>) {
+        stasher.array_of_objects_slice(self, Order::Ordered);
+    }
+}
+
+impl<C: Copy, T: 'static + Unstashable<C>> Unstashable<C> for Vec<T> {
+    fn unstash(unstasher: &mut Unstasher<C>) -> Result<Self, UnstashError> {
+        unstasher.array_of_objects_vec()
+    }
+}
+
+/// Existing elements are unstashed in place, additional
+/// elements are appended, and extra elements are removed.
+impl<C: Copy, T: Unstashable<C> + UnstashableInplace<C>> UnstashableInplace<C> for Vec<T> {
+    fn unstash_inplace(&mut self, unstasher: &mut InplaceUnstasher<C>) -> Result<(), UnstashError> {
+        let mut length = 0;
+        unstasher.array_of_proxy_objects_inplace(|unstasher| {
+            if let Some(item) = self.get_mut(length) {
+                item.unstash_inplace(unstasher)?;
+            } else {
+                let item = unstasher.unstash_always()?;
+                if unstasher.time_to_write() {
+                    self.push(item);
+                }
+            }
+            length += 1;
+            Ok(())
+        })?;
+        if unstasher.time_to_write() {
+            self.truncate(length);
+        }
+        Ok(())
+    }
+}
+
+impl<C: Copy, T: Stashable<C>> Stashable<C> for VecDeque<T> {
+    fn stash(&self, stasher: &mut Stasher<C>) {
+        stasher.array_of_objects_iter(self.iter(), Order::Ordered);
+    }
+}
+
+impl<C: Copy, T: 'static + Unstashable<C>> Unstashable<C> for VecDeque<T> {
+    fn unstash(unstasher: &mut Unstasher<C>) -> Result<Self, UnstashError> {
+        unstasher.array_of_objects_iter()?.collect()
+    }
+}
+
+/// Existing elements are unstashed in place, additional elements
+/// are pushed to the back, and extra elements are removed.
+impl<C: Copy, T: Unstashable<C> + UnstashableInplace<C>> UnstashableInplace<C> for VecDeque<T> {
+    fn unstash_inplace(&mut self, unstasher: &mut InplaceUnstasher<C>) -> Result<(), UnstashError> {
+        let mut length = 0;
+        unstasher.array_of_proxy_objects_inplace(|unstasher| {
+            if let Some(item) = self.get_mut(length) {
+                item.unstash_inplace(unstasher)?;
+            } else {
+                let item = unstasher.unstash_always()?;
+                if unstasher.time_to_write() {
+                    self.push_back(item);
+                }
+            }
+            length += 1;
+            Ok(())
+        })?;
+        if unstasher.time_to_write() {
+            self.truncate(length);
+        }
+        Ok(())
+    }
+}
+
+impl<C: Copy, T: Stashable<C>, const N: usize> Stashable<C> for [T; N] {
+    fn stash(&self, stasher: &mut Stasher<C>) {
+        stasher.array_of_objects_slice(self, Order::Ordered);
+    }
+}
+
+/// Unstashing an array with a different number of elements
+/// results in [crate::UnstashErrorKind::BadValue]
+impl<C: Copy, T: 'static + Unstashable<C>, const N: usize> Unstashable<C> for [T; N] {
+    fn unstash(unstasher: &mut Unstasher<C>) -> Result<Self, UnstashError> {
+        let items: Vec<T> = unstasher.array_of_objects_vec()?;
+        items
+            .try_into()
+            .map_err(|_| UnstashError::new(UnstashErrorKind::BadValue))
+    }
+}
+
+impl<C: Copy, T: UnstashableInplace<C>, const N: usize> UnstashableInplace<C> for [T; N] {
+    fn unstash_inplace(&mut self, unstasher: &mut InplaceUnstasher<C>) -> Result<(), UnstashError> {
+        let mut length = 0;
+        unstasher.array_of_proxy_objects_inplace(|unstasher| {
+            let item = self
+                .get_mut(length)
+                .ok_or(UnstashError::new(UnstashErrorKind::BadValue))?;
+            length += 1;
+            item.unstash_inplace(unstasher)
+        })?;
+        if length != N {
+            return Err(UnstashErrorKind::BadValue.into());
+        }
+        Ok(())
+    }
+}
+
+/// Sets are stashed as unordered arrays of objects
+impl<C: Copy, T: Stashable<C>, S> Stashable<C> for HashSet<T, S> {
+    fn stash(&self, stasher: &mut Stasher<C>) {
+        stasher.array_of_objects_iter(self.iter(), Order::Unordered);
+    }
+}
+
+impl<C: Copy, T: 'static + Unstashable<C> + Eq + Hash, S: BuildHasher + Default> Unstashable<C>
+    for HashSet<T, S>
+{
+    fn unstash(unstasher: &mut Unstasher<C>) -> Result<Self, UnstashError> {
+        unstasher.array_of_objects_iter()?.collect()
+    }
+}
+
+/// Elements that are already present are kept, elements that are no
+/// longer present are removed, and new elements are inserted.
+impl<C: Copy, T: Unstashable<C> + Eq + Hash, S: BuildHasher + Default> UnstashableInplace<C>
+    for HashSet<T, S>
+{
+    fn unstash_inplace(&mut self, unstasher: &mut InplaceUnstasher<C>) -> Result<(), UnstashError> {
+        let mut items = HashSet::<T, S>::default();
+        unstasher.array_of_proxy_objects(|unstasher| {
+            items.insert(T::unstash(unstasher)?);
+            Ok(())
+        })?;
+        if unstasher.time_to_write() {
+            self.retain(|item| items.contains(item));
+            self.extend(items);
+        }
+        Ok(())
+    }
+}
+
+impl<C: Copy, T: Stashable<C>> Stashable<C> for BTreeSet<T> {
+    fn stash(&self, stasher: &mut Stasher<C>) {
+        stasher.array_of_objects_iter(self.iter(), Order::Ordered);
+    }
+}
+
+impl<C: Copy, T: 'static + Unstashable<C> + Ord> Unstashable<C> for BTreeSet<T> {
+    fn unstash(unstasher: &mut Unstasher<C>) -> Result<Self, UnstashError> {
+        unstasher.array_of_objects_iter()?.collect()
+    }
+}
+
+/// Elements that are already present are kept, elements that are no
+/// longer present are removed, and new elements are inserted.
+impl<C: Copy, T: Unstashable<C> + Ord> UnstashableInplace<C> for BTreeSet<T> {
+    fn unstash_inplace(&mut self, unstasher: &mut InplaceUnstasher<C>) -> Result<(), UnstashError> {
+        let mut items = BTreeSet::new();
+        unstasher.array_of_proxy_objects(|unstasher| {
+            items.insert(T::unstash(unstasher)?);
+            Ok(())
+        })?;
+        if unstasher.time_to_write() {
+            self.retain(|item| items.contains(item));
+            self.extend(items);
+        }
+        Ok(())
+    }
+}
+
+/// Maps are stashed as arrays of entries, where each entry is an
+/// object containing the key and value as separate objects
+impl<C: Copy, K: Stashable<C>, V: Stashable<C>, S> Stashable<C> for HashMap<K, V, S> {
+    fn stash(&self, stasher: &mut Stasher<C>) {
+        stasher.array_of_proxy_objects(
+            self.iter(),
+            |(key, value), stasher| {
+                stasher.object(*key);
+                stasher.object(*value);
+            },
+            Order::Unordered,
+        );
+    }
+}
+
+impl<C, K, V, S> Unstashable<C> for HashMap<K, V, S>
+where
+    C: Copy,
+    K: 'static + Unstashable<C> + Eq + Hash,
+    V: 'static + Unstashable<C>,
+    S: BuildHasher + Default,
+{
+    fn unstash(unstasher: &mut Unstasher<C>) -> Result<Self, UnstashError> {
+        let mut map = HashMap::default();
+        unstasher.array_of_proxy_objects(|unstasher| {
+            let key = unstasher.object()?;
+            let value = unstasher.object()?;
+            map.insert(key, value);
+            Ok(())
+        })?;
+        Ok(map)
+    }
+}
+
+/// Values whose keys are already present are unstashed in place,
+/// entries whose keys are no longer present are removed, and new
+/// entries are inserted.
+impl<C, K, V, S> UnstashableInplace<C> for HashMap<K, V, S>
+where
+    C: Copy,
+    K: 'static + Unstashable<C> + Eq + Hash,
+    V: 'static + Unstashable<C> + UnstashableInplace<C>,
+    S: BuildHasher + Default,
+{
+    fn unstash_inplace(&mut self, unstasher: &mut InplaceUnstasher<C>) -> Result<(), UnstashError> {
+        let mut previous = if unstasher.time_to_write() {
+            std::mem::take(self)
+        } else {
+            HashMap::default()
+        };
+        unstasher.array_of_proxy_objects_inplace(|unstasher| {
+            let key: K = unstasher.object_always()?;
+            if unstasher.time_to_write() {
+                let value = match previous.remove(&key) {
+                    Some(mut value) => {
+                        unstasher.object_inplace(&mut value)?;
+                        value
+                    }
+                    None => unstasher.object_always()?,
+                };
+                self.insert(key, value);
+            } else if let Some(value) = self.get_mut(&key) {
+                unstasher.object_inplace(value)?;
+            } else {
+                unstasher.object_always::<V>()?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl<C: Copy, K: Stashable<C>, V: Stashable<C>> Stashable<C> for BTreeMap<K, V> {
+    fn stash(&self, stasher: &mut Stasher<C>) {
+        stasher.array_of_proxy_objects(
+            self.iter(),
+            |(key, value), stasher| {
+                stasher.object(*key);
+                stasher.object(*value);
+            },
+            Order::Ordered,
+        );
+    }
+}
+
+impl<C, K, V> Unstashable<C> for BTreeMap<K, V>
+where
+    C: Copy,
+    K: 'static + Unstashable<C> + Ord,
+    V: 'static + Unstashable<C>,
+{
+    fn unstash(unstasher: &mut Unstasher<C>) -> Result<Self, UnstashError> {
+        let mut map = BTreeMap::new();
+        unstasher.array_of_proxy_objects(|unstasher| {
+            let key = unstasher.object()?;
+            let value = unstasher.object()?;
+            map.insert(key, value);
+            Ok(())
+        })?;
+        Ok(map)
+    }
+}
+
+/// Values whose keys are already present are unstashed in place,
+/// entries whose keys are no longer present are removed, and new
+/// entries are inserted.
+impl<C, K, V> UnstashableInplace<C> for BTreeMap<K, V>
+where
+    C: Copy,
+    K: 'static + Unstashable<C> + Ord,
+    V: 'static + Unstashable<C> + UnstashableInplace<C>,
+{
+    fn unstash_inplace(&mut self, unstasher: &mut InplaceUnstasher<C>) -> Result<(), UnstashError> {
+        let mut previous = if unstasher.time_to_write() {
+            std::mem::take(self)
+        } else {
+            BTreeMap::new()
+        };
+        unstasher.array_of_proxy_objects_inplace(|unstasher| {
+            let key: K = unstasher.object_always()?;
+            if unstasher.time_to_write() {
+                let value = match previous.remove(&key) {
+                    Some(mut value) => {
+                        unstasher.object_inplace(&mut value)?;
+                        value
+                    }
+                    None => unstasher.object_always()?,
+                };
+                self.insert(key, value);
+            } else if let Some(value) = self.get_mut(&key) {
+                unstasher.object_inplace(value)?;
+            } else {
+                unstasher.object_always::<V>()?;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,262 @@
+use std::{collections::HashMap, fmt, sync::atomic};
+
+use crate::{
+    unstasher::{ObjectRef, ObjectRefs, RawValue},
+    ObjectHash, PrimitiveType, PrimitiveValue, StashMap, UnstashError, UnstashErrorKind,
+};
+
+/// A single stashed value decoded without knowing the Rust type of
+/// the object that stashed it, as returned by [crate::Stash::inspect].
+///
+/// Formatting a Value with `{}` prints it on a single line, while
+/// `{:#}` prints nested objects and arrays of objects indented over
+/// multiple lines.
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    /// A fixed-size primitive
+    Primitive(PrimitiveValue),
+
+    /// An array of primitives, all of the given type
+    Array(PrimitiveType, Vec<PrimitiveValue>),
+
+    /// A utf-8 encoded string
+    String(String),
+
+    /// Another stashed object and its contents
+    Object(Box<ObjectValue>),
+
+    /// An array of stashed objects and their contents
+    ArrayOfObjects(Vec<ObjectValue>),
+}
+
+/// The decoded contents of a single stashed object
+#[derive(Clone, PartialEq, Debug)]
+pub struct ObjectValue {
+    /// The hash of the stashed object
+    pub hash: ObjectHash,
+
+    /// The version number of the stashed object, or 0 if it has none
+    pub version: u32,
+
+    /// The fingerprint of the type name the stashed object was stashed
+    /// with, if any. See [crate::Stasher::type_name].
+    pub type_fingerprint: Option<u64>,
+
+    /// The values stashed by the object, in the order they were stashed
+    pub values: Vec<Value>,
+}
+
+/// The maximum number of nested objects that can be inspected, beyond
+/// which inspecting fails with [UnstashErrorKind::TooDeep]. This also
+/// bounds the recursion needed to format or drop the resulting [Value].
+pub(crate) const MAX_INSPECT_DEPTH: usize = 1024;
+
+/// An object whose values are partially decoded, waiting for the
+/// object it refers to next to be decoded
+struct PartialObject<'a> {
+    hash: ObjectHash,
+    version: u32,
+    type_fingerprint: Option<u64>,
+
+    /// The values which are yet to be decoded
+    raw_values: std::vec::IntoIter<RawValue<'a>>,
+
+    /// The values decoded so far
+    values: Vec<Value>,
+
+    /// The array of objects currently being decoded, if any, and the
+    /// objects decoded from it so far
+    array: Option<(ObjectRefs<'a>, Vec<ObjectValue>)>,
+}
+
+impl<'a> PartialObject<'a> {
+    fn new(stashmap: &'a StashMap, object: ObjectRef<'a>) -> Result<Self, UnstashError> {
+        let (version, type_fingerprint, raw_values) = stashmap.read_raw_values(object)?;
+        Ok(PartialObject {
+            hash: object.hash(),
+            version,
+            type_fingerprint,
+            raw_values: raw_values.into_iter(),
+            values: Vec::new(),
+            array: None,
+        })
+    }
+
+    /// Decode values until reaching an object which has not been
+    /// decoded already, and return it. Returns None once every value
+    /// has been decoded.
+    fn next_object(&mut self, decoded: &HashMap<ObjectHash, ObjectValue>) -> Option<ObjectRef<'a>> {
+        loop {
+            if let Some((objects, array_values)) = &mut self.array {
+                match objects.next() {
+                    Some(object) => match decoded.get(&object.hash()) {
+                        Some(value) => array_values.push(value.clone()),
+                        None => return Some(object),
+                    },
+                    None => {
+                        let (_, array_values) = self.array.take().unwrap();
+                        self.values.push(Value::ArrayOfObjects(array_values));
+                    }
+                }
+                continue;
+            }
+            match self.raw_values.next()? {
+                RawValue::Primitive(x) => self.values.push(Value::Primitive(x)),
+                RawValue::Array(prim_type, xs) => self.values.push(Value::Array(prim_type, xs)),
+                RawValue::String(s) => self.values.push(Value::String(s.to_string())),
+                RawValue::Object(object) => match decoded.get(&object.hash()) {
+                    Some(value) => self.values.push(Value::Object(Box::new(value.clone()))),
+                    None => return Some(object),
+                },
+                RawValue::ArrayOfObjects(objects) => self.array = Some((objects, Vec::new())),
+            }
+        }
+    }
+
+    /// Add the decoded contents of the object last returned by
+    /// [Self::next_object]
+    fn add_object(&mut self, value: ObjectValue) {
+        match &mut self.array {
+            Some((_, array_values)) => array_values.push(value),
+            None => self.values.push(Value::Object(Box::new(value))),
+        }
+    }
+
+    fn finish(self) -> ObjectValue {
+        ObjectValue {
+            hash: self.hash,
+            version: self.version,
+            type_fingerprint: self.type_fingerprint,
+            values: self.values,
+        }
+    }
+}
+
+/// Decode the stashed object with the given hash and every object it
+/// depends on. An explicit stack of partially decoded objects is used
+/// instead of recursion, and stashed objects which are referenced more
+/// than once are only decoded the first time and cloned afterwards.
+pub(crate) fn inspect(stashmap: &StashMap, object: ObjectRef) -> Result<ObjectValue, UnstashError> {
+    // Decoded objects which may be referred to again
+    let mut decoded: HashMap<ObjectHash, ObjectValue> = HashMap::new();
+
+    let mut stack = vec![PartialObject::new(stashmap, object)?];
+    loop {
+        let partial = stack.last_mut().unwrap();
+        if let Some(object) = partial.next_object(&decoded) {
+            if stack.len() >= MAX_INSPECT_DEPTH {
+                return Err(UnstashErrorKind::TooDeep.into());
+            }
+            stack.push(PartialObject::new(stashmap, object)?);
+            continue;
+        }
+
+        let value = stack.pop().unwrap().finish();
+        let shared = stashmap
+            .objects
+            .get(&value.hash)
+            .is_some_and(|object| object.reference_count.load(atomic::Ordering::Relaxed) > 1);
+        if shared {
+            decoded.insert(value.hash, value.clone());
+        }
+        match stack.last_mut() {
+            Some(parent) => parent.add_object(value),
+            None => return Ok(value),
+        }
+    }
+}
+
+/// Write the given number of levels of indentation
+fn write_indent(f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+    for _ in 0..depth {
+        f.write_str("    ")?;
+    }
+    Ok(())
+}
+
+/// Write a comma-separated list of items enclosed by the given
+/// delimiters, either on one line or with one item per line
+fn write_list<I, F>(
+    f: &mut fmt::Formatter<'_>,
+    open: &str,
+    close: &str,
+    items: I,
+    depth: usize,
+    mut write_item: F,
+) -> fmt::Result
+where
+    I: ExactSizeIterator,
+    F: FnMut(&mut fmt::Formatter<'_>, I::Item, usize) -> fmt::Result,
+{
+    let multiline = f.alternate() && items.len() > 0;
+    f.write_str(open)?;
+    for (i, item) in items.enumerate() {
+        if multiline {
+            f.write_str("\n")?;
+            write_indent(f, depth + 1)?;
+        } else if i > 0 {
+            f.write_str(", ")?;
+        }
+        write_item(f, item, depth + 1)?;
+        if multiline {
+            f.write_str(",")?;
+        }
+    }
+    if multiline {
+        f.write_str("\n")?;
+        write_indent(f, depth)?;
+    }
+    f.write_str(close)
+}
+
+impl Value {
+    fn write(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        match self {
+            Value::Primitive(x) => write!(f, "{}", x),
+            Value::Array(_, xs) => {
+                f.write_str("[")?;
+                for (i, x) in xs.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", x)?;
+                }
+                f.write_str("]")
+            }
+            Value::String(s) => write!(f, "{:?}", s),
+            Value::Object(object) => object.write(f, depth),
+            Value::ArrayOfObjects(objects) => {
+                write_list(f, "[", "]", objects.iter(), depth, |f, object, depth| {
+                    object.write(f, depth)
+                })
+            }
+        }
+    }
+}
+
+impl ObjectValue {
+    fn write(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        write!(f, "#{} ", self.hash)?;
+        if self.version != 0 {
+            write!(f, "v{} ", self.version)?;
+        }
+        if let Some(fingerprint) = self.type_fingerprint {
+            write!(f, "type:{:016x} ", fingerprint)?;
+        }
+        write_list(f, "{", "}", self.values.iter(), depth, |f, value, depth| {
+            value.write(f, depth)
+        })
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+impl fmt::Display for ObjectValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
@@ -0,0 +1,162 @@
+use std::sync::OnceLock;
+
+use crate::{
+    read_map_for_unstashing, unstasher::ObjectRef, InplaceUnstasher, StashHandle, Stashable,
+    Stasher, UnstashError, Unstashable, UnstashableInplace, Unstasher,
+};
+
+/// A value which is only unstashed when it is first accessed. When a
+/// Lazy is unstashed, it merely holds a [StashHandle] to its stashed
+/// contents, which keeps them alive, and its value is unstashed on the
+/// first call to [Lazy::get]. This allows large object graphs to be
+/// loaded piece by piece as they are needed.
+///
+/// A Lazy is stashed as an object containing a single reference to its
+/// stashed value, and so it is most useful for values that are large.
+/// Values which are small enough to be stored inline are unstashed
+/// right away.
+#[derive(Clone)]
+pub struct Lazy<T> {
+    /// The value, once it has been unstashed or if it was given directly
+    value: OnceLock<T>,
+
+    /// A handle to the stashed value, if the value was unstashed and
+    /// has not been modified since
+    handle: Option<StashHandle<T>>,
+}
+
+impl<T> Lazy<T> {
+    /// Create a new Lazy which already holds the given value
+    pub fn new(value: T) -> Lazy<T> {
+        Lazy {
+            value: OnceLock::from(value),
+            handle: None,
+        }
+    }
+
+    /// Create a new Lazy whose value has yet to be unstashed
+    pub(crate) fn from_handle(handle: StashHandle<T>) -> Lazy<T> {
+        Lazy {
+            value: OnceLock::new(),
+            handle: Some(handle),
+        }
+    }
+
+    /// Has the value been unstashed yet?
+    pub fn is_loaded(&self) -> bool {
+        self.value.get().is_some()
+    }
+
+    /// Get the handle to the stashed value, if the value was unstashed
+    /// and has not been modified since
+    pub fn handle(&self) -> Option<&StashHandle<T>> {
+        self.handle.as_ref()
+    }
+
+    /// Get the value, unstashing it first if needed
+    pub fn get(&self) -> Result<&T, UnstashError>
+    where
+        T: Unstashable<()>,
+    {
+        self.get_with_context(())
+    }
+
+    /// Get the value, unstashing it first with the given context if needed
+    pub fn get_with_context<C>(&self, context: C) -> Result<&T, UnstashError>
+    where
+        T: Unstashable<C>,
+    {
+        if let Some(value) = self.value.get() {
+            return Ok(value);
+        }
+        // A Lazy without a value always has a handle
+        let handle = self.handle.as_ref().unwrap();
+        let value = read_map_for_unstashing(&handle.map).unstash(
+            ObjectRef::Stashed(handle.hash),
+            T::unstash,
+            context,
+        )?;
+        Ok(self.value.get_or_init(|| value))
+    }
+
+    /// Get mutable access to the value, unstashing it first if needed.
+    /// Since the value may be modified, this releases the handle to
+    /// the stashed value.
+    pub fn get_mut(&mut self) -> Result<&mut T, UnstashError>
+    where
+        T: Unstashable<()>,
+    {
+        self.get_mut_with_context(())
+    }
+
+    /// Get mutable access to the value, unstashing it first with the given
+    /// context if needed. See [Self::get_mut].
+    pub fn get_mut_with_context<C>(&mut self, context: C) -> Result<&mut T, UnstashError>
+    where
+        T: Unstashable<C>,
+    {
+        self.get_with_context(context)?;
+        self.handle = None;
+        Ok(self.value.get_mut().unwrap())
+    }
+
+    /// Get the value, unstashing it first if needed
+    pub fn into_inner(self) -> Result<T, UnstashError>
+    where
+        T: Unstashable<()>,
+    {
+        self.into_inner_with_context(())
+    }
+
+    /// Get the value, unstashing it first with the given context if needed
+    pub fn into_inner_with_context<C>(mut self, context: C) -> Result<T, UnstashError>
+    where
+        T: Unstashable<C>,
+    {
+        self.get_with_context(context)?;
+        Ok(self.value.take().unwrap())
+    }
+}
+
+impl<T> From<T> for Lazy<T> {
+    fn from(value: T) -> Lazy<T> {
+        Lazy::new(value)
+    }
+}
+
+/// A Lazy is stashed as a reference to its value. A Lazy whose value was
+/// never unstashed reuses its stashed value when stashed into the stash
+/// it came from. Stashing it into a different stash first unstashes the
+/// value from its own stash, using the stasher's context, and this panics
+/// if the value can't be unstashed.
+impl<C: Copy, T: Stashable<C> + Unstashable<C>> Stashable<C> for Lazy<T> {
+    fn stash(&self, stasher: &mut Stasher<C>) {
+        if let Some(handle) = &self.handle {
+            if stasher.existing_handle(handle) {
+                return;
+            }
+        }
+        match self.get_with_context(stasher.context()) {
+            Ok(value) => stasher.object(value),
+            Err(err) => panic!("A Lazy value couldn't be unstashed to be stashed: {}", err),
+        }
+    }
+}
+
+impl<C: Copy, T: 'static + Unstashable<C>> Unstashable<C> for Lazy<T> {
+    fn unstash(unstasher: &mut Unstasher<C>) -> Result<Self, UnstashError> {
+        unstasher.lazy()
+    }
+}
+
+/// Unstashing a Lazy in place replaces it with a new Lazy whose value
+/// has yet to be unstashed
+impl<C: Copy, T: 'static + Unstashable<C>> UnstashableInplace<C> for Lazy<T> {
+    fn unstash_inplace(&mut self, unstasher: &mut InplaceUnstasher<C>) -> Result<(), UnstashError> {
+        let lazy = unstasher.lazy_always()?;
+        if unstasher.time_to_write() {
+            *self = lazy;
+        }
+        Ok(())
+    }
+}
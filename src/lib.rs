@@ -1,25 +1,56 @@
 use std::{
     cell::{Cell, RefCell},
     collections::HashMap,
-    hash::{Hash, Hasher},
+    hash::Hash,
+    io::{Read, Write},
     marker::PhantomData,
-    rc::Rc,
+    ops::Deref,
+    sync::{
+        atomic::{self, AtomicU64},
+        Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak,
+    },
 };
 
 mod cache;
+mod commits;
+mod diff;
+mod eviction;
+mod hasher;
+mod history;
+mod impls;
+mod inspect;
+mod lazy;
+mod snapshot;
 mod stasher;
+mod stats;
 mod unstasher;
 mod valuetypes;
 
 #[cfg(test)]
 mod test;
 
+#[cfg(feature = "derive")]
+pub use hashstash_derive::{Stashable, Unstashable, UnstashableInplace};
+
 pub use cache::{HashCache, HashCacheProperty};
+pub use commits::{Commit, CommitGraph, CommitGraphError, CommitId};
+pub use diff::{Difference, PathSegment};
+pub use eviction::{EvictableHandle, EvictionPolicy};
+pub use history::History;
+pub use inspect::{ObjectValue, Value};
+pub use lazy::Lazy;
+pub use snapshot::{SnapshotError, StashPack};
 pub use stasher::{Order, Stasher};
-pub use unstasher::{InplaceUnstasher, UnstashError, Unstasher};
-pub use valuetypes::{PrimitiveType, ValueType};
+pub use stats::StashStats;
+pub use unstasher::{
+    InplaceUnstasher, UnstashError, UnstashErrorKind, UnstashPathEntry, Unstasher,
+};
+pub use valuetypes::{PrimitiveType, PrimitiveValue, ValueType};
 
-use unstasher::{InplaceUnstashPhase, UnstasherBackend};
+use eviction::Eviction;
+use hasher::{DefaultObjectHasher, HashValue, ObjectHasher};
+use unstasher::{InplaceUnstashPhase, ObjectRef, RawValue, UnstasherBackend};
+use valuetypes::{bytes_canonically_eq, CanonicalFloats};
 
 /// Trait for hashing and serializing an object
 pub trait Stashable<Context = ()> {
@@ -78,6 +109,21 @@ pub trait UnstashableInplace<Context = ()> {
     ) -> Result<(), UnstashError>;
 }
 
+/// Trait for converting an object that was stashed with an older or
+/// otherwise different type, used by [Stash::unstash_as] when the
+/// stashed contents can't be read as the new type directly. Every type
+/// trivially migrates from itself.
+pub trait Migrate<From>: Sized {
+    /// Create a new object from one of the type it was stashed as
+    fn migrate(old: From) -> Result<Self, UnstashError>;
+}
+
+impl<T> Migrate<T> for T {
+    fn migrate(old: T) -> Result<T, UnstashError> {
+        Ok(old)
+    }
+}
+
 impl<C: Copy, T: Stashable<C>> Stashable<C> for Option<T> {
     fn stash(&self, stasher: &mut Stasher<C>) {
         match self {
@@ -95,7 +141,7 @@ impl<C: Copy, T: 'static + Unstashable<C>> Unstashable<C> for Option<T> {
         match unstasher.u8()? {
             0 => Ok(None),
             1 => Ok(Some(unstasher.object()?)),
-            _ => Err(UnstashError::BadValue),
+            _ => Err(UnstashErrorKind::BadValue.into()),
         }
     }
 }
@@ -124,7 +170,7 @@ impl<C: Copy, T: 'static + Unstashable<C> + UnstashableInplace<C>> UnstashableIn
                     Ok(())
                 }
             },
-            _ => Err(UnstashError::BadValue),
+            _ => Err(UnstashErrorKind::BadValue.into()),
         }
     }
 }
@@ -132,7 +178,7 @@ impl<C: Copy, T: 'static + Unstashable<C> + UnstashableInplace<C>> UnstashableIn
 /// A small and fixed-size summary of the contents to an object,
 /// such that changes to an object result in a different ObjectHash.
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
-pub struct ObjectHash(u64);
+pub struct ObjectHash(HashValue);
 
 impl ObjectHash {
     /// Create a new ObjectHash by hashing a Stashable object
@@ -156,17 +202,26 @@ impl ObjectHash {
 
     /// Create a new ObjectHash by hashing the data given to
     /// a Stasher in the provided function
-    pub fn with_stasher_and_context<C, F: FnMut(&mut Stasher<C>)>(
+    pub fn with_stasher_and_context<C, F: FnMut(&mut Stasher<C>)>(f: F, context: C) -> ObjectHash {
+        Self::with_stasher_and_context_measured(f, context).0
+    }
+
+    /// Create a new ObjectHash by hashing the data given to a Stasher
+    /// in the provided function, and also get the approximate size of
+    /// the data when serialized, or None if it has any dependencies
+    pub(crate) fn with_stasher_and_context_measured<C, F: FnMut(&mut Stasher<C>)>(
         mut f: F,
         context: C,
-    ) -> ObjectHash {
-        let mut hasher = seahash::SeaHasher::new();
+    ) -> (ObjectHash, Option<usize>) {
+        let mut hasher = DefaultObjectHasher::new();
 
         let mut stasher = Stasher::new_hasher(&mut hasher, context);
 
         f(&mut stasher);
 
-        ObjectHash(hasher.finish())
+        let size = stasher.hashed_size();
+
+        (hasher.finish(), size)
     }
 }
 
@@ -174,21 +229,74 @@ impl ObjectHash {
 /// it depends on, intended to be stored in a [StashMap]
 struct StashedObject {
     bytes: Vec<u8>,
-    reference_count: Cell<u16>,
+    /// Reference counts are only ever decreased while the [StashMap] is
+    /// locked for writing, so relaxed atomic operations are sufficient.
+    /// At 64 bits, the count can't realistically overflow.
+    reference_count: AtomicU64,
     dependencies: Vec<ObjectHash>,
 }
 
+/// The serialized contents of an object which has yet to be inserted
+/// into a [StashMap]
+struct SerializedObject {
+    bytes: Vec<u8>,
+    dependencies: Vec<ObjectHash>,
+
+    /// Where floats written with the `_canonical` methods of [Stasher]
+    /// are in the serialized bytes, if the stashmap detects collisions
+    canonical_floats: Vec<CanonicalFloats>,
+}
+
 /// A container storing stashed objects by the hashes of their contents
 struct StashMap {
     objects: HashMap<ObjectHash, StashedObject>,
+
+    /// Whether objects whose hash matches an existing stashed object
+    /// are serialized anyway and compared to it, to detect collisions
+    detect_collisions: bool,
+
+    /// The hash of the first collision that was detected since this
+    /// was last cleared, if any
+    collision: Option<ObjectHash>,
+
+    /// The total size of the serialized contents of every object
+    total_bytes: usize,
+
+    /// The limits on the size of the stashmap and the roots which
+    /// may be evicted to stay within them
+    eviction: Eviction,
+
+    /// The shared stashmap containing this one, from which new
+    /// handles can be created while unstashing
+    this: Weak<RwLock<StashMap>>,
 }
 
 impl StashMap {
-    /// Create a new empty StashMap
-    fn new() -> StashMap {
-        StashMap {
-            objects: HashMap::new(),
-        }
+    /// Create a new empty shared StashMap
+    fn new_shared(detect_collisions: bool) -> SharedStashMap {
+        Arc::new_cyclic(|this| {
+            RwLock::new(StashMap {
+                objects: HashMap::new(),
+                detect_collisions,
+                collision: None,
+                total_bytes: 0,
+                eviction: Eviction::default(),
+                this: Weak::clone(this),
+            })
+        })
+    }
+
+    /// Is this the stashmap inside the given shared stashmap?
+    fn is_shared_as(&self, map: &SharedStashMap) -> bool {
+        std::ptr::eq(self.this.as_ptr(), Arc::as_ptr(map))
+    }
+
+    /// Create a new handle to an existing stashed object, increasing its
+    /// reference count. This method panics if no object with the given
+    /// hash exists.
+    fn handle<T>(&self, hash: ObjectHash) -> StashHandle<T> {
+        self.add_reference(hash);
+        StashHandle::new(self.this.upgrade().unwrap(), hash)
     }
 
     /// Stash an object. The object is first hashed. If the hash doesn't
@@ -197,34 +305,124 @@ impl StashMap {
     /// one. Otherwise, if the hash matches an existing serialized object, it
     /// is not serialized a second time and the existing object has its reference
     /// count increased.
+    ///
+    /// If collision detection is enabled, objects matching an existing
+    /// hash are serialized anyway and compared to the existing object.
+    /// A mismatch is recorded in `self.collision`, but the existing
+    /// object is still referenced so that the stashmap stays consistent.
     fn stash_and_add_reference<C: Copy, F: FnMut(&mut Stasher<C>)>(
         &mut self,
         mut f: F,
         context: C,
     ) -> ObjectHash {
-        let hash = ObjectHash::with_stasher_and_context(&mut f, context.clone());
+        let hash = ObjectHash::with_stasher_and_context(&mut f, context);
+        self.add_reference_or_serialize(hash, f, context);
+        hash
+    }
 
-        if let Some(stashed_object) = self.objects.get(&hash) {
-            stashed_object
-                .reference_count
-                .set(stashed_object.reference_count.get() + 1);
-            return hash;
+    /// Add a reference to the existing stashed object with the given
+    /// hash if there is one, or else serialize the object and insert it.
+    /// See [Self::stash_and_add_reference].
+    fn add_reference_or_serialize<C: Copy, F: FnMut(&mut Stasher<C>)>(
+        &mut self,
+        hash: ObjectHash,
+        f: F,
+        context: C,
+    ) {
+        if self.objects.contains_key(&hash) {
+            if self.detect_collisions {
+                let object = self.serialize(f, context);
+                self.check_for_collision(hash, &object);
+                self.remove_dependency_references(object.dependencies);
+            }
+            self.add_reference(hash);
+            return;
         }
 
+        let object = self.serialize(f, context);
+
+        self.insert_serialized(hash, object);
+    }
+
+    /// Serialize an object, returning its serialized bytes and the
+    /// hashes of its dependencies. Every dependency is stashed and has
+    /// a reference added to it, but the object itself is not inserted.
+    fn serialize<C: Copy, F: FnMut(&mut Stasher<C>)>(
+        &mut self,
+        mut f: F,
+        context: C,
+    ) -> SerializedObject {
         let mut dependencies = Vec::<ObjectHash>::new();
         let mut bytes = Vec::<u8>::new();
+        let mut canonical_floats = Vec::<CanonicalFloats>::new();
 
-        let mut stasher = Stasher::new_serializer(&mut bytes, &mut dependencies, self, context);
+        let mut stasher = Stasher::new_serializer(
+            &mut bytes,
+            &mut dependencies,
+            &mut canonical_floats,
+            self,
+            context,
+        );
 
         f(&mut stasher);
 
+        SerializedObject {
+            bytes,
+            dependencies,
+            canonical_floats,
+        }
+    }
+
+    /// Insert an object that was just serialized with the given hash and
+    /// add a reference to it. If an object with the same hash already
+    /// exists, it is referenced instead and the references to the new
+    /// object's dependencies are removed again.
+    fn insert_serialized(&mut self, hash: ObjectHash, object: SerializedObject) {
+        if self.objects.contains_key(&hash) {
+            if self.detect_collisions {
+                self.check_for_collision(hash, &object);
+            }
+            self.remove_dependency_references(object.dependencies);
+            self.add_reference(hash);
+            return;
+        }
+
+        self.insert_object(hash, object.bytes, object.dependencies);
+    }
+
+    /// Insert a new object with a reference count of one. The object's
+    /// dependencies must already have had references added to them.
+    fn insert_object(&mut self, hash: ObjectHash, bytes: Vec<u8>, dependencies: Vec<ObjectHash>) {
+        self.total_bytes += bytes.len();
         let stashed_object = StashedObject {
             bytes,
-            reference_count: Cell::new(1),
+            reference_count: AtomicU64::new(1),
             dependencies,
         };
         self.objects.insert(hash, stashed_object);
-        hash
+    }
+
+    /// Record a collision if the given serialized contents differ
+    /// from those of the existing stashed object with the same hash.
+    /// Floats written with the `_canonical` methods of [Stasher] are
+    /// compared in their canonical form, since equivalent floats with
+    /// different bits hash the same.
+    fn check_for_collision(&mut self, hash: ObjectHash, object: &SerializedObject) {
+        let existing = self.objects.get(&hash).unwrap();
+        let same_bytes =
+            bytes_canonically_eq(&existing.bytes, &object.bytes, &object.canonical_floats);
+        if (!same_bytes || existing.dependencies != object.dependencies) && self.collision.is_none()
+        {
+            self.collision = Some(hash);
+        }
+    }
+
+    /// Remove the references that were added to the dependencies of an
+    /// object which was serialized but which is not kept
+    fn remove_dependency_references(&mut self, dependencies: Vec<ObjectHash>) {
+        for dependency in dependencies {
+            self.remove_reference(dependency);
+        }
     }
 
     /// Increase the reference count of an existing stashed object.
@@ -233,64 +431,87 @@ impl StashMap {
         let stashed_object = self.objects.get(&hash).unwrap();
         stashed_object
             .reference_count
-            .set(stashed_object.reference_count.get() + 1);
+            .fetch_add(1, atomic::Ordering::Relaxed);
     }
 
-    /// Unstash/deserialize an object by finding an existing stashed
-    /// object for the given hash and passing an [Unstasher] with
-    /// its contents to the given function.
-    /// This method panics if there is not stashed object with the
-    /// given hash.
+    /// Unstash/deserialize an object by finding its contents, either
+    /// inline or in an existing stashed object, and passing an
+    /// [Unstasher] with those contents to the given function.
+    /// This method panics if the object is not inline and there is
+    /// no stashed object with its hash.
     fn unstash<'a, C, R, F: FnMut(&mut Unstasher<C>) -> Result<R, UnstashError>>(
-        &self,
-        hash: ObjectHash,
+        &'a self,
+        object: ObjectRef<'a>,
         mut f: F,
         context: C,
     ) -> Result<R, UnstashError> {
-        let stashed_object = self.objects.get(&hash).unwrap();
+        let mut unstasher = Unstasher::new(UnstasherBackend::for_object(object, self), context);
 
-        let mut unstasher = Unstasher::new(
-            UnstasherBackend::from_stashed_object(stashed_object, self),
-            context,
-        );
-
-        let result = f(&mut unstasher)?;
-
-        if !unstasher.backend().is_finished() {
-            return Err(UnstashError::NotFinished);
-        }
+        let result = f(&mut unstasher).and_then(|result| {
+            if !unstasher.backend().is_finished() {
+                return Err(UnstashErrorKind::NotFinished.into());
+            }
+            Ok(result)
+        });
 
-        Ok(result)
+        result.map_err(|err| unstasher.backend().add_error_context(err))
     }
 
-    /// Unstash/deserialize an object by finding an existing stashed
-    /// object for the given hash and then calling the object's
-    /// [UnstashableInplace::unstash_inplace] method with the given
-    /// phase.
-    /// This method panics if there is not stashed object with the
-    /// given hash.
+    /// Unstash/deserialize an object by finding its contents, either
+    /// inline or in an existing stashed object, and then calling the
+    /// object's [UnstashableInplace::unstash_inplace] method with the
+    /// given phase.
+    /// This method panics if the object is not inline and there is
+    /// no stashed object with its hash.
     fn unstash_inplace<'a, C, F: FnMut(&mut InplaceUnstasher<C>) -> Result<(), UnstashError>>(
-        &self,
-        hash: ObjectHash,
+        &'a self,
+        object: ObjectRef<'a>,
         phase: InplaceUnstashPhase,
         mut f: F,
         context: C,
     ) -> Result<(), UnstashError> {
-        let stashed_object = self.objects.get(&hash).unwrap();
+        let mut unstasher =
+            InplaceUnstasher::new(UnstasherBackend::for_object(object, self), phase, context);
 
-        let mut unstasher = InplaceUnstasher::new(
-            UnstasherBackend::from_stashed_object(stashed_object, self),
-            phase,
-            context,
-        );
+        let result = f(&mut unstasher).and_then(|()| {
+            if !unstasher.backend().is_finished() {
+                return Err(UnstashErrorKind::NotFinished.into());
+            }
+            Ok(())
+        });
 
-        f(&mut unstasher)?;
+        result.map_err(|err| unstasher.backend().add_error_context(err))
+    }
 
-        if !unstasher.backend().is_finished() {
-            return Err(UnstashError::NotFinished);
+    /// Read the version number, type fingerprint and every value in the
+    /// contents of an object without unstashing it as any particular type.
+    /// This method panics if the object is not inline and there is
+    /// no stashed object with its hash.
+    fn read_raw_values<'a>(
+        &'a self,
+        object: ObjectRef<'a>,
+    ) -> Result<(u32, Option<u64>, Vec<RawValue<'a>>), UnstashError> {
+        let mut backend = UnstasherBackend::for_object(object, self);
+        let mut values = Vec::new();
+        while !backend.is_finished() {
+            let value = backend
+                .read_raw_value()
+                .map_err(|err| backend.add_error_context(err))?;
+            values.push(value);
         }
+        Ok((backend.version(), backend.type_fingerprint(), values))
+    }
 
-        Ok(())
+    /// Release a reference from a dropped handle
+    fn release(&mut self, release: Release) {
+        match release {
+            Release::Handle(hash) => self.remove_reference(hash),
+            Release::EvictableRoot(id, hash) => {
+                if self.eviction.remove_root(id) {
+                    self.remove_reference(hash);
+                }
+            }
+        }
     }
 
     /// Decrease the reference count of the stashed object,
@@ -300,30 +521,117 @@ impl StashMap {
     /// This method panics if no stashed object with the given
     /// hash exists.
     fn remove_reference(&mut self, hash: ObjectHash) {
-        fn decrease_refcounts_recursive(
-            stashmap: &StashMap,
-            hash: ObjectHash,
-            objects_to_remove: &mut Vec<ObjectHash>,
-        ) {
-            let object = stashmap.objects.get(&hash).unwrap();
-            let mut refcount = object.reference_count.get();
+        // Objects whose reference count is yet to be decreased. An explicit
+        // stack is used so that deep object graphs can't overflow the call stack.
+        let mut stack: Vec<ObjectHash> = vec![hash];
+
+        let mut objects_to_remove: Vec<ObjectHash> = Vec::new();
+
+        while let Some(hash) = stack.pop() {
+            let object = self.objects.get(&hash).unwrap();
+            let refcount = object
+                .reference_count
+                .fetch_sub(1, atomic::Ordering::Relaxed);
             debug_assert!(refcount > 0);
-            refcount -= 1;
-            object.reference_count.set(refcount);
-            if refcount == 0 {
+            if refcount == 1 {
                 objects_to_remove.push(hash);
-                for dependency in &object.dependencies {
-                    decrease_refcounts_recursive(stashmap, *dependency, objects_to_remove);
-                }
+                stack.extend_from_slice(&object.dependencies);
             }
         }
 
-        let mut objects_to_remove: Vec<ObjectHash> = Vec::new();
+        for hash in objects_to_remove {
+            let object = self.objects.remove(&hash).unwrap();
+            self.total_bytes -= object.bytes.len();
+        }
+    }
+}
 
-        decrease_refcounts_recursive(self, hash, &mut objects_to_remove);
+/// A [StashMap] shared between a [Stash] and all of its handles
+type SharedStashMap = Arc<RwLock<StashMap>>;
 
-        for hash in objects_to_remove {
-            self.objects.remove(&hash).unwrap();
+/// Lock a shared [StashMap] for reading. Lock poisoning is ignored,
+/// since a panic while stashing can at worst leave behind objects
+/// that are unreferenced, and never an inconsistent map.
+fn read_map(map: &SharedStashMap) -> RwLockReadGuard<'_, StashMap> {
+    map.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Lock a shared [StashMap] for writing. See [read_map].
+fn write_map(map: &SharedStashMap) -> RwLockWriteGuard<'_, StashMap> {
+    map.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+thread_local! {
+    /// The number of [UnstashingMap]s currently held by this thread
+    static UNSTASHING_DEPTH: Cell<usize> = const { Cell::new(0) };
+
+    /// The handles dropped by this thread while it held an [UnstashingMap],
+    /// which are released once it no longer holds any
+    static DEFERRED_RELEASES: RefCell<Vec<(SharedStashMap, Release)>> =
+        const { RefCell::new(Vec::new()) };
+}
+
+/// A reference to a stashed object which is released when a handle
+/// is dropped
+pub(crate) enum Release {
+    /// The reference held by a [StashHandle]
+    Handle(ObjectHash),
+
+    /// The reference held by the evictable root with the given id,
+    /// unless it was already evicted
+    EvictableRoot(u64, ObjectHash),
+}
+
+/// Release a reference from a dropped handle, or defer releasing it
+/// if the current thread holds an [UnstashingMap]
+pub(crate) fn release_or_defer(map: &SharedStashMap, release: Release) {
+    if UNSTASHING_DEPTH.with(|depth| depth.get() > 0) {
+        DEFERRED_RELEASES.with(|released| released.borrow_mut().push((Arc::clone(map), release)));
+        return;
+    }
+    write_map(map).release(release);
+}
+
+/// A shared [StashMap] locked for reading while objects are unstashed
+/// from it. Unstashing may create new handles, such as those held by
+/// [Lazy], which may be dropped again before unstashing finishes, for
+/// example if an error occurs. Since releasing a handle requires a write
+/// lock, handles dropped by the same thread are instead released once
+/// the read lock is unlocked.
+struct UnstashingMap<'a> {
+    guard: Option<RwLockReadGuard<'a, StashMap>>,
+}
+
+/// Lock a shared [StashMap] for reading in order to unstash objects
+/// from it. See [UnstashingMap].
+fn read_map_for_unstashing(map: &SharedStashMap) -> UnstashingMap<'_> {
+    let guard = read_map(map);
+    UNSTASHING_DEPTH.with(|depth| depth.set(depth.get() + 1));
+    UnstashingMap { guard: Some(guard) }
+}
+
+impl<'a> Deref for UnstashingMap<'a> {
+    type Target = StashMap;
+
+    fn deref(&self) -> &StashMap {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+/// Unlocking the last UnstashingMap held by a thread releases
+/// any handles that the thread dropped in the meantime
+impl<'a> Drop for UnstashingMap<'a> {
+    fn drop(&mut self) {
+        self.guard = None;
+        let depth = UNSTASHING_DEPTH.with(|depth| {
+            depth.set(depth.get() - 1);
+            depth.get()
+        });
+        if depth == 0 {
+            let released = DEFERRED_RELEASES.with(|released| released.take());
+            for (map, release) in released {
+                write_map(&map).release(release);
+            }
         }
     }
 }
@@ -335,25 +643,109 @@ impl StashMap {
 ///
 /// Objects that are stashed should implement [Stashable] and at
 /// least of [Unstashable] and [UnstashableInplace].
+///
+/// A Stash and its [StashHandle]s can be sent and shared between
+/// threads. Objects can be unstashed from multiple threads at once,
+/// while stashing objects and dropping handles briefly takes exclusive
+/// access. Stashing or dropping handles from within a [Stashable] or
+/// [Unstashable] implementation using the same Stash will deadlock.
+///
+/// Objects are identified only by their [ObjectHash]. A Stash created
+/// with [Stash::with_collision_detection] additionally verifies that
+/// objects with matching hashes really do have the same contents.
 pub struct Stash {
-    map: Rc<RefCell<StashMap>>,
+    map: SharedStashMap,
 }
 
 impl Stash {
-    // TODO: add the ability to save *one snapshot* to disk
-
     /// Create a new empty Stash
     pub fn new() -> Stash {
         Stash {
-            map: Rc::new(RefCell::new(StashMap::new())),
+            map: StashMap::new_shared(false),
         }
     }
 
+    /// Create a new empty Stash which detects hash collisions. Whenever
+    /// an object is stashed whose hash matches an existing stashed object,
+    /// the object is serialized anyway and compared to the existing one.
+    /// If they differ, [Self::try_stash] returns an error and [Self::stash]
+    /// panics rather than silently treating them as the same object.
+    ///
+    /// This makes stashing duplicate objects much more expensive, and is
+    /// intended for debugging and testing.
+    pub fn with_collision_detection() -> Stash {
+        Stash {
+            map: StashMap::new_shared(true),
+        }
+    }
+
+    /// Does the stash detect hash collisions? See [Self::with_collision_detection].
+    pub fn detects_collisions(&self) -> bool {
+        read_map(&self.map).detect_collisions
+    }
+
+    /// Limit the total size in bytes of the serialized objects in the
+    /// stash, or remove the limit if None is given. Whenever the stash
+    /// exceeds its limits, evictable roots are released according to the
+    /// [EvictionPolicy] until it no longer does or until none are left.
+    /// See [Self::make_evictable].
+    pub fn set_max_bytes(&self, max_bytes: Option<usize>) {
+        eviction::set_max_bytes(&self.map, max_bytes);
+    }
+
+    /// Limit the number of objects in the stash, or remove the limit if
+    /// None is given. See [Self::set_max_bytes].
+    pub fn set_max_objects(&self, max_objects: Option<usize>) {
+        eviction::set_max_objects(&self.map, max_objects);
+    }
+
+    /// Choose which evictable root is released first when the stash
+    /// exceeds its limits. The default is [EvictionPolicy::Oldest].
+    pub fn set_eviction_policy(&self, policy: EvictionPolicy) {
+        eviction::set_policy(&self.map, policy);
+    }
+
+    /// Set a function to be called with the hash of every evictable
+    /// root that is released because the stash exceeded its limits.
+    /// The function is called after eviction has finished, and may
+    /// use the stash.
+    pub fn set_eviction_callback<F: Fn(ObjectHash) + Send + Sync + 'static>(&self, callback: F) {
+        eviction::set_callback(&self.map, Some(Arc::new(callback)));
+    }
+
+    /// Turn a handle into an [EvictableHandle], registering its object as
+    /// a root which the stash may release when it exceeds its limits.
+    /// Evictable roots are the only references the stash ever releases by
+    /// itself, and releasing one removes the root object and any objects
+    /// that only it depends on, unless they are still referred to elsewhere.
+    pub fn make_evictable<T>(&self, handle: StashHandle<T>) -> EvictableHandle<T> {
+        assert!(
+            Arc::ptr_eq(&self.map, &handle.map),
+            "The handle belongs to a different stash"
+        );
+        EvictableHandle::new(handle)
+    }
+
     /// Get the number of objects stored in the stash.
     /// Due to deduplication, this may be less than the
     /// number of objects that have been stashed overall.
     pub fn num_objects(&self) -> usize {
-        self.map.borrow().objects.len()
+        read_map(&self.map).objects.len()
+    }
+
+    /// Collect statistics about the objects in the stash and the
+    /// memory they use. This visits every object in the stash.
+    pub fn stats(&self) -> StashStats {
+        stats::stats(&read_map(&self.map))
+    }
+
+    /// Get the total size in bytes of the serialized objects that
+    /// would be removed from the stash if the given handle were
+    /// dropped, which includes the handle's object and any objects
+    /// that only it depends on. This is zero if the handle's object
+    /// is also referred to elsewhere.
+    pub fn retained_size<T>(&self, handle: &StashHandle<T>) -> usize {
+        stats::retained_size(&read_map(&self.map), handle.hash)
     }
 
     /// Stash an object, and get a [StashHandle] to its stashed contents
@@ -363,9 +755,7 @@ impl Stash {
     /// If an existing object has the same contents, its storage
     /// is reused and the serialization is skipped.
     pub fn stash<T: Stashable<()>>(&self, object: &T) -> StashHandle<T> {
-        let mut stashmap = self.map.borrow_mut();
-        let hash = stashmap.stash_and_add_reference(|stasher| object.stash(stasher), ());
-        StashHandle::new(Rc::clone(&self.map), hash)
+        self.stash_with_context(object, ())
     }
 
     pub fn stash_with_context<C: Copy, T: Stashable<C>>(
@@ -373,9 +763,37 @@ impl Stash {
         object: &T,
         context: C,
     ) -> StashHandle<T> {
-        let mut stashmap = self.map.borrow_mut();
-        let hash = stashmap.stash_and_add_reference(|stasher| object.stash(stasher), context);
-        StashHandle::new(Rc::clone(&self.map), hash)
+        self.try_stash_with_context(object, context)
+            .unwrap_or_else(|collision| {
+                panic!("Hash collision detected for object {}", collision.hash)
+            })
+    }
+
+    /// Stash an object like [Self::stash], but return an error instead
+    /// of panicking if a hash collision is detected. Collisions are only
+    /// detected by stashes created with [Self::with_collision_detection].
+    /// If an error is returned, the stash is left unchanged.
+    pub fn try_stash<T: Stashable<()>>(&self, object: &T) -> Result<StashHandle<T>, HashCollision> {
+        self.try_stash_with_context(object, ())
+    }
+
+    pub fn try_stash_with_context<C: Copy, T: Stashable<C>>(
+        &self,
+        object: &T,
+        context: C,
+    ) -> Result<StashHandle<T>, HashCollision> {
+        let hash = {
+            let mut stashmap = write_map(&self.map);
+            let hash = stashmap.stash_and_add_reference(|stasher| object.stash(stasher), context);
+            if let Some(collision) = stashmap.collision.take() {
+                stashmap.remove_reference(hash);
+                return Err(HashCollision { hash: collision });
+            }
+            hash
+        };
+        let handle = StashHandle::new(Arc::clone(&self.map), hash);
+        eviction::evict_over_limits(&self.map);
+        Ok(handle)
     }
 
     /// Unstash a new object to deserialize and recreate the state of an
@@ -394,7 +812,43 @@ impl Stash {
         handle: &StashHandle<T>,
         context: C,
     ) -> Result<T, UnstashError> {
-        self.map.borrow().unstash(handle.hash, T::unstash, context)
+        read_map_for_unstashing(&self.map).unstash(
+            ObjectRef::Stashed(handle.hash),
+            T::unstash,
+            context,
+        )
+    }
+
+    /// Unstash an object that was stashed as type T as a different type U.
+    /// The stashed contents are first read directly as U. If that fails,
+    /// they are read as T instead and converted to U using [Migrate]. If
+    /// both fail, the error from reading U directly is returned.
+    ///
+    /// This allows stashed data, such as snapshots, to be read after the
+    /// type it was stashed with has been changed or replaced.
+    pub fn unstash_as<U, T>(&self, handle: &StashHandle<T>) -> Result<U, UnstashError>
+    where
+        U: Unstashable<()> + Migrate<T>,
+        T: Unstashable<()>,
+    {
+        self.unstash_as_with_context(handle, ())
+    }
+
+    pub fn unstash_as_with_context<U, T, C: Copy>(
+        &self,
+        handle: &StashHandle<T>,
+        context: C,
+    ) -> Result<U, UnstashError>
+    where
+        U: Unstashable<C> + Migrate<T>,
+        T: Unstashable<C>,
+    {
+        let map = read_map_for_unstashing(&self.map);
+        let object = ObjectRef::Stashed(handle.hash);
+        map.unstash(object, U::unstash, context).or_else(|err| {
+            let old = map.unstash(object, T::unstash, context).map_err(|_| err)?;
+            U::migrate(old)
+        })
     }
 
     /// Unstash a new object to deserialize and recreate a previously-
@@ -418,7 +872,7 @@ impl Stash {
     where
         F: FnMut(&mut Unstasher<C>) -> Result<T, UnstashError>,
     {
-        self.map.borrow().unstash(handle.hash, f, context)
+        read_map_for_unstashing(&self.map).unstash(ObjectRef::Stashed(handle.hash), f, context)
     }
 
     /// Unstash an existing object to deserialize and restore the state
@@ -444,29 +898,166 @@ impl Stash {
         object: &mut T,
         context: C,
     ) -> Result<(), UnstashError> {
-        let map = self.map.borrow();
+        let map = read_map_for_unstashing(&self.map);
         map.unstash_inplace(
-            handle.hash,
+            ObjectRef::Stashed(handle.hash),
             InplaceUnstashPhase::Validate,
             |unstasher| object.unstash_inplace(unstasher),
             context,
         )?;
         map.unstash_inplace(
-            handle.hash,
+            ObjectRef::Stashed(handle.hash),
             InplaceUnstashPhase::Write,
             |unstasher| object.unstash_inplace(unstasher),
             context,
         )
     }
+
+    /// Find the structural differences between two stashed objects,
+    /// such as two snapshots of the same data structure. Both object
+    /// graphs are walked in parallel, and any pair of stashed objects
+    /// with identical hashes is skipped without being compared.
+    ///
+    /// Values are compared by position within their objects, and
+    /// elements of arrays are compared by their position in the array.
+    /// Unordered arrays are compared in the order they were stashed.
+    pub fn diff<T>(
+        &self,
+        old: &StashHandle<T>,
+        new: &StashHandle<T>,
+    ) -> Result<Vec<Difference>, UnstashError> {
+        diff::diff(&read_map(&self.map), old.hash, new.hash)
+    }
+
+    /// Decode the stashed object referred to by the given handle into
+    /// a [Value] tree, without needing to know the object's Rust type.
+    /// This is mainly intended for debugging and for tools that need
+    /// to examine stashed data generically. Every object that the
+    /// stashed object depends on is decoded as well. Inspecting fails
+    /// with [UnstashErrorKind::TooDeep] if objects are nested more than
+    /// 1024 levels deep.
+    pub fn inspect<T>(&self, handle: &StashHandle<T>) -> Result<Value, UnstashError> {
+        let object = inspect::inspect(&read_map(&self.map), ObjectRef::Stashed(handle.hash))?;
+        Ok(Value::Object(Box::new(object)))
+    }
+
+    /// Write a single snapshot to the given writer, consisting of the
+    /// stashed object referred to by the given [StashHandle] and every
+    /// stashed object that it depends on. Other objects in the stash
+    /// are not written. The snapshot can be read back into this or any
+    /// other stash using [Self::import_snapshot].
+    pub fn export_snapshot<T, W: Write>(
+        &self,
+        handle: &StashHandle<T>,
+        writer: W,
+    ) -> std::io::Result<()> {
+        snapshot::write_snapshot(&read_map(&self.map), handle.hash, writer)
+    }
+
+    /// Read a single snapshot that was previously written using
+    /// [Self::export_snapshot] and add its objects to the stash,
+    /// returning a [StashHandle] to the snapshot's root object.
+    /// Objects which are already present in the stash are reused
+    /// rather than being duplicated. If an error occurs, the stash
+    /// is not modified.
+    ///
+    /// The type of the returned handle is not checked. It is up
+    /// to the caller to unstash the snapshot using the same type
+    /// it was exported with.
+    pub fn import_snapshot<T, R: Read>(&self, reader: R) -> Result<StashHandle<T>, SnapshotError> {
+        let hash = snapshot::read_snapshot(&mut write_map(&self.map), reader)?;
+        let handle = StashHandle::new(Arc::clone(&self.map), hash);
+        eviction::evict_over_limits(&self.map);
+        Ok(handle)
+    }
+
+    /// Write a pack to the given writer, consisting of the names of all
+    /// handles in the given [StashPack], the stashed objects they refer
+    /// to, and every stashed object that those depend on. Objects which
+    /// are shared between multiple handles are only written once. The
+    /// pack can be read back into this or any other stash using
+    /// [Self::import_pack].
+    ///
+    /// All handles in the pack must belong to this stash.
+    pub fn export_pack<W: Write>(&self, pack: &StashPack, writer: W) -> std::io::Result<()> {
+        snapshot::write_pack(&read_map(&self.map), &pack.roots(), writer)
+    }
+
+    /// Read a pack that was previously written using [Self::export_pack]
+    /// and add its objects to the stash, returning a [StashPack] with
+    /// handles to the same named objects. Objects which are already present
+    /// in the stash are reused rather than being duplicated. If an error
+    /// occurs, the stash is not modified.
+    pub fn import_pack<R: Read>(&self, reader: R) -> Result<StashPack, SnapshotError> {
+        let roots = snapshot::read_pack(&mut write_map(&self.map), reader)?;
+        let mut pack = StashPack::new();
+        for (name, hash) in roots {
+            pack.push(name, StashHandle::new(Arc::clone(&self.map), hash));
+        }
+        eviction::evict_over_limits(&self.map);
+        Ok(pack)
+    }
+
+    /// Does the stash contain an object with the given hash? This can
+    /// be used with [ObjectHash::from_stashable] to find out whether
+    /// an object is already stashed without stashing it.
+    ///
+    /// Small objects written with [Stasher::object] or in arrays of
+    /// objects are stored inline within the object that refers to them
+    /// rather than on their own, and so they are not found here unless
+    /// they were also stashed separately, for example with [Stash::stash].
+    pub fn contains(&self, hash: ObjectHash) -> bool {
+        read_map(&self.map).objects.contains_key(&hash)
+    }
+
+    /// Get a new handle to an object in the stash with the given hash,
+    /// increasing its reference count, or None if there is no such
+    /// object. This allows objects to be shared by parts of a program
+    /// that only exchange hashes.
+    ///
+    /// The type of the handle is not checked. It is up to the caller
+    /// to unstash the object using the same type it was stashed with.
+    /// As with [Self::contains], objects which are only stored inline
+    /// are not found.
+    pub fn handle_for<T>(&self, hash: ObjectHash) -> Option<StashHandle<T>> {
+        let map = read_map(&self.map);
+        if !map.objects.contains_key(&hash) {
+            return None;
+        }
+        Some(map.handle(hash))
+    }
+}
+
+impl Default for Stash {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
+/// Error returned by [Stash::try_stash] when a stash with collision
+/// detection finds an object whose hash matches an existing stashed
+/// object with different contents
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct HashCollision {
+    /// The hash shared by both objects
+    pub hash: ObjectHash,
+}
+
+impl std::fmt::Display for HashCollision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "hash collision on #{}", self.hash)
+    }
+}
+
+impl std::error::Error for HashCollision {}
+
 /// Errors that can happen during one of the round trip tests,
 /// which indicate a bug in an object's stashing and unstashing
 /// implementations. See each variant's documentation for
 /// explanations about how these bugs can be fixed.
 ///
 /// See [test_stash_roundtrip] and [test_stash_roundtrip_inplace].
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum RoundTripError {
     /// The object could not be unstashed because the contents
     /// that were stashed do not match the type of contents being
@@ -545,7 +1136,7 @@ where
 
     let unstashed_object = stash
         .unstash_with_context(&handle_to_original, unstash_context)
-        .map_err(|e| RoundTripError::BasicUnstashError(e))?;
+        .map_err(RoundTripError::BasicUnstashError)?;
 
     let hash_after_unstashing =
         ObjectHash::from_stashable_and_context(&unstashed_object, stash_context);
@@ -597,14 +1188,14 @@ where
 
     let hash_before_validation = hash_after_modifying;
 
-    let map = stash.map.borrow();
+    let map = read_map_for_unstashing(&stash.map);
     map.unstash_inplace(
-        handle_to_original.hash,
+        ObjectRef::Stashed(handle_to_original.hash),
         InplaceUnstashPhase::Validate,
         |unstasher| object.unstash_inplace(unstasher),
         unstash_context,
     )
-    .map_err(|e| RoundTripError::BasicUnstashError(e))?;
+    .map_err(RoundTripError::BasicUnstashError)?;
 
     let hash_after_validation = ObjectHash::from_stashable_and_context(&object, stash_context);
     if hash_after_validation != hash_before_validation {
@@ -612,12 +1203,12 @@ where
     }
 
     map.unstash_inplace(
-        handle_to_original.hash,
+        ObjectRef::Stashed(handle_to_original.hash),
         InplaceUnstashPhase::Write,
         |unstasher| object.unstash_inplace(unstasher),
         unstash_context,
     )
-    .map_err(|e| RoundTripError::UncaughtUnstashError(e))?;
+    .map_err(RoundTripError::UncaughtUnstashError)?;
 
     let hash_after_write = ObjectHash::from_stashable_and_context(&object, stash_context);
     if hash_after_write != handle_to_original.object_hash() {
@@ -632,14 +1223,14 @@ where
 /// not cleaned up, and dropping this handle may result in
 /// the stashed object being removed from the stash.
 pub struct StashHandle<T> {
-    map: Rc<RefCell<StashMap>>,
+    map: SharedStashMap,
     hash: ObjectHash,
-    _phantom_data: PhantomData<T>,
+    _phantom_data: PhantomData<fn() -> T>,
 }
 
 impl<T> StashHandle<T> {
     /// Create a new handle
-    fn new(map: Rc<RefCell<StashMap>>, hash: ObjectHash) -> StashHandle<T> {
+    fn new(map: SharedStashMap, hash: ObjectHash) -> StashHandle<T> {
         StashHandle {
             map,
             hash,
@@ -652,36 +1243,100 @@ impl<T> StashHandle<T> {
         self.hash
     }
 
+    /// Create a weak handle to the same stashed object, which doesn't
+    /// keep the object alive. See [WeakStashHandle].
+    pub fn downgrade(&self) -> WeakStashHandle<T> {
+        WeakStashHandle {
+            map: Arc::downgrade(&self.map),
+            hash: self.hash,
+            _phantom_data: PhantomData,
+        }
+    }
+
+    /// Create a new handle to the same stashed object but with a
+    /// different type, increasing its reference count. The types are
+    /// not checked, and unstashing the new handle only succeeds if the
+    /// stashed contents can be read as the new type. See also
+    /// [Stash::unstash_as] and [Migrate].
+    pub fn cast<U>(&self) -> StashHandle<U> {
+        read_map(&self.map).add_reference(self.hash);
+        StashHandle::new(Arc::clone(&self.map), self.hash)
+    }
+
     /// Get the reference count of the stashed object
     #[cfg(test)]
-    pub(crate) fn reference_count(&self) -> u16 {
-        self.map
-            .borrow()
+    pub(crate) fn reference_count(&self) -> u64 {
+        read_map(&self.map)
             .objects
             .get(&self.hash)
             .unwrap()
             .reference_count
-            .get()
+            .load(atomic::Ordering::Relaxed)
     }
 }
 
 /// Cloning a StashHandle increases its reference count
 impl<T> Clone for StashHandle<T> {
     fn clone(&self) -> Self {
-        self.map.borrow().add_reference(self.hash);
+        read_map(&self.map).add_reference(self.hash);
         Self {
-            map: Rc::clone(&self.map),
+            map: Arc::clone(&self.map),
             hash: self.hash,
             _phantom_data: PhantomData,
         }
     }
 }
 
-/// Dropping a StashHandle decreases its reference count
+/// Dropping a StashHandle decreases its reference count, or defers
+/// doing so until the current thread finishes unstashing.
 impl<T> Drop for StashHandle<T> {
     fn drop(&mut self) {
-        let mut map = self.map.borrow_mut();
-        map.remove_reference(self.hash);
+        release_or_defer(&self.map, Release::Handle(self.hash));
+    }
+}
+
+/// A weak reference to a stashed object, created using
+/// [StashHandle::downgrade]. Unlike a [StashHandle], a weak handle
+/// doesn't increase the object's reference count and so doesn't keep
+/// the object or the stash alive. It must be upgraded to a regular
+/// handle using [WeakStashHandle::upgrade] in order to be unstashed.
+pub struct WeakStashHandle<T> {
+    map: Weak<RwLock<StashMap>>,
+    hash: ObjectHash,
+    _phantom_data: PhantomData<fn() -> T>,
+}
+
+impl<T> WeakStashHandle<T> {
+    /// Get the hash of the stashed object, whether or not it still exists
+    pub fn object_hash(&self) -> ObjectHash {
+        self.hash
+    }
+
+    /// Get a new handle to the stashed object, increasing its
+    /// reference count, or None if the object is no longer present
+    /// in the stash. Since objects are identified by their contents,
+    /// this also succeeds if the object was removed and an object
+    /// with identical contents was stashed since.
+    pub fn upgrade(&self) -> Option<StashHandle<T>> {
+        let map = self.map.upgrade()?;
+        {
+            let stashmap = read_map(&map);
+            if !stashmap.objects.contains_key(&self.hash) {
+                return None;
+            }
+            stashmap.add_reference(self.hash);
+        }
+        Some(StashHandle::new(map, self.hash))
+    }
+}
+
+impl<T> Clone for WeakStashHandle<T> {
+    fn clone(&self) -> Self {
+        Self {
+            map: Weak::clone(&self.map),
+            hash: self.hash,
+            _phantom_data: PhantomData,
+        }
     }
 }
 
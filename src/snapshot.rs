@@ -0,0 +1,532 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, BufReader, BufWriter, Read, Write},
+};
+
+use crate::{ObjectHash, StashHandle, StashMap};
+
+/// The first bytes of every snapshot, used to recognize the format
+const SNAPSHOT_MAGIC: [u8; 4] = *b"HSSN";
+
+/// The first bytes of every pack, used to recognize the format
+const PACK_MAGIC: [u8; 4] = *b"HSPK";
+
+/// The version of the snapshot and pack formats that is written.
+/// Snapshots and packs with other versions are rejected when read.
+/// Builds with the hash128 feature write wider hashes and so use
+/// a distinct version, so that snapshots are never read by a build
+/// with a different hash size.
+#[cfg(not(feature = "hash128"))]
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// The version of the snapshot and pack formats that is written
+/// by builds with the hash128 feature
+#[cfg(feature = "hash128")]
+const SNAPSHOT_VERSION: u32 = 0x0001_0001;
+
+/// Error that can happen while reading a snapshot or a pack
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// The underlying reader failed, or ran out of data
+    Io(io::Error),
+
+    /// The data does not start with the expected header and
+    /// is probably not a snapshot or pack at all
+    NotASnapshot,
+
+    /// The snapshot or pack was written with a format version
+    /// that is not supported
+    UnsupportedVersion(u32),
+
+    /// The snapshot or pack is internally inconsistent, for
+    /// example because an object depends on another object
+    /// which is missing from it
+    Corrupted,
+
+    /// The stash detects hash collisions, and an object in the
+    /// snapshot or pack has the same hash as an existing stashed
+    /// object but different contents. Nothing was imported.
+    HashCollision(ObjectHash),
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> Self {
+        SnapshotError::Io(error)
+    }
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(error) => write!(f, "i/o error: {}", error),
+            SnapshotError::NotASnapshot => f.write_str("not a snapshot or pack"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported format version {:#x}", version)
+            }
+            SnapshotError::Corrupted => f.write_str("corrupted snapshot or pack"),
+            SnapshotError::HashCollision(hash) => write!(f, "hash collision on #{}", hash),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SnapshotError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// A collection of named [StashHandle]s, possibly of different types,
+/// which can be written to and read from a single pack using
+/// [crate::Stash::export_pack] and [crate::Stash::import_pack].
+///
+/// Holding a StashPack keeps all of its handles' stashed objects alive.
+pub struct StashPack {
+    roots: Vec<(String, StashHandle<()>)>,
+}
+
+impl StashPack {
+    /// Create a new empty StashPack
+    pub fn new() -> StashPack {
+        StashPack { roots: Vec::new() }
+    }
+
+    /// Get the number of named handles in the pack
+    pub fn len(&self) -> usize {
+        self.roots.len()
+    }
+
+    /// Returns true iff the pack has no named handles
+    pub fn is_empty(&self) -> bool {
+        self.roots.is_empty()
+    }
+
+    /// Add a copy of the given handle with the given name, replacing
+    /// any existing handle with the same name
+    pub fn insert<T>(&mut self, name: &str, handle: &StashHandle<T>) {
+        let handle = handle.cast::<()>();
+        match self.roots.iter_mut().find(|(n, _)| n == name) {
+            Some((_, existing_handle)) => *existing_handle = handle,
+            None => self.roots.push((name.to_string(), handle)),
+        }
+    }
+
+    /// Get a copy of the handle with the given name, if there is one.
+    ///
+    /// The type of the returned handle is not checked. It is up to
+    /// the caller to use the same type that the handle was inserted with.
+    pub fn get<T>(&self, name: &str) -> Option<StashHandle<T>> {
+        self.roots
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, handle)| handle.cast())
+    }
+
+    /// Remove the handle with the given name and return it, if there is one.
+    ///
+    /// The type of the returned handle is not checked. It is up to
+    /// the caller to use the same type that the handle was inserted with.
+    pub fn remove<T>(&mut self, name: &str) -> Option<StashHandle<T>> {
+        let index = self.roots.iter().position(|(n, _)| n == name)?;
+        let (_, handle) = self.roots.remove(index);
+        Some(handle.cast())
+    }
+
+    /// Iterate over the names of all handles in the pack, in the order
+    /// they were inserted
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.roots.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Get the names and object hashes of all handles in the pack
+    pub(crate) fn roots(&self) -> Vec<(&str, ObjectHash)> {
+        self.roots
+            .iter()
+            .map(|(name, handle)| (name.as_str(), handle.object_hash()))
+            .collect()
+    }
+
+    /// Add a handle without checking for existing names
+    pub(crate) fn push(&mut self, name: String, handle: StashHandle<()>) {
+        self.roots.push((name, handle));
+    }
+}
+
+impl Default for StashPack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The serialized contents of an object which was read from a
+/// snapshot but not yet inserted into a [StashMap]
+struct ImportedObject {
+    bytes: Vec<u8>,
+    dependencies: Vec<ObjectHash>,
+}
+
+fn write_u32<W: Write>(writer: &mut W, x: u32) -> io::Result<()> {
+    writer.write_all(&x.to_be_bytes())
+}
+
+fn write_u64<W: Write>(writer: &mut W, x: u64) -> io::Result<()> {
+    writer.write_all(&x.to_be_bytes())
+}
+
+fn write_hash<W: Write>(writer: &mut W, hash: ObjectHash) -> io::Result<()> {
+    writer.write_all(&hash.to_be_bytes())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn read_hash<R: Read>(reader: &mut R) -> io::Result<ObjectHash> {
+    let mut bytes = [0; ObjectHash::SIZE];
+    reader.read_exact(&mut bytes)?;
+    Ok(ObjectHash::from_be_bytes(bytes))
+}
+
+/// Read a length prefix, making sure that it fits in memory
+fn read_length<R: Read>(reader: &mut R) -> Result<usize, SnapshotError> {
+    usize::try_from(read_u64(reader)?).map_err(|_| SnapshotError::Corrupted)
+}
+
+/// Find every object reachable from the given roots, exactly once each
+fn collect_reachable<I: Iterator<Item = ObjectHash>>(
+    stashmap: &StashMap,
+    roots: I,
+) -> Vec<ObjectHash> {
+    let mut reachable: Vec<ObjectHash> = Vec::new();
+    let mut visited: HashSet<ObjectHash> = HashSet::new();
+    let mut stack: Vec<ObjectHash> = roots.collect();
+    while let Some(hash) = stack.pop() {
+        if !visited.insert(hash) {
+            continue;
+        }
+        reachable.push(hash);
+        let object = stashmap.objects.get(&hash).unwrap();
+        stack.extend_from_slice(&object.dependencies);
+    }
+    reachable
+}
+
+/// Write the number of objects followed by each object as its hash,
+/// its length-prefixed bytes and its length-prefixed list of dependency
+/// hashes.
+fn write_objects<W: Write>(
+    writer: &mut W,
+    stashmap: &StashMap,
+    hashes: &[ObjectHash],
+) -> io::Result<()> {
+    write_u64(writer, hashes.len() as u64)?;
+
+    for hash in hashes {
+        let object = stashmap.objects.get(hash).unwrap();
+        write_hash(writer, *hash)?;
+        write_u64(writer, object.bytes.len() as u64)?;
+        writer.write_all(&object.bytes)?;
+        write_u64(writer, object.dependencies.len() as u64)?;
+        for dependency in &object.dependencies {
+            write_hash(writer, *dependency)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Read objects as written by [write_objects]
+fn read_objects<R: Read>(
+    reader: &mut R,
+) -> Result<HashMap<ObjectHash, ImportedObject>, SnapshotError> {
+    let num_objects = read_length(reader)?;
+
+    let mut objects: HashMap<ObjectHash, ImportedObject> = HashMap::new();
+    for _ in 0..num_objects {
+        let hash = read_hash(reader)?;
+
+        let num_bytes = read_length(reader)?;
+        let mut bytes = Vec::new();
+        reader.take(num_bytes as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != num_bytes {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let num_dependencies = read_length(reader)?;
+        let mut dependencies = Vec::new();
+        for _ in 0..num_dependencies {
+            dependencies.push(read_hash(reader)?);
+        }
+
+        if objects
+            .insert(
+                hash,
+                ImportedObject {
+                    bytes,
+                    dependencies,
+                },
+            )
+            .is_some()
+        {
+            return Err(SnapshotError::Corrupted);
+        }
+    }
+
+    Ok(objects)
+}
+
+/// Read and check the magic number and format version
+fn read_header<R: Read>(reader: &mut R, expected_magic: [u8; 4]) -> Result<(), SnapshotError> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != expected_magic {
+        return Err(SnapshotError::NotASnapshot);
+    }
+
+    let version = read_u32(reader)?;
+    if version != SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+
+    Ok(())
+}
+
+/// Write the given root object and every object that it transitively
+/// depends on to the writer.
+///
+/// The format consists of a 4-byte magic number, a 32-bit format version,
+/// the root hash and the number of objects, followed by each object as its
+/// hash, its length-prefixed bytes and its length-prefixed list of dependency
+/// hashes. All integers are big-endian.
+pub(crate) fn write_snapshot<W: Write>(
+    stashmap: &StashMap,
+    root: ObjectHash,
+    writer: W,
+) -> io::Result<()> {
+    let reachable = collect_reachable(stashmap, std::iter::once(root));
+
+    let mut writer = BufWriter::new(writer);
+
+    writer.write_all(&SNAPSHOT_MAGIC)?;
+    write_u32(&mut writer, SNAPSHOT_VERSION)?;
+    write_hash(&mut writer, root)?;
+    write_objects(&mut writer, stashmap, &reachable)?;
+
+    writer.flush()
+}
+
+/// Read a snapshot previously written by [write_snapshot] and insert its
+/// objects into the stashmap. Objects which already exist in the stashmap
+/// are reused rather than duplicated. On success, the root object has had
+/// one reference added on behalf of the caller, and its hash is returned.
+/// On failure, the stashmap is not modified.
+pub(crate) fn read_snapshot<R: Read>(
+    stashmap: &mut StashMap,
+    reader: R,
+) -> Result<ObjectHash, SnapshotError> {
+    let mut reader = BufReader::new(reader);
+
+    read_header(&mut reader, SNAPSHOT_MAGIC)?;
+
+    let root = read_hash(&mut reader)?;
+    let mut objects = read_objects(&mut reader)?;
+
+    check_object_graph(&objects, std::iter::once(root))?;
+    check_for_collisions(stashmap, &objects)?;
+
+    insert_imported(stashmap, root, &mut objects);
+
+    Ok(root)
+}
+
+/// Write a pack containing the given named roots and every object that
+/// they transitively depend on to the writer.
+///
+/// The format is the same as for a single snapshot, except that it uses a
+/// different magic number and that the root hash is replaced by the number
+/// of roots, followed by each root's length-prefixed UTF-8 name and hash.
+pub(crate) fn write_pack<W: Write>(
+    stashmap: &StashMap,
+    roots: &[(&str, ObjectHash)],
+    writer: W,
+) -> io::Result<()> {
+    let reachable = collect_reachable(stashmap, roots.iter().map(|(_, hash)| *hash));
+
+    let mut writer = BufWriter::new(writer);
+
+    writer.write_all(&PACK_MAGIC)?;
+    write_u32(&mut writer, SNAPSHOT_VERSION)?;
+    write_u64(&mut writer, roots.len() as u64)?;
+    for (name, hash) in roots {
+        write_u64(&mut writer, name.len() as u64)?;
+        writer.write_all(name.as_bytes())?;
+        write_hash(&mut writer, *hash)?;
+    }
+    write_objects(&mut writer, stashmap, &reachable)?;
+
+    writer.flush()
+}
+
+/// Read a pack previously written by [write_pack] and insert its objects
+/// into the stashmap, deduplicating them as with [read_snapshot]. On success,
+/// each root has had one reference added on behalf of the caller, and the
+/// roots' names and hashes are returned. On failure, the stashmap is not
+/// modified.
+pub(crate) fn read_pack<R: Read>(
+    stashmap: &mut StashMap,
+    reader: R,
+) -> Result<Vec<(String, ObjectHash)>, SnapshotError> {
+    let mut reader = BufReader::new(reader);
+
+    read_header(&mut reader, PACK_MAGIC)?;
+
+    let num_roots = read_length(&mut reader)?;
+    let mut roots: Vec<(String, ObjectHash)> = Vec::new();
+    for _ in 0..num_roots {
+        let name_len = read_length(&mut reader)?;
+        let mut name = Vec::new();
+        (&mut reader).take(name_len as u64).read_to_end(&mut name)?;
+        if name.len() != name_len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let name = String::from_utf8(name).map_err(|_| SnapshotError::Corrupted)?;
+        if roots.iter().any(|(other_name, _)| *other_name == name) {
+            return Err(SnapshotError::Corrupted);
+        }
+        let hash = read_hash(&mut reader)?;
+        roots.push((name, hash));
+    }
+
+    let mut objects = read_objects(&mut reader)?;
+
+    check_object_graph(&objects, roots.iter().map(|(_, hash)| *hash))?;
+    check_for_collisions(stashmap, &objects)?;
+
+    for (_, hash) in &roots {
+        insert_imported(stashmap, *hash, &mut objects);
+    }
+
+    Ok(roots)
+}
+
+/// Make sure that the object graph is complete and acyclic when
+/// starting from the given roots, so that it can be safely inserted.
+/// The graph is traversed depth-first using an explicit stack, so
+/// that arbitrarily deep graphs can't overflow the call stack.
+fn check_object_graph<I: Iterator<Item = ObjectHash>>(
+    objects: &HashMap<ObjectHash, ImportedObject>,
+    roots: I,
+) -> Result<(), SnapshotError> {
+    #[derive(Copy, Clone, Eq, PartialEq)]
+    enum Status {
+        Visiting,
+        Done,
+    }
+
+    let mut statuses: HashMap<ObjectHash, Status> = HashMap::new();
+
+    // The objects currently being visited, each with the index
+    // of the next dependency to visit
+    let mut stack: Vec<(ObjectHash, usize)> = Vec::new();
+
+    for root in roots {
+        if statuses.contains_key(&root) {
+            continue;
+        }
+        if !objects.contains_key(&root) {
+            return Err(SnapshotError::Corrupted);
+        }
+        statuses.insert(root, Status::Visiting);
+        stack.push((root, 0));
+
+        while let Some((hash, index)) = stack.last_mut() {
+            let Some(dependency) = objects[hash].dependencies.get(*index).copied() else {
+                statuses.insert(*hash, Status::Done);
+                stack.pop();
+                continue;
+            };
+            *index += 1;
+            match statuses.get(&dependency) {
+                Some(Status::Done) => continue,
+                Some(Status::Visiting) => return Err(SnapshotError::Corrupted),
+                None => (),
+            }
+            if !objects.contains_key(&dependency) {
+                return Err(SnapshotError::Corrupted);
+            }
+            statuses.insert(dependency, Status::Visiting);
+            stack.push((dependency, 0));
+        }
+    }
+    Ok(())
+}
+
+/// If the stashmap detects hash collisions, make sure that every
+/// imported object which is already stashed has the same contents as
+/// the stashed object, like [StashMap::check_for_collision]. This is
+/// done before anything is inserted, so that a collision leaves the
+/// stashmap unchanged. Snapshots don't record which floats are hashed
+/// canonically, and so imported objects must match exactly.
+fn check_for_collisions(
+    stashmap: &StashMap,
+    objects: &HashMap<ObjectHash, ImportedObject>,
+) -> Result<(), SnapshotError> {
+    if !stashmap.detect_collisions {
+        return Ok(());
+    }
+    for (hash, object) in objects {
+        if let Some(existing) = stashmap.objects.get(hash) {
+            if existing.bytes != object.bytes || existing.dependencies != object.dependencies {
+                return Err(SnapshotError::HashCollision(*hash));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Insert an imported object and its dependencies into the stashmap,
+/// adding one reference to it. This mirrors [StashMap::stash_and_add_reference]
+/// in that existing objects only have their reference count increased,
+/// while new objects are inserted and add a reference to each of their
+/// dependencies. Dependencies are inserted before the objects that depend
+/// on them, using an explicit stack. The object graph must already have
+/// been checked using [check_object_graph].
+fn insert_imported(
+    stashmap: &mut StashMap,
+    root: ObjectHash,
+    objects: &mut HashMap<ObjectHash, ImportedObject>,
+) {
+    if stashmap.objects.contains_key(&root) {
+        stashmap.add_reference(root);
+        return;
+    }
+
+    // The objects waiting for their dependencies to be inserted, each
+    // with the index of the next dependency to insert
+    let mut stack: Vec<(ObjectHash, ImportedObject, usize)> =
+        vec![(root, objects.remove(&root).unwrap(), 0)];
+
+    while let Some((_, object, index)) = stack.last_mut() {
+        if let Some(dependency) = object.dependencies.get(*index).copied() {
+            *index += 1;
+            if stashmap.objects.contains_key(&dependency) {
+                stashmap.add_reference(dependency);
+            } else {
+                stack.push((dependency, objects.remove(&dependency).unwrap(), 0));
+            }
+            continue;
+        }
+        let (hash, object, _) = stack.pop().unwrap();
+        stashmap.insert_object(hash, object.bytes, object.dependencies);
+    }
+}
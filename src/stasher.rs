@@ -1,11 +1,44 @@
-use std::hash::Hasher;
-
-use crate::{valuetypes::PrimitiveReadWrite, ObjectHash, StashMap, Stashable, ValueType};
+use crate::{
+    hasher::{DefaultObjectHasher, HashValue, ObjectHasher},
+    valuetypes::{
+        type_fingerprint, write_length_at, write_length_to, CanonicalFloat, CanonicalFloats,
+        PrimitiveReadWrite, INLINE_OBJECT_TAG, LONG_LENGTH_ESCAPE,
+    },
+    ObjectHash, StashHandle, StashMap, Stashable, StashedObject, ValueType,
+};
+
+/// The largest serialized size of an object that is stored inline
+/// within the object that refers to it, rather than as a separate
+/// object in the stashmap. Storing an object separately costs at
+/// least a [StashedObject] as well as its hash, which is stored
+/// once as the stashmap's key and again as a dependency.
+pub(crate) const MAX_INLINE_SIZE: usize = std::mem::size_of::<StashedObject>();
 
 /// A [Stasher] backend which hashes the object contents
 struct HashingStasher<'a> {
-    hasher: &'a mut seahash::SeaHasher,
-    current_unordered_hash: Option<u64>,
+    hasher: &'a mut DefaultObjectHasher,
+    current_unordered_hash: Option<HashValue>,
+
+    /// The approximate number of bytes the object will take up
+    /// when serialized, or None if it has any dependencies
+    size: Option<usize>,
+
+    /// The number of raw bytes written so far, which includes the
+    /// type tag of every value and so is nonzero once anything has
+    /// been written
+    written: usize,
+}
+
+impl<'a> HashingStasher<'a> {
+    /// Hash the hash of a dependency, either in order or
+    /// as part of an unordered sequence
+    fn add_dependency(&mut self, hash: ObjectHash) {
+        match self.current_unordered_hash.as_mut() {
+            Some(unorderd_hash) => *unorderd_hash ^= hash.0,
+            None => self.hasher.write_hash(hash),
+        }
+        self.size = None;
+    }
 }
 
 /// A [Stasher] backend which serializes the object contents
@@ -17,6 +50,10 @@ struct SerializingStasher<'a> {
     /// that object hashes will be added to
     dependencies: &'a mut Vec<ObjectHash>,
 
+    /// Where floats written with the `_canonical` methods were
+    /// written, which is only tracked if collisions are detected
+    canonical_floats: &'a mut Vec<CanonicalFloats>,
+
     /// The stashmap into which we are serializing
     stashmap: &'a mut StashMap,
 }
@@ -44,8 +81,9 @@ pub enum Order {
 }
 
 /// Used when serializing sequences to know where to write
-/// the length prefix after the count is known.
-struct SequenceBookmark(usize);
+/// the length prefix after the count is known, or None if
+/// the length was known up front and already written.
+struct SequenceBookmark(Option<usize>);
 
 impl<'a> StasherBackend<'a> {
     /// Write a slice of raw bytes
@@ -53,38 +91,90 @@ impl<'a> StasherBackend<'a> {
         match self {
             StasherBackend::Hash(hash) => {
                 hash.hasher.write(bytes);
+                hash.size = hash.size.map(|size| size + bytes.len());
+                hash.written += bytes.len();
             }
             StasherBackend::Serialize(serialize) => serialize.data.extend_from_slice(bytes),
         }
     }
 
-    /// Stash and track a dependency. When hashing, this simply
-    /// hashes the object. When serializing, this stashes the
-    /// object in the stashmap and adds a reference to it.
-    fn stash_dependency<OtherContext: Copy, F: FnMut(&mut Stasher<'_, OtherContext>)>(
+    /// Get the number of raw bytes written so far
+    fn written_len(&self) -> usize {
+        match self {
+            StasherBackend::Hash(hash) => hash.written,
+            StasherBackend::Serialize(serialize) => serialize.data.len(),
+        }
+    }
+
+    /// Stash a single object along with its type tag. When hashing,
+    /// this hashes the object exactly like any other dependency. When
+    /// serializing, objects which are small enough and which have no
+    /// dependencies of their own are written inline together with their
+    /// hash. Other objects are stashed in the stashmap as a dependency.
+    /// The decision is made using the size found while hashing, so that
+    /// large objects are only serialized once.
+    fn stash_object<OtherContext: Copy, F: FnMut(&mut Stasher<'_, OtherContext>)>(
         &mut self,
-        f: F,
+        mut f: F,
         context: OtherContext,
     ) {
-        match self {
-            StasherBackend::Hash(hasher) => {
+        let serializer = match self {
+            StasherBackend::Hash(_) => {
+                self.write_raw_bytes(&[ValueType::StashedObject.to_byte()]);
                 let hash = ObjectHash::with_stasher_and_context(f, context);
-                match hasher.current_unordered_hash.as_mut() {
-                    Some(unorderd_hash) => *unorderd_hash ^= hash.0,
-                    None => hasher.hasher.write_u64(hash.0),
-                }
+                self.add_existing_dependency(hash);
+                return;
+            }
+            StasherBackend::Serialize(serializer) => serializer,
+        };
+
+        let (hash, size) = ObjectHash::with_stasher_and_context_measured(&mut f, context);
+
+        if size.is_some_and(|size| size <= MAX_INLINE_SIZE) {
+            let object = serializer.stashmap.serialize(f, context);
+            if object.dependencies.is_empty() && object.bytes.len() <= MAX_INLINE_SIZE {
+                serializer.data.push(INLINE_OBJECT_TAG);
+                serializer.data.extend_from_slice(&hash.to_be_bytes());
+                serializer
+                    .data
+                    .extend_from_slice(&(object.bytes.len() as u32).to_be_bytes());
+                let offset = serializer.data.len();
+                serializer
+                    .canonical_floats
+                    .extend(
+                        object
+                            .canonical_floats
+                            .iter()
+                            .map(|floats| CanonicalFloats {
+                                offset: offset + floats.offset,
+                                ..*floats
+                            }),
+                    );
+                serializer.data.extend_from_slice(&object.bytes);
+                return;
             }
+            // The object's serialized contents didn't match what was
+            // found while hashing, so it is stashed separately after all
+            serializer.stashmap.insert_serialized(hash, object);
+        } else {
+            serializer
+                .stashmap
+                .add_reference_or_serialize(hash, f, context);
+        }
+
+        serializer.data.push(ValueType::StashedObject.to_byte());
+        serializer.dependencies.push(hash);
+    }
+
+    /// Track a dependency on an object which is already stashed.
+    /// When hashing, this hashes the given hash exactly as if the
+    /// object had been stashed. When serializing, this adds a
+    /// reference to the existing object, which must exist.
+    fn add_existing_dependency(&mut self, hash: ObjectHash) {
+        match self {
+            StasherBackend::Hash(hasher) => hasher.add_dependency(hash),
             StasherBackend::Serialize(serializer) => {
-                // TODO: consider adding a small object optimization.
-                // For example if the serialized object contents take
-                // up no more space than a reference to the contents
-                // elsewhere in the stashmap, then just store the contents
-                // directly rather than adding a dependency. This
-                // could be done cleverly by first extending the
-                // hashing backend to sum the content size, allowing
-                // this decision to be made after the ObjectHash has
-                // been computed but before the stashmap is modified.
-                let hash = serializer.stashmap.stash_and_add_reference(f, context);
+                serializer.stashmap.add_reference(hash);
                 serializer.dependencies.push(hash);
             }
         }
@@ -93,9 +183,10 @@ impl<'a> StasherBackend<'a> {
     /// Start a sequence of objects. When hashing, this
     /// instructs the hasher whether to combine hashes of
     /// subsequent objects in an order-sensitive or order-
-    /// insensitive manner. When serializing, this makes
-    /// space to store a prefixed length.
-    fn begin_sequence(&mut self, ordering: Order) -> SequenceBookmark {
+    /// insensitive manner. When serializing, this writes
+    /// the prefixed length if it is already known, and
+    /// otherwise makes space to store it.
+    fn begin_sequence(&mut self, ordering: Order, length: Option<usize>) -> SequenceBookmark {
         match self {
             StasherBackend::Hash(hasher) => {
                 if let Order::Unordered = ordering {
@@ -103,9 +194,14 @@ impl<'a> StasherBackend<'a> {
                 }
 
                 // This will not be used
-                SequenceBookmark(usize::MAX)
+                SequenceBookmark(None)
             }
             StasherBackend::Serialize(serializer) => {
+                if let Some(length) = length {
+                    write_length_to(serializer.data, length);
+                    return SequenceBookmark(None);
+                }
+
                 let bookmark = serializer.data.len();
                 let placeholder_length: u32 = 0;
                 for b in placeholder_length.to_be_bytes() {
@@ -113,25 +209,47 @@ impl<'a> StasherBackend<'a> {
                 }
 
                 // Where to write the length prefix later
-                SequenceBookmark(bookmark)
+                SequenceBookmark(Some(bookmark))
             }
         }
     }
 
     /// Complete a sequence of objects. When hashing, this
     /// simply hashes the length. When serializing, this
-    /// writes the length at the previously bookmarked location.
-    fn end_sequence(&mut self, bookmark: SequenceBookmark, length: u32) {
+    /// writes the length at the previously bookmarked location,
+    /// unless it was already written. Lengths of 2^32 - 1 or
+    /// more are escaped and followed by their 64-bit value, see
+    /// [LONG_LENGTH_ESCAPE].
+    fn end_sequence(&mut self, bookmark: SequenceBookmark, length: usize) {
         match self {
             StasherBackend::Hash(hasher) => {
                 if let Some(hash) = hasher.current_unordered_hash.take() {
-                    hasher.hasher.write_u64(hash);
+                    hasher.hasher.write_hash(ObjectHash(hash));
+                }
+                match u32::try_from(length) {
+                    Ok(length) if length != LONG_LENGTH_ESCAPE => {
+                        hasher.hasher.write_u32(length);
+                        hasher.size = hasher.size.map(|size| size + u32::SIZE);
+                    }
+                    _ => {
+                        hasher.hasher.write_u32(LONG_LENGTH_ESCAPE);
+                        hasher.hasher.write(&(length as u64).to_be_bytes());
+                        hasher.size = hasher.size.map(|size| size + u32::SIZE + u64::SIZE);
+                    }
                 }
-                hasher.hasher.write_u32(length)
             }
             StasherBackend::Serialize(serializer) => {
-                for (i, b) in length.to_be_bytes().into_iter().enumerate() {
-                    serializer.data[bookmark.0 + i] = b;
+                let Some(position) = bookmark.0 else {
+                    return;
+                };
+                let old_len = serializer.data.len();
+                write_length_at(serializer.data, position, length);
+                // Floats after an escaped length were moved along with it
+                let moved = serializer.data.len() - old_len;
+                for floats in serializer.canonical_floats.iter_mut() {
+                    if floats.offset > position {
+                        floats.offset += moved;
+                    }
                 }
             }
         }
@@ -144,6 +262,10 @@ impl<'a> StasherBackend<'a> {
 pub struct Stasher<'a, Context = ()> {
     backend: StasherBackend<'a>,
     context: Context,
+
+    /// Whether a nonzero version was written, which may precede the
+    /// type fingerprint
+    version_written: bool,
 }
 
 /// Private methods
@@ -152,6 +274,7 @@ impl<'a, Context> Stasher<'a, Context> {
     pub(crate) fn new_serializer(
         data: &'a mut Vec<u8>,
         dependencies: &'a mut Vec<ObjectHash>,
+        canonical_floats: &'a mut Vec<CanonicalFloats>,
         stashmap: &'a mut StashMap,
         context: Context,
     ) -> Stasher<'a, Context> {
@@ -159,23 +282,28 @@ impl<'a, Context> Stasher<'a, Context> {
             backend: StasherBackend::Serialize(SerializingStasher {
                 data,
                 dependencies,
+                canonical_floats,
                 stashmap,
             }),
             context,
+            version_written: false,
         }
     }
 
     /// Create a new Stasher for hashing
     pub(crate) fn new_hasher(
-        hasher: &'a mut seahash::SeaHasher,
+        hasher: &'a mut DefaultObjectHasher,
         context: Context,
     ) -> Stasher<'a, Context> {
         Stasher {
             backend: StasherBackend::Hash(HashingStasher {
                 hasher,
                 current_unordered_hash: None,
+                size: Some(0),
+                written: 0,
             }),
             context,
+            version_written: false,
         }
     }
 
@@ -190,12 +318,17 @@ impl<'a, Context> Stasher<'a, Context> {
         x.write_raw_bytes_to(self);
     }
 
-    /// Helper method to write a slice of primitives
-    fn write_primitive_array<T: PrimitiveReadWrite, I: Iterator<Item = T>>(&mut self, it: I) {
+    /// Helper method to write a sequence of primitives, whose length
+    /// is given if it is known up front
+    fn write_primitive_array<T: PrimitiveReadWrite, I: Iterator<Item = T>>(
+        &mut self,
+        it: I,
+        known_length: Option<usize>,
+    ) {
         self.backend
             .write_raw_bytes(&[ValueType::Array(T::TYPE).to_byte()]);
-        let bookmark = self.backend.begin_sequence(Order::Ordered);
-        let mut length: u32 = 0;
+        let bookmark = self.backend.begin_sequence(Order::Ordered, known_length);
+        let mut length: usize = 0;
         for x in it {
             x.write_raw_bytes_to(self);
             length += 1;
@@ -203,6 +336,96 @@ impl<'a, Context> Stasher<'a, Context> {
         self.backend.end_sequence(bookmark, length);
     }
 
+    /// Helper method to write a sequence of objects, whose length is
+    /// given if it is known up front
+    fn write_object_array<'b, C1: Copy, T: 'b + Stashable<C1>, I: Iterator<Item = &'b T>>(
+        &mut self,
+        it: I,
+        known_length: Option<usize>,
+        order: Order,
+        context: C1,
+    ) {
+        self.backend
+            .write_raw_bytes(&[ValueType::ArrayOfObjects.to_byte()]);
+        let bookmark = self.backend.begin_sequence(order, known_length);
+        let mut length: usize = 0;
+        for object in it {
+            self.backend
+                .stash_object(|stasher| object.stash(stasher), context);
+            length += 1;
+        }
+        self.backend.end_sequence(bookmark, length);
+    }
+
+    /// Record that the given number of floats of type T were just
+    /// written with the `_canonical` methods, when serializing into
+    /// a stashmap that detects collisions. Such floats are compared
+    /// in their canonical form, so that objects which only differ in
+    /// the bits of equivalent floats are not reported as collisions.
+    fn record_canonical_floats<T: CanonicalFloat>(&mut self, count: usize) {
+        if let StasherBackend::Serialize(serializer) = &mut self.backend {
+            if serializer.stashmap.detect_collisions && count > 0 {
+                serializer.canonical_floats.push(CanonicalFloats {
+                    offset: serializer.data.len() - count * T::SIZE,
+                    count,
+                    float_type: T::TYPE,
+                });
+            }
+        }
+    }
+
+    /// Helper method to write a single float which is hashed canonically
+    fn write_float_canonical<T: CanonicalFloat>(&mut self, x: T) {
+        if self.hashing() {
+            self.write_primitive(x.canonical());
+        } else {
+            self.write_primitive(x);
+            self.record_canonical_floats::<T>(1);
+        }
+    }
+
+    /// Helper method to write a slice of floats which are hashed canonically
+    fn write_float_array_canonical<T: CanonicalFloat>(&mut self, x: &[T]) {
+        if self.hashing() {
+            self.write_primitive_array(x.iter().map(|x| x.canonical()), Some(x.len()));
+        } else {
+            self.write_primitive_array(x.iter().cloned(), Some(x.len()));
+            self.record_canonical_floats::<T>(x.len());
+        }
+    }
+
+    /// Write a reference to an object which is already stashed in the
+    /// same stash, given only its hash. This is equivalent to stashing
+    /// that object again with [Stasher::object].
+    pub(crate) fn existing_object(&mut self, hash: ObjectHash) {
+        self.write_raw_bytes(&[ValueType::StashedObject.to_byte()]);
+        self.backend.add_existing_dependency(hash);
+    }
+
+    /// Write a reference to the object referred to by the given handle,
+    /// as if that object had been stashed again with [Stasher::object].
+    /// When serializing, this only succeeds if the handle belongs to the
+    /// stash being serialized into, and otherwise returns false without
+    /// writing anything.
+    pub(crate) fn existing_handle<T>(&mut self, handle: &StashHandle<T>) -> bool {
+        if let StasherBackend::Serialize(serializer) = &self.backend {
+            if !serializer.stashmap.is_shared_as(&handle.map) {
+                return false;
+            }
+        }
+        self.existing_object(handle.object_hash());
+        true
+    }
+
+    /// Get the approximate serialized size of everything hashed so far,
+    /// or None if any dependencies were hashed or if not hashing
+    pub(crate) fn hashed_size(&self) -> Option<usize> {
+        match &self.backend {
+            StasherBackend::Hash(hasher) => hasher.size,
+            StasherBackend::Serialize(_) => None,
+        }
+    }
+
     /// Returns true iff the backend is hashing and not serializing
     pub(crate) fn hashing(&self) -> bool {
         match &self.backend {
@@ -214,6 +437,52 @@ impl<'a, Context> Stasher<'a, Context> {
 
 /// Public methods
 impl<'a, Context: Copy> Stasher<'a, Context> {
+    /// Write the version number of the object's stashed format, which
+    /// can be read back with [crate::Unstasher::version] to support
+    /// unstashing objects that were stashed by older code. This must
+    /// be called before any other values are written. Version 0 is
+    /// the default for objects without a version and writes nothing,
+    /// so adding `stasher.version(0)` does not change any hashes.
+    ///
+    /// This method panics if other values were already written.
+    pub fn version(&mut self, version: u32) {
+        assert!(
+            self.backend.written_len() == 0,
+            "Stasher::version must be called before writing any other values"
+        );
+        if version == 0 {
+            return;
+        }
+        self.write_raw_bytes(&[ValueType::Version.to_byte()]);
+        version.write_raw_bytes_to(self);
+        self.version_written = true;
+    }
+
+    /// Write a fingerprint of the object's type name, so that
+    /// [crate::Unstasher::type_name] can verify that the object is
+    /// unstashed as the type it was stashed as. The fingerprint is
+    /// hashed along with the object's contents, so that two types
+    /// with different names never share stashed objects even if they
+    /// stash the same values. The name should stay the same when the
+    /// type is renamed or moved, and so it is given explicitly rather
+    /// than taken from [std::any::type_name]. This must be called
+    /// before any other values are written, except for the version.
+    ///
+    /// This method panics if other values were already written.
+    pub fn type_name(&mut self, name: &str) {
+        let version_len = if self.version_written {
+            1 + u32::SIZE
+        } else {
+            0
+        };
+        assert!(
+            self.backend.written_len() == version_len,
+            "Stasher::type_name must be called before writing any values other than the version"
+        );
+        self.write_raw_bytes(&[ValueType::TypeFingerprint.to_byte()]);
+        type_fingerprint(name).write_raw_bytes_to(self);
+    }
+
     /// Write a single bool value
     pub fn bool(&mut self, x: bool) {
         self.write_primitive::<bool>(x);
@@ -279,104 +548,184 @@ impl<'a, Context: Copy> Stasher<'a, Context> {
         self.write_primitive::<f64>(x);
     }
 
+    /// Write a single u128 value
+    pub fn u128(&mut self, x: u128) {
+        self.write_primitive::<u128>(x);
+    }
+
+    /// Write a single i128 value
+    pub fn i128(&mut self, x: i128) {
+        self.write_primitive::<i128>(x);
+    }
+
+    /// Write a single char value
+    pub fn char(&mut self, x: char) {
+        self.write_primitive::<char>(x);
+    }
+
+    /// Write a single f32 value which is hashed in its canonical form,
+    /// so that `-0.0` hashes the same as `0.0` and all NaNs hash the
+    /// same. See [crate::PrimitiveType] for details. The value is
+    /// unstashed using [crate::Unstasher::f32] as usual, but an object
+    /// that is stashed again with an equivalent value keeps the exact
+    /// bits it was first stashed with.
+    pub fn f32_canonical(&mut self, x: f32) {
+        self.write_float_canonical(x);
+    }
+
+    /// Write a single f64 value which is hashed in its canonical form.
+    /// See [Self::f32_canonical].
+    pub fn f64_canonical(&mut self, x: f64) {
+        self.write_float_canonical(x);
+    }
+
     /// Write an array of u8 values from a slice
     pub fn array_of_u8_slice(&mut self, x: &[u8]) {
-        self.write_primitive_array(x.iter().cloned());
+        self.write_primitive_array(x.iter().cloned(), Some(x.len()));
     }
 
     /// Write an array of i8 values from a slice
     pub fn array_of_i8_slice(&mut self, x: &[i8]) {
-        self.write_primitive_array(x.iter().cloned());
+        self.write_primitive_array(x.iter().cloned(), Some(x.len()));
     }
 
     /// Write an array of u16 values from a slice
     pub fn array_of_u16_slice(&mut self, x: &[u16]) {
-        self.write_primitive_array(x.iter().cloned());
+        self.write_primitive_array(x.iter().cloned(), Some(x.len()));
     }
 
     /// Write an array of i16 values from a slice
     pub fn array_of_i16_slice(&mut self, x: &[i16]) {
-        self.write_primitive_array(x.iter().cloned());
+        self.write_primitive_array(x.iter().cloned(), Some(x.len()));
     }
 
     /// Write an array of u32 values from a slice
     pub fn array_of_u32_slice(&mut self, x: &[u32]) {
-        self.write_primitive_array(x.iter().cloned());
+        self.write_primitive_array(x.iter().cloned(), Some(x.len()));
     }
 
     /// Write an array of i32 values from a slice
     pub fn array_of_i32_slice(&mut self, x: &[i32]) {
-        self.write_primitive_array(x.iter().cloned());
+        self.write_primitive_array(x.iter().cloned(), Some(x.len()));
     }
 
     /// Write an array of u64 values from a slice
     pub fn array_of_u64_slice(&mut self, x: &[u64]) {
-        self.write_primitive_array(x.iter().cloned());
+        self.write_primitive_array(x.iter().cloned(), Some(x.len()));
     }
 
     /// Write an array of i64 values from a slice
     pub fn array_of_i64_slice(&mut self, x: &[i64]) {
-        self.write_primitive_array(x.iter().cloned());
+        self.write_primitive_array(x.iter().cloned(), Some(x.len()));
     }
 
     /// Write an array of f32 values from a slice
     pub fn array_of_f32_slice(&mut self, x: &[f32]) {
-        self.write_primitive_array(x.iter().cloned());
+        self.write_primitive_array(x.iter().cloned(), Some(x.len()));
     }
 
     /// Write an array of f64 values from a slice
     pub fn array_of_f64_slice(&mut self, x: &[f64]) {
-        self.write_primitive_array(x.iter().cloned());
+        self.write_primitive_array(x.iter().cloned(), Some(x.len()));
+    }
+
+    /// Write an array of u128 values from a slice
+    pub fn array_of_u128_slice(&mut self, x: &[u128]) {
+        self.write_primitive_array(x.iter().cloned(), Some(x.len()));
+    }
+
+    /// Write an array of i128 values from a slice
+    pub fn array_of_i128_slice(&mut self, x: &[i128]) {
+        self.write_primitive_array(x.iter().cloned(), Some(x.len()));
+    }
+
+    /// Write an array of char values from a slice
+    pub fn array_of_char_slice(&mut self, x: &[char]) {
+        self.write_primitive_array(x.iter().cloned(), Some(x.len()));
+    }
+
+    /// Write a fixed-size blob of bytes, such as a UUID or a digest.
+    /// This is stashed as an array of u8 values and is read back with
+    /// [crate::Unstasher::byte_array], which checks its length.
+    pub fn byte_array<const N: usize>(&mut self, x: &[u8; N]) {
+        self.array_of_u8_slice(x);
+    }
+
+    /// Write an array of f32 values from a slice, which are hashed in
+    /// their canonical form. See [Self::f32_canonical].
+    pub fn array_of_f32_slice_canonical(&mut self, x: &[f32]) {
+        self.write_float_array_canonical(x);
+    }
+
+    /// Write an array of f64 values from a slice, which are hashed in
+    /// their canonical form. See [Self::f32_canonical].
+    pub fn array_of_f64_slice_canonical(&mut self, x: &[f64]) {
+        self.write_float_array_canonical(x);
     }
 
     /// Write an array of u8 values from an iterator
     pub fn array_of_u8_iter<I: Iterator<Item = u8>>(&mut self, it: I) {
-        self.write_primitive_array(it);
+        self.write_primitive_array(it, None);
     }
 
     /// Write an array of i8 values from an iterator
     pub fn array_of_i8_iter<I: Iterator<Item = i8>>(&mut self, it: I) {
-        self.write_primitive_array(it);
+        self.write_primitive_array(it, None);
     }
 
     /// Write an array of u16 values from an iterator
     pub fn array_of_u16_iter<I: Iterator<Item = u16>>(&mut self, it: I) {
-        self.write_primitive_array(it);
+        self.write_primitive_array(it, None);
     }
 
     /// Write an array of i16 values from an iterator
     pub fn array_of_i16_iter<I: Iterator<Item = i16>>(&mut self, it: I) {
-        self.write_primitive_array(it);
+        self.write_primitive_array(it, None);
     }
 
     /// Write an array of u32 values from an iterator
     pub fn array_of_u32_iter<I: Iterator<Item = u32>>(&mut self, it: I) {
-        self.write_primitive_array(it);
+        self.write_primitive_array(it, None);
     }
 
     /// Write an array of i32 values from an iterator
     pub fn array_of_i32_iter<I: Iterator<Item = i32>>(&mut self, it: I) {
-        self.write_primitive_array(it);
+        self.write_primitive_array(it, None);
     }
 
     /// Write an array of u64 values from an iterator
     pub fn array_of_u64_iter<I: Iterator<Item = u64>>(&mut self, it: I) {
-        self.write_primitive_array(it);
+        self.write_primitive_array(it, None);
     }
 
     /// Write an array of i64 values from an iterator
     pub fn array_of_i64_iter<I: Iterator<Item = i64>>(&mut self, it: I) {
-        self.write_primitive_array(it);
+        self.write_primitive_array(it, None);
     }
 
     /// Write an array of f32 values from an iterator
     pub fn array_of_f32_iter<I: Iterator<Item = f32>>(&mut self, it: I) {
-        self.write_primitive_array(it);
+        self.write_primitive_array(it, None);
     }
 
     /// Write an array of f64 values from an iterator
     pub fn array_of_f64_iter<I: Iterator<Item = f64>>(&mut self, it: I) {
-        self.write_primitive_array(it);
+        self.write_primitive_array(it, None);
+    }
+
+    /// Write an array of u128 values from an iterator
+    pub fn array_of_u128_iter<I: Iterator<Item = u128>>(&mut self, it: I) {
+        self.write_primitive_array(it, None);
+    }
+
+    /// Write an array of i128 values from an iterator
+    pub fn array_of_i128_iter<I: Iterator<Item = i128>>(&mut self, it: I) {
+        self.write_primitive_array(it, None);
+    }
+
+    /// Write an array of char values from an iterator
+    pub fn array_of_char_iter<I: Iterator<Item = char>>(&mut self, it: I) {
+        self.write_primitive_array(it, None);
     }
 
     /// Write a single [Stashable] object
@@ -385,9 +734,8 @@ impl<'a, Context: Copy> Stasher<'a, Context> {
     }
 
     pub fn object_with_context<C1: Copy, T: Stashable<C1>>(&mut self, object: &T, context: C1) {
-        self.write_raw_bytes(&[ValueType::StashedObject.to_byte()]);
         self.backend
-            .stash_dependency(|stasher| object.stash(stasher), context);
+            .stash_object(|stasher| object.stash(stasher), context);
     }
 
     /// Write a single object via a function receiving a [Stasher].
@@ -402,13 +750,12 @@ impl<'a, Context: Copy> Stasher<'a, Context> {
     where
         F: FnMut(&mut Stasher<'_, OtherContext>),
     {
-        self.write_raw_bytes(&[ValueType::StashedObject.to_byte()]);
-        self.backend.stash_dependency(f, context);
+        self.backend.stash_object(f, context);
     }
 
     /// Write an array of [Stashable] objects from a slice
     pub fn array_of_objects_slice<T: Stashable<Context>>(&mut self, objects: &[T], order: Order) {
-        self.array_of_objects_iter_with_context(objects.iter(), order, self.context);
+        self.write_object_array(objects.iter(), Some(objects.len()), order, self.context);
     }
 
     pub fn array_of_objects_slice_with_context<C1: Copy, T: Stashable<C1>>(
@@ -417,7 +764,7 @@ impl<'a, Context: Copy> Stasher<'a, Context> {
         order: Order,
         context: C1,
     ) {
-        self.array_of_objects_iter_with_context(objects.iter(), order, context);
+        self.write_object_array(objects.iter(), Some(objects.len()), order, context);
     }
 
     /// Write an array of [Stashable] objects from an iterator
@@ -440,16 +787,7 @@ impl<'a, Context: Copy> Stasher<'a, Context> {
         order: Order,
         context: C1,
     ) {
-        self.backend
-            .write_raw_bytes(&[ValueType::ArrayOfObjects.to_byte()]);
-        let bookmark = self.backend.begin_sequence(order);
-        let mut length: u32 = 0;
-        for object in it {
-            self.backend
-                .stash_dependency(|stasher| object.stash(stasher), context);
-            length += 1;
-        }
-        self.backend.end_sequence(bookmark, length);
+        self.write_object_array(it, None, order, context);
     }
 
     /// Write an array of objects from an intermediate iterator and function
@@ -472,10 +810,10 @@ impl<'a, Context: Copy> Stasher<'a, Context> {
     {
         self.backend
             .write_raw_bytes(&[ValueType::ArrayOfObjects.to_byte()]);
-        let bookmark = self.backend.begin_sequence(order);
-        let mut length: u32 = 0;
+        let bookmark = self.backend.begin_sequence(order, None);
+        let mut length: usize = 0;
         for object in it {
-            self.backend.stash_dependency(
+            self.backend.stash_object(
                 |stasher: &mut Stasher<'_, OtherContext>| f(&object, stasher),
                 context,
             );
@@ -487,10 +825,12 @@ impl<'a, Context: Copy> Stasher<'a, Context> {
     /// Write a single string
     pub fn string(&mut self, x: &str) {
         self.backend.write_raw_bytes(&[ValueType::String.to_byte()]);
-        let bookmark = self.backend.begin_sequence(Order::Ordered);
         let bytes = x.as_bytes();
+        let bookmark = self
+            .backend
+            .begin_sequence(Order::Ordered, Some(bytes.len()));
         self.write_raw_bytes(bytes);
-        self.backend.end_sequence(bookmark, bytes.len() as u32);
+        self.backend.end_sequence(bookmark, bytes.len());
     }
 
     pub fn context(&self) -> Context {
@@ -0,0 +1,180 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::atomic,
+};
+
+use crate::{ObjectHash, StashMap};
+
+/// The number of objects listed in [StashStats::largest_objects]
+const NUM_LARGEST_OBJECTS: usize = 10;
+
+/// Statistics about the contents and memory use of a stash, as
+/// returned by [crate::Stash::stats]. All sizes are the sizes of
+/// serialized object contents in bytes, not including the fixed
+/// overhead of each stashed object.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct StashStats {
+    /// The number of distinct objects in the stash
+    pub num_objects: usize,
+
+    /// The total size of every object in the stash
+    pub total_bytes: usize,
+
+    /// The total number of references from objects to the objects
+    /// they depend on
+    pub num_dependencies: usize,
+
+    /// The number of objects by size, where the entry at index 0
+    /// counts empty objects and the entry at index i > 0 counts
+    /// objects of at least 2^(i-1) and less than 2^i bytes
+    pub size_histogram: Vec<usize>,
+
+    /// The number of objects having each reference count
+    pub reference_counts: BTreeMap<u64, usize>,
+
+    /// The total size that every object referred to from outside the
+    /// stash, such as by a handle, would take up together with all of
+    /// its dependencies if nothing was deduplicated
+    pub logical_bytes: usize,
+
+    /// The hashes and sizes of the largest objects, largest first
+    pub largest_objects: Vec<(ObjectHash, usize)>,
+}
+
+impl StashStats {
+    /// Get the ratio of logical bytes to the bytes actually stored,
+    /// which is 1 if nothing is deduplicated and greater otherwise.
+    /// An empty stash has a ratio of 1.
+    pub fn deduplication_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 1.0;
+        }
+        self.logical_bytes as f64 / self.total_bytes as f64
+    }
+}
+
+/// Collect statistics about every object in the stashmap
+pub(crate) fn stats(stashmap: &StashMap) -> StashStats {
+    let mut stats = StashStats {
+        num_objects: stashmap.objects.len(),
+        ..Default::default()
+    };
+
+    // The number of references to each object from other objects
+    let mut dependent_counts: HashMap<ObjectHash, u64> = HashMap::new();
+
+    for (hash, object) in &stashmap.objects {
+        let size = object.bytes.len();
+        stats.total_bytes += size;
+        stats.num_dependencies += object.dependencies.len();
+
+        let bucket = (usize::BITS - size.leading_zeros()) as usize;
+        if stats.size_histogram.len() <= bucket {
+            stats.size_histogram.resize(bucket + 1, 0);
+        }
+        stats.size_histogram[bucket] += 1;
+
+        let refcount = object.reference_count.load(atomic::Ordering::Relaxed);
+        *stats.reference_counts.entry(refcount).or_insert(0) += 1;
+
+        stats.largest_objects.push((*hash, size));
+
+        for dependency in &object.dependencies {
+            *dependent_counts.entry(*dependency).or_insert(0) += 1;
+        }
+    }
+
+    stats
+        .largest_objects
+        .sort_by(|(hash_a, size_a), (hash_b, size_b)| {
+            size_b.cmp(size_a).then(hash_a.0.cmp(&hash_b.0))
+        });
+    stats.largest_objects.truncate(NUM_LARGEST_OBJECTS);
+
+    let mut logical_sizes = HashMap::new();
+    for (hash, object) in &stashmap.objects {
+        let refcount = object.reference_count.load(atomic::Ordering::Relaxed);
+        let outside_refcount = refcount - dependent_counts.get(hash).copied().unwrap_or(0);
+        if outside_refcount > 0 {
+            let size = logical_size(stashmap, *hash, &mut logical_sizes);
+            stats.logical_bytes = stats
+                .logical_bytes
+                .saturating_add(size.saturating_mul(outside_refcount as usize));
+        }
+    }
+
+    stats
+}
+
+/// Get the size of an object plus the logical sizes of all of its
+/// dependencies, counting each dependency as often as it is referred to.
+/// Dependencies are visited in post-order using an explicit stack so
+/// that arbitrarily deep object graphs don't overflow the call stack.
+fn logical_size(
+    stashmap: &StashMap,
+    hash: ObjectHash,
+    logical_sizes: &mut HashMap<ObjectHash, usize>,
+) -> usize {
+    // Each entry is an object and whether its dependencies' sizes
+    // have already been computed
+    let mut stack = vec![(hash, false)];
+    while let Some((hash, dependencies_done)) = stack.pop() {
+        if logical_sizes.contains_key(&hash) {
+            continue;
+        }
+        let object = stashmap.objects.get(&hash).unwrap();
+        if dependencies_done {
+            let size = object
+                .dependencies
+                .iter()
+                .fold(object.bytes.len(), |size, dependency| {
+                    size.saturating_add(logical_sizes[dependency])
+                });
+            logical_sizes.insert(hash, size);
+        } else {
+            stack.push((hash, true));
+            for dependency in &object.dependencies {
+                if !logical_sizes.contains_key(dependency) {
+                    stack.push((*dependency, false));
+                }
+            }
+        }
+    }
+    logical_sizes[&hash]
+}
+
+/// Get the total size of the objects which would be removed from the
+/// stashmap if a single reference to the object with the given hash
+/// were removed.
+/// This function panics if no object with the given hash exists.
+pub(crate) fn retained_size(stashmap: &StashMap, hash: ObjectHash) -> usize {
+    let mut removed_references: HashMap<ObjectHash, u64> = HashMap::new();
+
+    // Add one removed reference to the given object, and return true
+    // if that accounts for every reference to it
+    let mut remove_reference = |hash: ObjectHash| -> bool {
+        let object = stashmap.objects.get(&hash).unwrap();
+        let removed = removed_references.entry(hash).or_insert(0);
+        *removed += 1;
+        *removed >= object.reference_count.load(atomic::Ordering::Relaxed)
+    };
+
+    // Objects which would be removed but whose dependencies haven't
+    // been visited yet
+    let mut worklist = Vec::new();
+    if remove_reference(hash) {
+        worklist.push(hash);
+    }
+
+    let mut size = 0;
+    while let Some(hash) = worklist.pop() {
+        let object = stashmap.objects.get(&hash).unwrap();
+        size += object.bytes.len();
+        for dependency in &object.dependencies {
+            if remove_reference(*dependency) {
+                worklist.push(*dependency);
+            }
+        }
+    }
+    size
+}
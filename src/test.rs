@@ -1,10 +1,16 @@
 use rand::prelude::*;
 
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
 
 use crate::{
-    test_stash_roundtrip, test_stash_roundtrip_inplace, InplaceUnstasher, Order, Stash, Stashable,
-    Stasher, UnstashError, Unstashable, UnstashableInplace, Unstasher,
+    test_stash_roundtrip, test_stash_roundtrip_inplace, CommitGraph, CommitGraphError, Difference,
+    EvictableHandle, EvictionPolicy, HashCollision, History, InplaceUnstasher, Lazy, Migrate,
+    ObjectHash, ObjectValue, Order, PathSegment, PrimitiveType, PrimitiveValue, SnapshotError,
+    Stash, StashHandle, StashPack, Stashable, Stasher, UnstashError, UnstashErrorKind,
+    UnstashPathEntry, Unstashable, UnstashableInplace, Unstasher, Value, ValueType,
 };
 
 #[derive(Clone, Eq, PartialEq, Debug, Hash)]
@@ -210,12 +216,12 @@ fn test_basic_struct_proxy() {
 
     let handle = stash.stash(&s1);
 
-    // 1 StructAProxy and 1 StructA
-    assert_eq!(stash.num_objects(), 2);
+    // 1 StructAProxy, with the small StructA stored inline
+    assert_eq!(stash.num_objects(), 1);
 
     let s2 = stash.unstash(&handle).unwrap();
 
-    assert_eq!(stash.num_objects(), 2);
+    assert_eq!(stash.num_objects(), 1);
 
     assert_eq!(s2.0.i, 123);
     assert_eq!(s2.0.x, 0x0123456789abcdef);
@@ -223,7 +229,7 @@ fn test_basic_struct_proxy() {
 
     let s3 = stash.unstash(&handle).unwrap();
 
-    assert_eq!(stash.num_objects(), 2);
+    assert_eq!(stash.num_objects(), 1);
 
     assert_eq!(s3.0.i, 123);
     assert_eq!(s3.0.x, 0x0123456789abcdef);
@@ -305,8 +311,8 @@ fn test_one_level_nested_struct() {
 
     let handle1 = stash.stash(&b1);
 
-    // one B and three A's
-    assert_eq!(stash.num_objects(), 4);
+    // one B, with the three small A's stored inline
+    assert_eq!(stash.num_objects(), 1);
     assert_eq!(handle1.reference_count(), 1);
 
     let b2 = b1.clone();
@@ -314,7 +320,7 @@ fn test_one_level_nested_struct() {
     let handle2 = stash.stash(&b2);
 
     // same
-    assert_eq!(stash.num_objects(), 4);
+    assert_eq!(stash.num_objects(), 1);
 
     assert_eq!(handle1.reference_count(), 2);
     assert_eq!(handle2.reference_count(), 2);
@@ -332,18 +338,18 @@ fn test_one_level_nested_struct() {
     assert_eq!(handle2.reference_count(), 2);
     assert_eq!(handle3.reference_count(), 1);
 
-    // one new B and one new A, copied three times
-    assert_eq!(stash.num_objects(), 6);
+    // one new B, with its A's inline as well
+    assert_eq!(stash.num_objects(), 2);
 
     std::mem::drop(handle2);
 
-    assert_eq!(stash.num_objects(), 6);
+    assert_eq!(stash.num_objects(), 2);
     assert_eq!(handle1.reference_count(), 1);
     assert_eq!(handle3.reference_count(), 1);
 
     std::mem::drop(handle1);
 
-    assert_eq!(stash.num_objects(), 2);
+    assert_eq!(stash.num_objects(), 1);
     assert_eq!(handle3.reference_count(), 1);
 
     let unstashed3 = stash.unstash(&handle3).unwrap();
@@ -359,6 +365,91 @@ fn test_one_level_nested_struct() {
     assert_eq!(stash.num_objects(), 0);
 }
 
+#[test]
+fn test_small_objects_inline() {
+    let stash = Stash::new();
+
+    let mut b = make_large_struct_b(1);
+    b.a1.s = "a".to_string();
+    b.a2.s = "b".to_string();
+
+    let handle = stash.stash(&b);
+    assert_eq!(handle.object_hash(), ObjectHash::from_stashable(&b));
+
+    // a1 and a2 are small enough to be stored inline, but a3 is not
+    assert_eq!(stash.num_objects(), 2);
+
+    assert_eq!(stash.unstash(&handle).unwrap(), b);
+    let mut b2 = make_large_struct_b(0);
+    stash.unstash_inplace(&handle, &mut b2).unwrap();
+    assert_eq!(b2, b);
+
+    // Inline objects are inspected like any other object
+    let Ok(Value::Object(object)) = stash.inspect(&handle) else {
+        panic!("expected an object");
+    };
+    let Value::Object(a1) = &object.values[0] else {
+        panic!("expected an object");
+    };
+    assert_eq!(a1.hash, ObjectHash::from_stashable(&b.a1));
+
+    std::mem::drop(handle);
+    assert_eq!(stash.num_objects(), 0);
+
+    // Small elements of arrays of objects are stored inline as well
+    let small = |i: i32| StructA {
+        i,
+        x: 0,
+        s: "small".to_string(),
+    };
+    let mut s1 = StructWithVecOfObjects {
+        objects: vec![small(1), b.a3.clone(), small(2), small(1)],
+    };
+    let handle1 = stash.stash(&s1);
+    assert_eq!(stash.num_objects(), 2);
+    assert_eq!(stash.unstash(&handle1).unwrap(), s1);
+    let mut s2 = StructWithVecOfObjects { objects: vec![] };
+    stash.unstash_inplace(&handle1, &mut s2).unwrap();
+    assert_eq!(s2, s1);
+
+    let Ok(Value::Object(object)) = stash.inspect(&handle1) else {
+        panic!("expected an object");
+    };
+    let Value::ArrayOfObjects(elements) = &object.values[0] else {
+        panic!("expected an array of objects");
+    };
+    let hashes: Vec<ObjectHash> = elements.iter().map(|element| element.hash).collect();
+    let expected: Vec<ObjectHash> = s1.objects.iter().map(ObjectHash::from_stashable).collect();
+    assert_eq!(hashes, expected);
+
+    s1.objects[2].i = 3;
+    let handle2 = stash.stash(&s1);
+    assert_eq!(stash.num_objects(), 3);
+    assert_eq!(
+        stash.diff(&handle1, &handle2).unwrap(),
+        vec![
+            Difference::Object {
+                path: vec![PathSegment::Value(0), PathSegment::Element(2)],
+                old: Some(ObjectHash::from_stashable(&small(2))),
+                new: Some(ObjectHash::from_stashable(&s1.objects[2])),
+            },
+            Difference::Primitive {
+                path: vec![
+                    PathSegment::Value(0),
+                    PathSegment::Element(2),
+                    PathSegment::Value(0)
+                ],
+                old: Some(PrimitiveValue::I32(2)),
+                new: Some(PrimitiveValue::I32(3)),
+            }
+        ]
+    );
+
+    std::mem::drop(handle1);
+    std::mem::drop(handle2);
+    assert_eq!(stash.num_objects(), 0);
+}
+
 #[test]
 fn test_roundtrip_nested() {
     let create_a = || StructA {
@@ -630,7 +721,8 @@ fn test_vec_of_objects() {
 
     let handle_s = stash.stash(&s1);
 
-    assert_eq!(stash.num_objects(), 4);
+    // The small A's are stored inline within the vector
+    assert_eq!(stash.num_objects(), 1);
 
     let s2 = stash.unstash(&handle_s).unwrap();
 
@@ -642,9 +734,9 @@ fn test_vec_of_objects() {
 
     assert_eq!(stash.num_objects(), 4);
 
-    assert_eq!(handle_a1.reference_count(), 2);
-    assert_eq!(handle_a2.reference_count(), 3);
-    assert_eq!(handle_a3.reference_count(), 4);
+    assert_eq!(handle_a1.reference_count(), 1);
+    assert_eq!(handle_a2.reference_count(), 1);
+    assert_eq!(handle_a3.reference_count(), 1);
 
     std::mem::drop(handle_s);
 
@@ -1304,3 +1396,2002 @@ fn test_option() {
 
     assert_eq!(a, some_234);
 }
+
+#[test]
+fn test_roundtrip_std_collections() {
+    let create_vec = || vec!["a".to_string(), "b".to_string(), "c".to_string()];
+    let modify_vec_1 = |v: &mut Vec<String>| v.push("d".to_string());
+    let modify_vec_2 = |v: &mut Vec<String>| v[1].push('!');
+    let modify_vec_3 = |v: &mut Vec<String>| v.truncate(1);
+    let modify_vec_4 = |v: &mut Vec<String>| v.reverse();
+    for modify in [modify_vec_1, modify_vec_2, modify_vec_3, modify_vec_4] {
+        assert_eq!(test_stash_roundtrip(create_vec, modify, (), ()), Ok(()));
+        assert_eq!(
+            test_stash_roundtrip_inplace(create_vec, modify, (), ()),
+            Ok(())
+        );
+    }
+
+    let create_deque = || VecDeque::from([1_u8, 2, 3]);
+    let modify_deque = |v: &mut VecDeque<u8>| {
+        v.pop_front();
+        v.push_back(4);
+        v.push_back(5);
+    };
+    assert_eq!(
+        test_stash_roundtrip(create_deque, modify_deque, (), ()),
+        Ok(())
+    );
+    assert_eq!(
+        test_stash_roundtrip_inplace(create_deque, modify_deque, (), ()),
+        Ok(())
+    );
+
+    let create_hashmap = || {
+        (0..10)
+            .map(|i| (format!("key{}", i), vec![i; i as usize]))
+            .collect::<HashMap<String, Vec<i32>>>()
+    };
+    let modify_hashmap_1 = |m: &mut HashMap<String, Vec<i32>>| {
+        m.remove("key3");
+    };
+    let modify_hashmap_2 = |m: &mut HashMap<String, Vec<i32>>| {
+        m.get_mut("key5").unwrap().push(-1);
+        m.insert("key10".to_string(), Vec::new());
+    };
+    for modify in [modify_hashmap_1, modify_hashmap_2] {
+        assert_eq!(test_stash_roundtrip(create_hashmap, modify, (), ()), Ok(()));
+        assert_eq!(
+            test_stash_roundtrip_inplace(create_hashmap, modify, (), ()),
+            Ok(())
+        );
+    }
+
+    let create_btreemap = || {
+        (0..10)
+            .map(|i| (i, format!("value{}", i)))
+            .collect::<BTreeMap<u64, String>>()
+    };
+    let modify_btreemap = |m: &mut BTreeMap<u64, String>| {
+        m.remove(&4);
+        m.get_mut(&7).unwrap().push('?');
+    };
+    assert_eq!(
+        test_stash_roundtrip(create_btreemap, modify_btreemap, (), ()),
+        Ok(())
+    );
+    assert_eq!(
+        test_stash_roundtrip_inplace(create_btreemap, modify_btreemap, (), ()),
+        Ok(())
+    );
+
+    let create_hashset = || (0..20).collect::<HashSet<i64>>();
+    let modify_hashset = |s: &mut HashSet<i64>| {
+        s.remove(&3);
+        s.insert(100);
+    };
+    assert_eq!(
+        test_stash_roundtrip(create_hashset, modify_hashset, (), ()),
+        Ok(())
+    );
+    assert_eq!(
+        test_stash_roundtrip_inplace(create_hashset, modify_hashset, (), ()),
+        Ok(())
+    );
+
+    let create_btreeset = || (0..20).map(|i| i * 3).collect::<BTreeSet<u16>>();
+    let modify_btreeset = |s: &mut BTreeSet<u16>| {
+        s.insert(1);
+    };
+    assert_eq!(
+        test_stash_roundtrip(create_btreeset, modify_btreeset, (), ()),
+        Ok(())
+    );
+    assert_eq!(
+        test_stash_roundtrip_inplace(create_btreeset, modify_btreeset, (), ()),
+        Ok(())
+    );
+
+    let create_misc = || (Box::new(1.5_f64), [true, false, true], ());
+    let modify_misc = |t: &mut (Box<f64>, [bool; 3], ())| t.1[2] = false;
+    assert_eq!(
+        test_stash_roundtrip(create_misc, modify_misc, (), ()),
+        Ok(())
+    );
+    assert_eq!(
+        test_stash_roundtrip_inplace(create_misc, modify_misc, (), ()),
+        Ok(())
+    );
+}
+
+#[test]
+fn test_std_collections_inplace_reuse() {
+    let stash = Stash::new();
+
+    let original: Vec<Vec<i32>> = vec![vec![1, 2, 3], vec![4, 5, 6]];
+    let handle = stash.stash(&original);
+
+    // Elements are unstashed in place rather than replaced
+    let mut v: Vec<Vec<i32>> = vec![vec![7, 8, 9], vec![0], vec![1, 1]];
+    let first_ptr = v[0].as_ptr();
+    stash.unstash_inplace(&handle, &mut v).unwrap();
+    assert_eq!(v, original);
+    assert_eq!(v[0].as_ptr(), first_ptr);
+
+    let original: HashMap<String, Vec<i32>> =
+        [("a".to_string(), vec![1, 2]), ("b".to_string(), vec![3, 4])]
+            .into_iter()
+            .collect();
+    let handle = stash.stash(&original);
+
+    // Values are unstashed in place by key
+    let mut m: HashMap<String, Vec<i32>> =
+        [("a".to_string(), vec![0, 0]), ("c".to_string(), vec![5])]
+            .into_iter()
+            .collect();
+    let a_ptr = m["a"].as_ptr();
+    stash.unstash_inplace(&handle, &mut m).unwrap();
+    assert_eq!(m, original);
+    assert_eq!(m["a"].as_ptr(), a_ptr);
+}
+
+/// Stashes a Vec but unstashes it as an array of length 2
+struct VecAsArray(Vec<u32>);
+
+impl Stashable for VecAsArray {
+    fn stash(&self, stasher: &mut Stasher) {
+        stasher.object(&self.0);
+    }
+}
+
+impl Unstashable for VecAsArray {
+    fn unstash(unstasher: &mut Unstasher) -> Result<Self, UnstashError> {
+        let array: [u32; 2] = unstasher.object()?;
+        Ok(VecAsArray(array.to_vec()))
+    }
+}
+
+impl UnstashableInplace for VecAsArray {
+    fn unstash_inplace(&mut self, unstasher: &mut InplaceUnstasher) -> Result<(), UnstashError> {
+        let mut array = [0_u32; 2];
+        unstasher.object_inplace(&mut array)?;
+        if unstasher.time_to_write() {
+            self.0 = array.to_vec();
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_array_length_mismatch() {
+    let stash = Stash::new();
+
+    let handle_ok = stash.stash(&VecAsArray(vec![1, 2]));
+    assert_eq!(stash.unstash(&handle_ok).unwrap().0, vec![1, 2]);
+
+    for items in [vec![1], vec![1, 2, 3]] {
+        let handle = stash.stash(&VecAsArray(items));
+        assert_eq!(
+            stash.unstash(&handle).err().map(|e| e.kind()),
+            Some(UnstashErrorKind::BadValue)
+        );
+        let mut v = VecAsArray(vec![5, 6]);
+        assert_eq!(
+            stash.unstash_inplace(&handle, &mut v).map_err(|e| e.kind()),
+            Err(UnstashErrorKind::BadValue)
+        );
+        assert_eq!(v.0, vec![5, 6]);
+    }
+}
+
+#[test]
+fn test_stash_across_threads() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Stash>();
+    assert_send_sync::<StashHandle<StructB>>();
+    assert_send_sync::<StashPack>();
+
+    let stash = Stash::new();
+
+    // Stash on worker threads and restore on this one
+    let stash_ref = &stash;
+    let handles: Vec<StashHandle<StructB>> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..4)
+            .map(|i| scope.spawn(move || stash_ref.stash(&make_struct_b(i))))
+            .collect();
+        workers.into_iter().map(|w| w.join().unwrap()).collect()
+    });
+
+    for (i, handle) in handles.iter().enumerate() {
+        assert_eq!(stash.unstash(handle).unwrap(), make_struct_b(i as i32));
+    }
+
+    // Unstash the same objects concurrently while cloning and dropping handles
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for (i, handle) in handles.iter().enumerate() {
+                    let handle = handle.clone();
+                    let mut b = make_struct_b(-1);
+                    stash.unstash_inplace(&handle, &mut b).unwrap();
+                    assert_eq!(b, make_struct_b(i as i32));
+                }
+            });
+        }
+    });
+
+    for handle in &handles {
+        assert_eq!(handle.reference_count(), 1);
+    }
+    std::mem::drop(handles);
+    assert_eq!(stash.num_objects(), 0);
+}
+
+#[test]
+fn test_many_references_to_same_object() {
+    const COUNT: usize = 100_000;
+
+    let stash = Stash::new();
+    // The string is long enough to keep the leaf from being stored inline
+    let leaf = StructA {
+        i: 0,
+        x: 0,
+        s: "a".repeat(64),
+    };
+
+    let handles: Vec<StashHandle<StructA>> = (0..COUNT).map(|_| stash.stash(&leaf)).collect();
+    assert_eq!(stash.num_objects(), 1);
+    assert_eq!(handles[0].reference_count(), COUNT as u64);
+
+    // The same leaf is also referenced by many parents
+    let parent = vec![leaf.clone(); COUNT];
+    let handle_parent = stash.stash(&parent);
+    assert_eq!(stash.num_objects(), 2);
+    assert_eq!(handles[0].reference_count(), 2 * COUNT as u64);
+    assert_eq!(stash.unstash(&handle_parent).unwrap(), parent);
+
+    std::mem::drop(handles);
+    assert_eq!(stash.num_objects(), 2);
+    std::mem::drop(handle_parent);
+    assert_eq!(stash.num_objects(), 0);
+}
+
+#[test]
+fn test_history_undo_redo() {
+    let mut history = History::new();
+    let mut b = make_large_struct_b(0);
+
+    assert!(history.push(&b));
+    assert!(!history.can_undo());
+    assert!(!history.undo(&mut b).unwrap());
+
+    // Pushing an unchanged object does nothing
+    assert!(!history.push(&b));
+    assert_eq!(history.stash().num_objects(), 3);
+
+    b.a1.i = 1;
+    assert!(history.push(&b));
+    b.a1.i = 2;
+    assert!(history.push(&b));
+
+    assert!(history.undo(&mut b).unwrap());
+    assert_eq!(b, make_large_struct_b(1));
+    assert!(history.undo(&mut b).unwrap());
+    assert_eq!(b, make_large_struct_b(0));
+    assert!(!history.undo(&mut b).unwrap());
+    assert_eq!(b, make_large_struct_b(0));
+
+    assert!(history.redo(&mut b).unwrap());
+    assert_eq!(b, make_large_struct_b(1));
+
+    // Pushing a new state discards the redo stack
+    b.a1.i = 3;
+    assert!(history.push(&b));
+    assert!(!history.can_redo());
+    assert!(!history.redo(&mut b).unwrap());
+    assert_eq!(b, make_large_struct_b(3));
+
+    assert!(history.undo(&mut b).unwrap());
+    assert_eq!(b, make_large_struct_b(1));
+}
+
+#[test]
+fn test_history_max_depth() {
+    let mut history = History::with_max_depth(3);
+    let mut b = make_large_struct_b(0);
+
+    for i in 0..10 {
+        b.a1.i = i;
+        assert!(history.push(&b));
+    }
+
+    // Only the objects of the last three states remain, which
+    // share the objects for a2 and a3
+    assert_eq!(history.stash().num_objects(), 3 * 2 + 1);
+
+    assert!(history.undo(&mut b).unwrap());
+    assert!(history.undo(&mut b).unwrap());
+    assert_eq!(b, make_large_struct_b(7));
+    assert!(!history.undo(&mut b).unwrap());
+
+    history.clear();
+    assert_eq!(history.stash().num_objects(), 0);
+}
+
+#[test]
+fn test_commit_graph_branches() {
+    let mut graph = CommitGraph::new();
+    let mut b = make_struct_b(0);
+    assert_eq!(graph.head(), None);
+    assert_eq!(graph.current_branch(), Some("main"));
+
+    let c0 = graph.commit(&b, "first");
+    b.a1.i = 1;
+    let c1 = graph.commit(&b, "second");
+    assert_eq!(graph.branch("main"), Some(c1));
+
+    let commit = graph.get(c1).unwrap();
+    assert_eq!(commit.message(), "second");
+    assert_eq!(commit.parents(), &[c0]);
+    assert_eq!(commit.root_hash(), ObjectHash::from_stashable(&b));
+
+    // Diverge on a second branch
+    graph.create_branch("feature", c0).unwrap();
+    assert_eq!(
+        graph.create_branch("feature", c1),
+        Err(CommitGraphError::NameInUse)
+    );
+    graph.checkout_branch("feature", &mut b).unwrap();
+    assert_eq!(b, make_struct_b(0));
+    b.a1.i = 2;
+    let c2 = graph.commit(&b, "third");
+    assert_eq!(graph.branch("feature"), Some(c2));
+    assert_eq!(graph.branch("main"), Some(c1));
+
+    graph.create_tag("v1", c1).unwrap();
+    assert_eq!(graph.resolve("v1"), Some(c1));
+    assert_eq!(graph.resolve("v2"), None);
+
+    let log: Vec<_> = graph.log(c2).unwrap().iter().map(|c| c.id()).collect();
+    assert_eq!(log, vec![c2, c0]);
+
+    graph.checkout_branch("main", &mut b).unwrap();
+    assert_eq!(b, make_struct_b(1));
+    assert_eq!(graph.unstash(c2).unwrap(), make_struct_b(2));
+
+    // Detached commits only move the head
+    graph.checkout(c0, &mut b).unwrap();
+    assert_eq!(graph.current_branch(), None);
+    b.a1.i = 3;
+    let c3 = graph.commit(&b, "detached");
+    assert_eq!(graph.head(), Some(c3));
+    assert_eq!(graph.branch("main"), Some(c1));
+
+    // Commits that are no longer reachable are removed
+    assert_eq!(
+        graph.delete_branch("nonexistent"),
+        Err(CommitGraphError::UnknownName)
+    );
+    graph.checkout_branch("main", &mut b).unwrap();
+    let num_objects = graph.stash().num_objects();
+    graph.delete_branch("feature").unwrap();
+    assert!(graph.stash().num_objects() < num_objects);
+    assert_eq!(graph.get(c2), Err(CommitGraphError::UnknownCommit));
+    assert_eq!(graph.get(c3), Err(CommitGraphError::UnknownCommit));
+    assert_eq!(
+        graph.delete_branch("main"),
+        Err(CommitGraphError::CurrentBranch)
+    );
+    assert_eq!(
+        CommitGraphError::CurrentBranch.to_string(),
+        "can't delete the current branch"
+    );
+}
+
+#[test]
+fn test_commit_graph_merge_base() {
+    let mut graph = CommitGraph::new();
+    let mut b = make_struct_b(0);
+
+    let c0 = graph.commit(&b, "base");
+    b.a1.i = 1;
+    let c1 = graph.commit(&b, "main 1");
+    graph.create_branch("other", c1).unwrap();
+    b.a1.i = 2;
+    let c2 = graph.commit(&b, "main 2");
+
+    graph.checkout_branch("other", &mut b).unwrap();
+    b.a1.i = 3;
+    let c3 = graph.commit(&b, "other 1");
+
+    assert_eq!(graph.merge_base(c2, c3), Ok(Some(c1)));
+    assert_eq!(graph.merge_base(c3, c0), Ok(Some(c0)));
+    assert_eq!(graph.merge_base(c2, c2), Ok(Some(c2)));
+
+    // Merging makes the merged commit the new merge base
+    graph.checkout_branch("main", &mut b).unwrap();
+    b.a1.i = 4;
+    let c4 = graph.commit_merge(&b, "merge", &[c3]).unwrap();
+    assert_eq!(graph.get(c4).unwrap().parents(), &[c2, c3]);
+    assert_eq!(graph.merge_base(c4, c3), Ok(Some(c3)));
+    assert_eq!(graph.log(c4).unwrap().len(), 5);
+
+    // Commits from a separate history don't share a base
+    graph.checkout(c0, &mut b).unwrap();
+    let mut unrelated = CommitGraph::new();
+    let c5 = unrelated.commit(&make_struct_b(5), "unrelated");
+    assert_eq!(
+        graph.commit_merge(&b, "bad", &[c5]),
+        Err(CommitGraphError::UnknownCommit)
+    );
+}
+
+#[test]
+fn test_diff() {
+    let stash = Stash::new();
+
+    let b0 = make_struct_b(0);
+    let mut b1 = b0.clone();
+    b1.a1.i = 1;
+    b1.a1.s = "z".to_string();
+    b1.u = 12;
+
+    let h0 = stash.stash(&b0);
+    let h1 = stash.stash(&b1);
+
+    assert_eq!(stash.diff(&h0, &h0.clone()), Ok(Vec::new()));
+
+    // Unchanged objects a2 and a3 are skipped
+    let a1_old = ObjectHash::from_stashable(&b0.a1);
+    let a1_new = ObjectHash::from_stashable(&b1.a1);
+    assert_eq!(
+        stash.diff(&h0, &h1),
+        Ok(vec![
+            Difference::Object {
+                path: vec![PathSegment::Value(0)],
+                old: Some(a1_old),
+                new: Some(a1_new),
+            },
+            Difference::Primitive {
+                path: vec![PathSegment::Value(0), PathSegment::Value(0)],
+                old: Some(PrimitiveValue::I32(0)),
+                new: Some(PrimitiveValue::I32(1)),
+            },
+            Difference::String {
+                path: vec![PathSegment::Value(0), PathSegment::Value(2)],
+                old: Some("a".to_string()),
+                new: Some("z".to_string()),
+            },
+            Difference::Primitive {
+                path: vec![PathSegment::Value(3)],
+                old: Some(PrimitiveValue::U8(11)),
+                new: Some(PrimitiveValue::U8(12)),
+            },
+        ])
+    );
+
+    // Added and removed elements of arrays
+    let v0 = stash.stash(&vec![make_struct_b(0), make_struct_b(1)]);
+    let v1 = stash.stash(&vec![make_struct_b(0)]);
+    assert_eq!(
+        stash.diff(&v0, &v1),
+        Ok(vec![Difference::Object {
+            path: vec![PathSegment::Value(0), PathSegment::Element(1)],
+            old: Some(ObjectHash::from_stashable(&make_struct_b(1))),
+            new: None,
+        }])
+    );
+
+    let p0 = stash.stash(&vec![1.0_f32, 2.0]);
+    let p1 = stash.stash(&vec![1.0_f32, 3.0, 4.0]);
+    let differences = stash.diff(&p0, &p1).unwrap();
+    assert_eq!(differences.len(), 3);
+    assert_eq!(
+        differences[1],
+        Difference::Primitive {
+            path: vec![
+                PathSegment::Value(0),
+                PathSegment::Element(1),
+                PathSegment::Value(0)
+            ],
+            old: Some(PrimitiveValue::F32(2.0)),
+            new: Some(PrimitiveValue::F32(3.0)),
+        }
+    );
+    assert_eq!(
+        differences[2],
+        Difference::Object {
+            path: vec![PathSegment::Value(0), PathSegment::Element(2)],
+            old: None,
+            new: Some(ObjectHash::from_stashable(&4.0_f32)),
+        }
+    );
+}
+
+fn make_struct_b(i: i32) -> StructB {
+    StructB {
+        a1: StructA {
+            i,
+            x: 0x0123456789abcdef,
+            s: "a".to_string(),
+        },
+        b: true,
+        a2: StructA {
+            i: 2,
+            x: 0x0123456789abcdef,
+            s: "b".to_string(),
+        },
+        u: 11,
+        a3: StructA {
+            i: 2,
+            x: 0x0123456789abcdef,
+            s: "b".to_string(),
+        },
+    }
+}
+
+/// Like [make_struct_b], but with strings long enough to keep the
+/// A's from being stored inline
+fn make_large_struct_b(i: i32) -> StructB {
+    let mut b = make_struct_b(i);
+    b.a1.s = "a".repeat(64);
+    b.a2.s = "b".repeat(64);
+    b.a3.s = "b".repeat(64);
+    b
+}
+
+#[test]
+fn test_snapshot_roundtrip() {
+    let b = make_large_struct_b(1);
+
+    let stash1 = Stash::new();
+    let handle1 = stash1.stash(&b);
+
+    // one B and two distinct A's
+    assert_eq!(stash1.num_objects(), 3);
+
+    let mut data = Vec::<u8>::new();
+    stash1.export_snapshot(&handle1, &mut data).unwrap();
+
+    let stash2 = Stash::new();
+    let handle2: StashHandle<StructB> = stash2.import_snapshot(data.as_slice()).unwrap();
+
+    assert_eq!(stash2.num_objects(), 3);
+    assert_eq!(handle2.object_hash(), handle1.object_hash());
+    assert_eq!(handle2.reference_count(), 1);
+    assert_eq!(stash2.unstash(&handle2).unwrap(), b);
+
+    std::mem::drop(handle2);
+
+    assert_eq!(stash2.num_objects(), 0);
+}
+
+#[test]
+fn test_snapshot_import_deduplicates() {
+    let b1 = make_large_struct_b(1);
+    let b2 = make_large_struct_b(2);
+
+    let stash1 = Stash::new();
+    let handle1 = stash1.stash(&b1);
+    let mut data = Vec::<u8>::new();
+    stash1.export_snapshot(&handle1, &mut data).unwrap();
+
+    let stash2 = Stash::new();
+    let handle_b2 = stash2.stash(&b2);
+    let handle_a2 = stash2.stash(&b2.a2);
+
+    // one B, two distinct A's
+    assert_eq!(stash2.num_objects(), 3);
+    assert_eq!(handle_a2.reference_count(), 3);
+
+    let handle_b1: StashHandle<StructB> = stash2.import_snapshot(data.as_slice()).unwrap();
+
+    // only one new B and one new A
+    assert_eq!(stash2.num_objects(), 5);
+    assert_eq!(handle_a2.reference_count(), 5);
+
+    // importing the same snapshot again only adds a reference
+    let handle_b1_again: StashHandle<StructB> = stash2.import_snapshot(data.as_slice()).unwrap();
+    assert_eq!(stash2.num_objects(), 5);
+    assert_eq!(handle_b1.reference_count(), 2);
+    assert_eq!(handle_a2.reference_count(), 5);
+
+    assert_eq!(stash2.unstash(&handle_b1).unwrap(), b1);
+    assert_eq!(stash2.unstash(&handle_b2).unwrap(), b2);
+
+    std::mem::drop(handle_b1);
+    std::mem::drop(handle_b1_again);
+
+    assert_eq!(stash2.num_objects(), 3);
+    assert_eq!(handle_a2.reference_count(), 3);
+
+    std::mem::drop(handle_b2);
+    std::mem::drop(handle_a2);
+
+    assert_eq!(stash2.num_objects(), 0);
+}
+
+#[test]
+fn test_snapshot_bad_data() {
+    let stash1 = Stash::new();
+    let handle = stash1.stash(&make_struct_b(1));
+    let mut data = Vec::<u8>::new();
+    stash1.export_snapshot(&handle, &mut data).unwrap();
+
+    let stash2 = Stash::new();
+
+    let mut not_a_snapshot = data.clone();
+    not_a_snapshot[0] = b'X';
+    assert!(matches!(
+        stash2.import_snapshot::<StructB, _>(not_a_snapshot.as_slice()),
+        Err(SnapshotError::NotASnapshot)
+    ));
+
+    let mut wrong_version = data.clone();
+    wrong_version[4..8].copy_from_slice(&99_u32.to_be_bytes());
+    assert!(matches!(
+        stash2.import_snapshot::<StructB, _>(wrong_version.as_slice()),
+        Err(SnapshotError::UnsupportedVersion(99))
+    ));
+
+    let truncated = &data[..data.len() - 1];
+    assert!(matches!(
+        stash2.import_snapshot::<StructB, _>(truncated),
+        Err(SnapshotError::Io(_))
+    ));
+    let Err(err) = stash2.import_snapshot::<StructB, _>(truncated) else {
+        panic!("expected an error");
+    };
+    assert!(err.to_string().starts_with("i/o error: "));
+    assert!(std::error::Error::source(&err).is_some());
+    assert_eq!(
+        SnapshotError::UnsupportedVersion(99).to_string(),
+        "unsupported format version 0x63"
+    );
+
+    // Remove the last object from the snapshot
+    let mut missing_object = data.clone();
+    let count_offset = 4 + 4 + 8;
+    let count = u64::from_be_bytes(
+        missing_object[count_offset..(count_offset + 8)]
+            .try_into()
+            .unwrap(),
+    );
+    missing_object[count_offset..(count_offset + 8)].copy_from_slice(&(count - 1).to_be_bytes());
+    assert!(matches!(
+        stash2.import_snapshot::<StructB, _>(missing_object.as_slice()),
+        Err(SnapshotError::Corrupted)
+    ));
+
+    assert_eq!(stash2.num_objects(), 0);
+}
+
+#[test]
+fn test_snapshot_deep_graph() {
+    // A snapshot of a linked list that is far too deep to be
+    // traversed recursively, with each object depending on the next
+    const DEPTH: u64 = 100_000;
+    let hash_of = |i: u64| {
+        let mut bytes = [0; ObjectHash::SIZE];
+        bytes[(ObjectHash::SIZE - 8)..].copy_from_slice(&(i + 1).to_be_bytes());
+        ObjectHash::from_be_bytes(bytes)
+    };
+    let mut data = Vec::<u8>::new();
+    let mut header = Vec::<u8>::new();
+    let stash = Stash::new();
+    stash
+        .export_snapshot(&stash.stash(&()), &mut header)
+        .unwrap();
+    data.extend_from_slice(&header[..8]);
+    data.extend_from_slice(&hash_of(0).to_be_bytes());
+    data.extend_from_slice(&DEPTH.to_be_bytes());
+    for i in 0..DEPTH {
+        data.extend_from_slice(&hash_of(i).to_be_bytes());
+        data.extend_from_slice(&0_u64.to_be_bytes());
+        let num_dependencies: u64 = if i + 1 < DEPTH { 1 } else { 0 };
+        data.extend_from_slice(&num_dependencies.to_be_bytes());
+        if i + 1 < DEPTH {
+            data.extend_from_slice(&hash_of(i + 1).to_be_bytes());
+        }
+    }
+
+    let stash = Stash::new();
+    let handle = stash.import_snapshot::<(), _>(data.as_slice()).unwrap();
+    assert_eq!(stash.num_objects(), DEPTH as usize);
+    std::mem::drop(handle);
+    assert_eq!(stash.num_objects(), 0);
+
+    // Closing the list into a cycle is detected without recursing either
+    let count_offset = data.len() - 8;
+    data[count_offset..].copy_from_slice(&1_u64.to_be_bytes());
+    data.extend_from_slice(&hash_of(0).to_be_bytes());
+    assert!(matches!(
+        stash.import_snapshot::<(), _>(data.as_slice()),
+        Err(SnapshotError::Corrupted)
+    ));
+    assert_eq!(stash.num_objects(), 0);
+}
+
+#[test]
+fn test_stats_deep_graph() {
+    // A chain of commits far too long to be traversed recursively
+    const DEPTH: usize = 200_000;
+    let mut graph = CommitGraph::<i32>::new();
+    for i in 0..DEPTH {
+        graph.commit(&(i as i32), "");
+    }
+    let head = graph.head().unwrap();
+
+    let stats = graph.stash().stats();
+    assert!(stats.num_objects >= DEPTH);
+    assert!(stats.logical_bytes >= stats.total_bytes);
+
+    // The main branch still refers to the head commit, so removing
+    // another reference to it wouldn't remove anything
+    let handle = graph.stash().handle_for::<()>(head.object_hash()).unwrap();
+    assert_eq!(graph.stash().retained_size(&handle), 0);
+
+    // Once exported and imported into another stash, nothing but the
+    // imported handle refers to the chain
+    let mut data = Vec::<u8>::new();
+    graph.stash().export_snapshot(&handle, &mut data).unwrap();
+    let stash = Stash::new();
+    let imported = stash.import_snapshot::<(), _>(data.as_slice()).unwrap();
+    let imported_stats = stash.stats();
+    assert_eq!(stash.retained_size(&imported), imported_stats.total_bytes);
+}
+
+#[test]
+fn test_pack_roundtrip() {
+    let b1 = make_large_struct_b(1);
+    let b2 = make_large_struct_b(2);
+
+    let stash1 = Stash::new();
+    let handle_b1 = stash1.stash(&b1);
+    let handle_b2 = stash1.stash(&b2);
+    let handle_a = stash1.stash(&b1.a1);
+
+    // two B's, three distinct A's
+    assert_eq!(stash1.num_objects(), 5);
+
+    let mut pack = StashPack::new();
+    pack.insert("autosave", &handle_b1);
+    pack.insert("checkpoint-3", &handle_b2);
+    pack.insert("a", &handle_a);
+    assert_eq!(pack.len(), 3);
+    assert_eq!(handle_b1.reference_count(), 2);
+
+    let mut data = Vec::<u8>::new();
+    stash1.export_pack(&pack, &mut data).unwrap();
+
+    std::mem::drop(pack);
+    assert_eq!(handle_b1.reference_count(), 1);
+
+    let stash2 = Stash::new();
+    let mut pack2 = stash2.import_pack(data.as_slice()).unwrap();
+
+    assert_eq!(stash2.num_objects(), 5);
+    assert_eq!(
+        pack2.names().collect::<Vec<_>>(),
+        vec!["autosave", "checkpoint-3", "a"]
+    );
+
+    let handle2_b1: StashHandle<StructB> = pack2.get("autosave").unwrap();
+    let handle2_b2: StashHandle<StructB> = pack2.remove("checkpoint-3").unwrap();
+    let handle2_a: StashHandle<StructA> = pack2.get("a").unwrap();
+    assert!(pack2.get::<StructB>("checkpoint-3").is_none());
+
+    assert_eq!(handle2_b1.object_hash(), handle_b1.object_hash());
+    assert_eq!(handle2_b1.reference_count(), 2);
+    assert_eq!(handle2_b2.reference_count(), 1);
+
+    assert_eq!(stash2.unstash(&handle2_b1).unwrap(), b1);
+    assert_eq!(stash2.unstash(&handle2_b2).unwrap(), b2);
+    assert_eq!(stash2.unstash(&handle2_a).unwrap(), b1.a1);
+
+    std::mem::drop(pack2);
+    std::mem::drop(handle2_b1);
+    std::mem::drop(handle2_b2);
+
+    // Only the A remains
+    assert_eq!(stash2.num_objects(), 1);
+
+    std::mem::drop(handle2_a);
+
+    assert_eq!(stash2.num_objects(), 0);
+}
+
+#[test]
+fn test_pack_replace_and_bad_data() {
+    let stash = Stash::new();
+    let handle1 = stash.stash(&make_struct_b(1));
+    let handle2 = stash.stash(&make_struct_b(2));
+
+    let mut pack = StashPack::new();
+    pack.insert("autosave", &handle1);
+    pack.insert("autosave", &handle2);
+    assert_eq!(pack.len(), 1);
+    assert_eq!(handle1.reference_count(), 1);
+    assert_eq!(handle2.reference_count(), 2);
+
+    let mut data = Vec::<u8>::new();
+    stash.export_pack(&pack, &mut data).unwrap();
+
+    // A pack is not a snapshot and vice versa
+    assert!(matches!(
+        stash.import_snapshot::<StructB, _>(data.as_slice()),
+        Err(SnapshotError::NotASnapshot)
+    ));
+
+    let mut snapshot_data = Vec::<u8>::new();
+    stash.export_snapshot(&handle1, &mut snapshot_data).unwrap();
+    assert!(matches!(
+        stash.import_pack(snapshot_data.as_slice()),
+        Err(SnapshotError::NotASnapshot)
+    ));
+
+    assert!(matches!(
+        stash.import_pack(&data[..data.len() - 3]),
+        Err(SnapshotError::Io(_))
+    ));
+
+    assert_eq!(handle1.reference_count(), 1);
+    assert_eq!(handle2.reference_count(), 2);
+}
+
+#[test]
+fn test_inspect() {
+    let stash = Stash::new();
+
+    let a = StructA {
+        i: -3,
+        x: 7,
+        s: "hi".to_string(),
+    };
+    let handle = stash.stash(&a);
+    let hash = ObjectHash::from_stashable(&a);
+
+    let expected_a = ObjectValue {
+        hash,
+        version: 0,
+        type_fingerprint: None,
+        values: vec![
+            Value::Primitive(PrimitiveValue::I32(-3)),
+            Value::Primitive(PrimitiveValue::U64(7)),
+            Value::String("hi".to_string()),
+        ],
+    };
+    assert_eq!(
+        stash.inspect(&handle),
+        Ok(Value::Object(Box::new(expected_a.clone())))
+    );
+    assert_eq!(
+        expected_a.to_string(),
+        format!("#{} {{-3_i32, 7_u64, \"hi\"}}", hash)
+    );
+
+    let v = vec![a.clone(), a];
+    let handle = stash.stash(&v);
+    let Ok(Value::Object(object)) = stash.inspect(&handle) else {
+        panic!("expected an object");
+    };
+    assert_eq!(
+        object.values,
+        vec![Value::ArrayOfObjects(vec![
+            expected_a.clone(),
+            expected_a.clone()
+        ])]
+    );
+
+    let inner = format!(
+        "#{} {{\n            -3_i32,\n            7_u64,\n            \"hi\",\n        }}",
+        hash
+    );
+    assert_eq!(
+        format!("{:#}", object),
+        format!(
+            "#{} {{\n    [\n        {},\n        {},\n    ],\n}}",
+            object.hash, inner, inner
+        )
+    );
+}
+
+/// An object referring to the same smaller diamond twice, down to
+/// a diamond of size 0
+struct Diamond(u32);
+
+impl Stashable for Diamond {
+    fn stash(&self, stasher: &mut Stasher) {
+        stasher.u32(self.0);
+        if self.0 > 0 {
+            stasher.object(&Diamond(self.0 - 1));
+            stasher.object(&Diamond(self.0 - 1));
+        }
+    }
+}
+
+#[test]
+fn test_inspect_shared_and_deep() {
+    let stash = Stash::new();
+    let handle = stash.stash(&Diamond(16));
+    let Ok(Value::Object(mut object)) = stash.inspect(&handle) else {
+        panic!("expected an object");
+    };
+    for size in (0..=16).rev() {
+        assert_eq!(object.hash, ObjectHash::from_stashable(&Diamond(size)));
+        assert_eq!(
+            object.values[0],
+            Value::Primitive(PrimitiveValue::U32(size))
+        );
+        if size == 0 {
+            assert_eq!(object.values.len(), 1);
+            break;
+        }
+        assert_eq!(object.values[1], object.values[2]);
+        let Value::Object(inner) = object.values.swap_remove(1) else {
+            panic!("expected an object");
+        };
+        object = inner;
+    }
+
+    // A chain of commits too long to be inspected
+    let mut graph = CommitGraph::<i32>::new();
+    for i in 0..2000 {
+        graph.commit(&i, "");
+    }
+    let handle = graph
+        .stash()
+        .handle_for::<()>(graph.head().unwrap().object_hash())
+        .unwrap();
+    assert_eq!(
+        graph.stash().inspect(&handle).map_err(|err| err.kind()),
+        Err(UnstashErrorKind::TooDeep)
+    );
+}
+
+/// An older version of PointV1 without a version number, having
+/// an extra label which was later removed
+struct PointV0 {
+    x: i32,
+    label: StructA,
+}
+
+impl Stashable for PointV0 {
+    fn stash(&self, stasher: &mut Stasher) {
+        stasher.i32(self.x);
+        stasher.object(&self.label);
+    }
+}
+
+/// A newer version of PointV0 with an added trailing value
+#[derive(Debug, PartialEq)]
+struct PointV1 {
+    x: i32,
+    y: i32,
+}
+
+impl Stashable for PointV1 {
+    fn stash(&self, stasher: &mut Stasher) {
+        stasher.version(1);
+        stasher.i32(self.x);
+        stasher.i32(self.y);
+    }
+}
+
+impl Unstashable for PointV1 {
+    fn unstash(unstasher: &mut Unstasher) -> Result<Self, UnstashError> {
+        let x = unstasher.i32()?;
+        if unstasher.version() == 0 {
+            unstasher.skip_value()?;
+        }
+        let y = unstasher.optional_or(7, Unstasher::i32)?;
+        Ok(PointV1 { x, y })
+    }
+}
+
+impl UnstashableInplace for PointV1 {
+    fn unstash_inplace(&mut self, unstasher: &mut InplaceUnstasher) -> Result<(), UnstashError> {
+        unstasher.i32_inplace(&mut self.x)?;
+        if unstasher.version() == 0 {
+            unstasher.skip_value()?;
+        }
+        let y = unstasher.optional_or(7, InplaceUnstasher::i32_always)?;
+        if unstasher.time_to_write() {
+            self.y = y;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_versioned_objects() {
+    let stash = Stash::new();
+
+    let old = PointV0 {
+        x: 3,
+        label: StructA {
+            i: 1,
+            x: 2,
+            s: "old".to_string(),
+        },
+    };
+    let old_handle = stash.stash(&old);
+    // The small label is stored inline
+    assert_eq!(stash.num_objects(), 1);
+    let old_as_new: StashHandle<PointV1> = stash.handle_for(old_handle.object_hash()).unwrap();
+    assert_eq!(stash.unstash(&old_as_new), Ok(PointV1 { x: 3, y: 7 }));
+
+    let mut p = PointV1 { x: 0, y: 0 };
+    stash.unstash_inplace(&old_as_new, &mut p).unwrap();
+    assert_eq!(p, PointV1 { x: 3, y: 7 });
+
+    let new = PointV1 { x: 4, y: 5 };
+    let new_handle = stash.stash(&new);
+    assert_eq!(stash.unstash(&new_handle), Ok(PointV1 { x: 4, y: 5 }));
+    let Ok(Value::Object(object)) = stash.inspect(&new_handle) else {
+        panic!("expected an object");
+    };
+    assert_eq!(object.version, 1);
+    assert_eq!(object.values.len(), 2);
+
+    // Version 0 is the same as having no version
+    let a = StructA {
+        i: 1,
+        x: 2,
+        s: "a".to_string(),
+    };
+    assert_eq!(
+        ObjectHash::with_stasher(|stasher| a.stash(stasher)),
+        ObjectHash::with_stasher(|stasher| {
+            stasher.version(0);
+            a.stash(stasher);
+        })
+    );
+}
+
+/// A value of every kind followed by a trailing integer
+struct SkippedValues;
+
+impl Stashable for SkippedValues {
+    fn stash(&self, stasher: &mut Stasher) {
+        stasher.bool(true);
+        stasher.array_of_u16_slice(&[1, 2, 3]);
+        stasher.string("skipped");
+        stasher.object(&"a".repeat(64));
+        stasher.object(&1_u8);
+        stasher.array_of_objects_slice(&[7_u32, 8_u32], Order::Ordered);
+        stasher.i32(-5);
+    }
+}
+
+#[test]
+fn test_skip_values() {
+    let stash = Stash::new();
+    let handle = stash.stash(&SkippedValues);
+    let handle: StashHandle<i32> = stash.handle_for(handle.object_hash()).unwrap();
+    let value = stash.unstash_proxy(&handle, |unstasher| {
+        for _ in 0..6 {
+            unstasher.skip_value()?;
+        }
+        unstasher.i32()
+    });
+    assert_eq!(value, Ok(-5));
+}
+
+#[test]
+fn test_unstash_error_context() {
+    let stash = Stash::new();
+
+    let a = StructA {
+        i: 1,
+        x: 2,
+        s: "a".to_string(),
+    };
+    let a_hash = ObjectHash::from_stashable(&a);
+    let handle = stash.stash(&(a.clone(),));
+    let wrong_handle: StashHandle<(String,)> = stash.handle_for(handle.object_hash()).unwrap();
+
+    let err = stash.unstash(&wrong_handle).unwrap_err();
+    assert_eq!(err.kind(), UnstashErrorKind::WrongValueType);
+    assert_eq!(err.expected(), Some(ValueType::String));
+    assert_eq!(err.found(), Some(ValueType::Primitive(PrimitiveType::I32)));
+    assert_eq!(err.object(), Some(a_hash));
+    assert_eq!(err.offset(), Some(0));
+    assert_eq!(
+        err.path(),
+        &[
+            UnstashPathEntry {
+                hash: handle.object_hash(),
+                label: None
+            },
+            UnstashPathEntry {
+                hash: a_hash,
+                label: None
+            }
+        ]
+    );
+
+    let handle = stash.stash(&a);
+    let err = stash
+        .unstash_proxy(&handle, |unstasher| {
+            unstasher.label("StructA");
+            Ok(StructA {
+                i: unstasher.i32()?,
+                x: 0,
+                s: String::new(),
+            })
+        })
+        .unwrap_err();
+    assert_eq!(err.kind(), UnstashErrorKind::NotFinished);
+    assert_eq!(err.offset(), Some(5));
+    assert_eq!(
+        err.to_string(),
+        format!("NotFinished at byte 5 in StructA #{}", a_hash)
+    );
+}
+
+#[test]
+fn test_object_hash_size() {
+    let hash = ObjectHash::from_stashable(&make_struct_b(0));
+    let expected_size = if cfg!(feature = "hash128") { 16 } else { 8 };
+    assert_eq!(ObjectHash::SIZE, expected_size);
+    assert_eq!(hash.to_string().len(), 2 * expected_size);
+    assert_eq!(ObjectHash::from_be_bytes(hash.to_be_bytes()), hash);
+}
+
+/// A badly behaved object which always hashes the same way but
+/// serializes its actual value, simulating a hash collision
+struct CollidingObject(i32);
+
+impl Stashable for CollidingObject {
+    fn stash(&self, stasher: &mut Stasher) {
+        // Padding to keep the object from being stored inline
+        stasher.array_of_u8_slice(&[0; 64]);
+        if stasher.hashing() {
+            stasher.i32(0);
+        } else {
+            stasher.i32(self.0);
+        }
+    }
+}
+
+#[test]
+fn test_collision_detection() {
+    let stash = Stash::new();
+    assert!(!stash.detects_collisions());
+    let _h1 = stash.stash(&CollidingObject(1));
+    let _h2 = stash.try_stash(&CollidingObject(2)).unwrap();
+    assert_eq!(stash.num_objects(), 1);
+
+    let stash = Stash::with_collision_detection();
+    assert!(stash.detects_collisions());
+    let h1 = stash.stash(&CollidingObject(1));
+    let hash = h1.object_hash();
+    let _h1_again = stash.try_stash(&CollidingObject(1)).unwrap();
+    assert_eq!(stash.num_objects(), 1);
+    assert_eq!(h1.reference_count(), 2);
+
+    assert_eq!(
+        stash.try_stash(&CollidingObject(2)).err(),
+        Some(HashCollision { hash })
+    );
+    assert_eq!(
+        HashCollision { hash }.to_string(),
+        format!("hash collision on #{}", hash)
+    );
+    assert_eq!(stash.num_objects(), 1);
+    assert_eq!(h1.reference_count(), 2);
+
+    // Collisions in dependencies are detected too, and any new
+    // objects that depended on them are removed again
+    assert_eq!(
+        stash.try_stash(&(CollidingObject(2), 5_u8)).err(),
+        Some(HashCollision { hash })
+    );
+    assert_eq!(stash.num_objects(), 1);
+    assert_eq!(h1.reference_count(), 2);
+
+    let _h3 = stash.try_stash(&(CollidingObject(1), 5_u8)).unwrap();
+    assert_eq!(stash.num_objects(), 2);
+
+    // Imported objects are compared with existing ones as well, and
+    // nothing is imported if any of them collide
+    let export = |object: &(CollidingObject, u8)| {
+        let other_stash = Stash::new();
+        let handle = other_stash.stash(object);
+        let mut data = Vec::<u8>::new();
+        other_stash.export_snapshot(&handle, &mut data).unwrap();
+        data
+    };
+    let colliding = export(&(CollidingObject(2), 6_u8));
+    assert!(matches!(
+        stash.import_snapshot::<(CollidingObject, u8), _>(colliding.as_slice()),
+        Err(SnapshotError::HashCollision(h)) if h == hash
+    ));
+    assert_eq!(stash.num_objects(), 2);
+    assert_eq!(h1.reference_count(), 3);
+
+    let matching = export(&(CollidingObject(1), 6_u8));
+    let _h4 = stash
+        .import_snapshot::<(CollidingObject, u8), _>(matching.as_slice())
+        .unwrap();
+    assert_eq!(stash.num_objects(), 3);
+    assert_eq!(h1.reference_count(), 4);
+}
+
+#[test]
+fn test_stash_stats() {
+    let stash = Stash::new();
+    assert_eq!(stash.stats(), Default::default());
+    assert_eq!(stash.stats().deduplication_ratio(), 1.0);
+
+    let b1 = make_large_struct_b(1);
+    let b2 = make_large_struct_b(2);
+    let size_a = {
+        let stash = Stash::new();
+        let _handle = stash.stash(&b1.a1);
+        stash.stats().total_bytes
+    };
+
+    let handle1 = stash.stash(&b1);
+    let handle2 = stash.stash(&b2);
+
+    // a1 is only referred to by its own B, while a2 and a3 are the
+    // same object shared by both B's
+    let size_b = stash.retained_size(&handle1) - size_a;
+    assert_eq!(stash.retained_size(&handle2), size_b + size_a);
+
+    let stats = stash.stats();
+    assert_eq!(stats.num_objects, 5);
+    assert_eq!(stats.num_dependencies, 6);
+    assert_eq!(stats.total_bytes, 2 * size_b + 3 * size_a);
+    assert_eq!(stats.logical_bytes, 2 * size_b + 6 * size_a);
+    assert!(stats.deduplication_ratio() > 1.0);
+    assert_eq!(stats.size_histogram.iter().sum::<usize>(), 5);
+    assert_eq!(stats.reference_counts, BTreeMap::from([(1, 4), (4, 1)]));
+    assert_eq!(stats.largest_objects.len(), 5);
+    assert_eq!(stats.largest_objects[0].1, size_a.max(size_b));
+
+    // Nothing is freed by dropping one of several handles
+    let handle1_again = handle1.clone();
+    assert_eq!(stash.retained_size(&handle1), 0);
+    std::mem::drop(handle1_again);
+
+    std::mem::drop(handle1);
+    assert_eq!(stash.stats().total_bytes, size_b + 2 * size_a);
+}
+
+#[test]
+fn test_eviction() {
+    let stash = Stash::new();
+    let evicted = Arc::new(Mutex::new(Vec::new()));
+    let evicted_clone = Arc::clone(&evicted);
+    stash.set_eviction_callback(move |hash| evicted_clone.lock().unwrap().push(hash));
+
+    // Each state adds one B and one A, while sharing a2 and a3
+    stash.set_max_objects(Some(5));
+    let handles: Vec<EvictableHandle<StructB>> = (0..3)
+        .map(|i| stash.make_evictable(stash.stash(&make_large_struct_b(i))))
+        .collect();
+
+    // The oldest state was evicted to make room for the newest
+    assert_eq!(stash.num_objects(), 5);
+    assert_eq!(*evicted.lock().unwrap(), vec![handles[0].object_hash()]);
+    assert!(handles[0].is_evicted());
+    assert!(handles[0].get().is_none());
+    let handle1 = handles[1].get().unwrap();
+    assert_eq!(stash.unstash(&handle1).unwrap(), make_large_struct_b(1));
+    std::mem::drop(handle1);
+
+    // State 1 was used more recently than state 2
+    stash.set_eviction_policy(EvictionPolicy::LeastRecentlyUsed);
+    let handle3 = stash.make_evictable(stash.stash(&make_large_struct_b(3)));
+    assert!(handles[2].is_evicted());
+    assert!(!handles[1].is_evicted());
+    assert!(!handle3.is_evicted());
+
+    // Objects that are still referred to elsewhere are kept even when
+    // their roots are evicted
+    let handle1 = handles[1].get().unwrap();
+    stash.set_max_bytes(Some(0));
+    assert!(handles[1].is_evicted());
+    assert!(handle3.is_evicted());
+    assert_eq!(stash.num_objects(), 3);
+    assert_eq!(stash.unstash(&handle1).unwrap(), make_large_struct_b(1));
+    assert_eq!(evicted.lock().unwrap().len(), 4);
+
+    std::mem::drop(handle1);
+    assert_eq!(stash.num_objects(), 0);
+
+    // Dropping an evictable handle releases its object
+    stash.set_max_bytes(None);
+    let handle = stash.make_evictable(stash.stash(&make_large_struct_b(0)));
+    assert_eq!(stash.num_objects(), 3);
+    std::mem::drop(handle);
+    assert_eq!(stash.num_objects(), 0);
+
+    // Dropping an evictable handle while unstashing releases its
+    // object once unstashing is done
+    let mut evictable = Some(stash.make_evictable(stash.stash(&make_large_struct_b(0))));
+    let handle = stash.stash(&make_large_struct_b(1));
+    assert_eq!(stash.num_objects(), 5);
+    let unstashed = stash.unstash_proxy(&handle, |unstasher| {
+        std::mem::drop(evictable.take());
+        StructB::unstash(unstasher)
+    });
+    assert_eq!(unstashed, Ok(make_large_struct_b(1)));
+    assert_eq!(stash.num_objects(), 3);
+}
+
+#[test]
+fn test_weak_handles() {
+    let stash = Stash::new();
+    let handle = stash.stash(&make_struct_b(1));
+    let weak = handle.downgrade();
+    assert_eq!(weak.object_hash(), handle.object_hash());
+    assert_eq!(handle.reference_count(), 1);
+
+    let upgraded = weak.upgrade().unwrap();
+    assert_eq!(handle.reference_count(), 2);
+    assert_eq!(stash.unstash(&upgraded).unwrap(), make_struct_b(1));
+
+    std::mem::drop(handle);
+    std::mem::drop(upgraded);
+    assert_eq!(stash.num_objects(), 0);
+    assert!(weak.clone().upgrade().is_none());
+
+    // Stashing identical contents again brings the object back
+    let handle = stash.stash(&make_struct_b(1));
+    assert!(weak.upgrade().is_some());
+
+    // Weak handles don't keep the stash alive either
+    std::mem::drop(handle);
+    let handle = stash.stash(&make_struct_b(1));
+    let weak = handle.downgrade();
+    std::mem::drop(stash);
+    assert!(weak.upgrade().is_some());
+    std::mem::drop(handle);
+    assert!(weak.upgrade().is_none());
+}
+
+#[test]
+fn test_handle_for_hash() {
+    let stash = Stash::new();
+    let b = make_large_struct_b(1);
+    let hash = ObjectHash::from_stashable(&b);
+    assert!(!stash.contains(hash));
+    assert!(stash.handle_for::<StructB>(hash).is_none());
+
+    let handle = stash.stash(&b);
+    assert!(stash.contains(hash));
+    assert!(stash.contains(ObjectHash::from_stashable(&b.a2)));
+
+    let handle2: StashHandle<StructB> = stash.handle_for(hash).unwrap();
+    assert_eq!(handle.reference_count(), 2);
+    assert_eq!(stash.unstash(&handle2).unwrap(), b);
+
+    std::mem::drop(handle);
+    std::mem::drop(handle2);
+    assert!(!stash.contains(hash));
+
+    // Small objects which are stored inline are not found on their own
+    let point = PointV0 {
+        x: 1,
+        label: StructA {
+            i: 1,
+            x: 2,
+            s: "small".to_string(),
+        },
+    };
+    let point_handle = stash.stash(&point);
+    assert!(stash.contains(point_handle.object_hash()));
+    let label_hash = ObjectHash::from_stashable(&point.label);
+    assert!(!stash.contains(label_hash));
+    assert!(stash.handle_for::<StructA>(label_hash).is_none());
+}
+
+struct Document {
+    title: String,
+    body: Lazy<StructB>,
+}
+
+impl Stashable for Document {
+    fn stash(&self, stasher: &mut Stasher) {
+        stasher.string(&self.title);
+        stasher.object(&self.body);
+    }
+}
+
+impl Unstashable for Document {
+    fn unstash(unstasher: &mut Unstasher) -> Result<Self, UnstashError> {
+        Ok(Document {
+            title: unstasher.string()?,
+            body: unstasher.object()?,
+        })
+    }
+}
+
+impl UnstashableInplace for Document {
+    fn unstash_inplace(&mut self, unstasher: &mut InplaceUnstasher) -> Result<(), UnstashError> {
+        unstasher.string_inplace(&mut self.title)?;
+        unstasher.object_inplace(&mut self.body)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_lazy() {
+    let stash = Stash::new();
+    let document = Document {
+        title: "doc".to_string(),
+        body: Lazy::new(make_large_struct_b(1)),
+    };
+    let handle = stash.stash(&document);
+
+    // The document, the Lazy, one B and two A's
+    assert_eq!(stash.num_objects(), 5);
+
+    let unstashed = stash.unstash(&handle).unwrap();
+    assert_eq!(unstashed.title, "doc");
+    assert!(!unstashed.body.is_loaded());
+    assert_eq!(unstashed.body.get().unwrap(), &make_large_struct_b(1));
+    assert!(unstashed.body.is_loaded());
+
+    // Stashing the same document again refers to the same objects
+    let handle2 = stash.stash(&unstashed);
+    assert_eq!(handle2.object_hash(), handle.object_hash());
+    assert_eq!(stash.num_objects(), 5);
+
+    std::mem::drop(unstashed);
+
+    // The unstashed value keeps the stashed B alive
+    let mut unstashed = stash.unstash(&handle).unwrap();
+    std::mem::drop(handle);
+    std::mem::drop(handle2);
+    assert_eq!(stash.num_objects(), 3);
+
+    // Values that were never loaded are loaded from their own stash
+    // when stashed elsewhere
+    let other_stash = Stash::new();
+    assert!(!unstashed.body.is_loaded());
+    let other_handle = other_stash.stash(&unstashed);
+    assert!(unstashed.body.is_loaded());
+    assert_eq!(other_stash.num_objects(), 5);
+    let other_unstashed = other_stash.unstash(&other_handle).unwrap();
+    assert_eq!(
+        other_unstashed.body.into_inner().unwrap(),
+        make_large_struct_b(1)
+    );
+
+    unstashed.body.get_mut().unwrap().u = 99;
+    assert!(unstashed.body.handle().is_none());
+    let handle = stash.stash(&unstashed);
+    assert_eq!(stash.num_objects(), 5);
+
+    let mut document = Document {
+        title: String::new(),
+        body: Lazy::new(make_large_struct_b(0)),
+    };
+    stash.unstash_inplace(&handle, &mut document).unwrap();
+    assert!(!document.body.is_loaded());
+    assert_eq!(document.body.get().unwrap().u, 99);
+    std::mem::drop(unstashed);
+
+    // Handles created while unstashing can be dropped before unstashing
+    // finishes, such as when an error occurs
+    let result = stash.unstash_proxy(&handle, |unstasher| {
+        let title = unstasher.string()?;
+        let body = unstasher.object()?;
+        unstasher.u8()?;
+        Ok(Document { title, body })
+    });
+    assert_eq!(
+        result.err().map(|err| err.kind()),
+        Some(UnstashErrorKind::OutOfData)
+    );
+
+    std::mem::drop(handle);
+    assert_eq!(stash.num_objects(), 3);
+    std::mem::drop(document);
+    assert_eq!(stash.num_objects(), 0);
+}
+
+#[derive(PartialEq, Debug)]
+struct OldConfig {
+    width: u32,
+}
+
+impl Stashable for OldConfig {
+    fn stash(&self, stasher: &mut Stasher) {
+        stasher.u32(self.width);
+    }
+}
+
+impl Unstashable for OldConfig {
+    fn unstash(unstasher: &mut Unstasher) -> Result<Self, UnstashError> {
+        Ok(OldConfig {
+            width: unstasher.u32()?,
+        })
+    }
+}
+
+#[derive(PartialEq, Debug)]
+struct NewConfig {
+    width: u64,
+    height: u64,
+}
+
+impl Stashable for NewConfig {
+    fn stash(&self, stasher: &mut Stasher) {
+        stasher.u64(self.width);
+        stasher.u64(self.height);
+    }
+}
+
+impl Unstashable for NewConfig {
+    fn unstash(unstasher: &mut Unstasher) -> Result<Self, UnstashError> {
+        Ok(NewConfig {
+            width: unstasher.u64()?,
+            height: unstasher.u64()?,
+        })
+    }
+}
+
+impl Migrate<OldConfig> for NewConfig {
+    fn migrate(old: OldConfig) -> Result<Self, UnstashError> {
+        Ok(NewConfig {
+            width: old.width.into(),
+            height: 100,
+        })
+    }
+}
+
+#[test]
+fn test_unstash_as() {
+    let stash = Stash::new();
+    let old_handle = stash.stash(&OldConfig { width: 3 });
+
+    let cast_handle: StashHandle<NewConfig> = old_handle.cast();
+    assert_eq!(cast_handle.object_hash(), old_handle.object_hash());
+    assert_eq!(old_handle.reference_count(), 2);
+    assert_eq!(
+        stash.unstash(&cast_handle).err().map(|err| err.kind()),
+        Some(UnstashErrorKind::WrongValueType)
+    );
+
+    assert_eq!(
+        stash.unstash_as::<NewConfig, _>(&old_handle),
+        Ok(NewConfig {
+            width: 3,
+            height: 100
+        })
+    );
+
+    // Data that can be read directly is not migrated
+    let new_handle = stash.stash(&NewConfig {
+        width: 4,
+        height: 5,
+    });
+    assert_eq!(
+        stash.unstash_as::<NewConfig, _>(&new_handle.cast::<OldConfig>()),
+        Ok(NewConfig {
+            width: 4,
+            height: 5
+        })
+    );
+
+    // If neither type can be read, the error for the new type is returned
+    let other_handle = stash.stash(&make_struct_b(0)).cast::<OldConfig>();
+    let err = stash.unstash_as::<NewConfig, _>(&other_handle).unwrap_err();
+    assert_eq!(err.kind(), UnstashErrorKind::WrongValueType);
+    assert_eq!(
+        err.expected(),
+        Some(ValueType::Primitive(PrimitiveType::U64))
+    );
+}
+
+#[derive(PartialEq, Debug)]
+struct Point {
+    x: f32,
+    y: f32,
+}
+
+impl Stashable for Point {
+    fn stash(&self, stasher: &mut Stasher) {
+        stasher.type_name("Point");
+        stasher.f32(self.x);
+        stasher.f32(self.y);
+    }
+}
+
+impl Unstashable for Point {
+    fn unstash(unstasher: &mut Unstasher) -> Result<Self, UnstashError> {
+        unstasher.type_name("Point")?;
+        Ok(Point {
+            x: unstasher.f32()?,
+            y: unstasher.f32()?,
+        })
+    }
+}
+
+#[derive(PartialEq, Debug)]
+struct Size {
+    w: f32,
+    h: f32,
+}
+
+impl Stashable for Size {
+    fn stash(&self, stasher: &mut Stasher) {
+        stasher.version(2);
+        stasher.type_name("Size");
+        stasher.f32(self.w);
+        stasher.f32(self.h);
+    }
+}
+
+impl Unstashable for Size {
+    fn unstash(unstasher: &mut Unstasher) -> Result<Self, UnstashError> {
+        unstasher.type_name("Size")?;
+        assert_eq!(unstasher.version(), 2);
+        Ok(Size {
+            w: unstasher.f32()?,
+            h: unstasher.f32()?,
+        })
+    }
+}
+
+#[test]
+fn test_type_names() {
+    let stash = Stash::new();
+    let point = Point { x: 1.0, y: 2.0 };
+    let size = Size { w: 1.0, h: 2.0 };
+
+    // The same values stashed under different type names don't collide
+    assert_ne!(
+        ObjectHash::from_stashable(&point),
+        ObjectHash::from_stashable(&size)
+    );
+    assert_ne!(
+        ObjectHash::from_stashable(&point),
+        ObjectHash::with_stasher(|stasher| {
+            stasher.f32(1.0);
+            stasher.f32(2.0);
+        })
+    );
+
+    let point_handle = stash.stash(&point);
+    let size_handle = stash.stash(&size);
+    assert_eq!(stash.stats().num_objects, 2);
+    assert_eq!(stash.unstash(&point_handle), Ok(point));
+    assert_eq!(stash.unstash(&size_handle), Ok(size));
+
+    // Unstashing as the wrong type fails before reading any values
+    let err = stash.unstash(&point_handle.cast::<Size>()).unwrap_err();
+    assert_eq!(err.kind(), UnstashErrorKind::TypeMismatch);
+    assert_eq!(err.object(), Some(point_handle.object_hash()));
+    assert_eq!(
+        stash
+            .unstash(&size_handle.cast::<Point>())
+            .err()
+            .map(|err| err.kind()),
+        Some(UnstashErrorKind::TypeMismatch)
+    );
+
+    // Objects stashed without a type name don't match either
+    struct UntypedPoint(f32, f32);
+    impl Stashable for UntypedPoint {
+        fn stash(&self, stasher: &mut Stasher) {
+            stasher.f32(self.0);
+            stasher.f32(self.1);
+        }
+    }
+    let untyped_handle = stash.stash(&UntypedPoint(1.0, 2.0));
+    assert_eq!(
+        stash
+            .unstash(&untyped_handle.cast::<Point>())
+            .err()
+            .map(|err| err.kind()),
+        Some(UnstashErrorKind::TypeMismatch)
+    );
+
+    // The fingerprint is not a value of its own
+    let inspected = stash.inspect(&size_handle).unwrap();
+    let Value::Object(object) = inspected else {
+        panic!("expected an object");
+    };
+    assert_eq!(object.version, 2);
+    assert!(object.type_fingerprint.is_some());
+    assert_eq!(object.values.len(), 2);
+}
+
+#[test]
+#[should_panic(expected = "Stasher::type_name must be called before")]
+fn test_type_name_after_values() {
+    // A u32 is as long as a version but must still be rejected, and
+    // this must happen while hashing as well as while serializing
+    ObjectHash::with_stasher(|stasher| {
+        stasher.u32(1);
+        stasher.type_name("Late");
+    });
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Measurement {
+    value: f64,
+    samples: [f32; 2],
+}
+
+impl Stashable for Measurement {
+    fn stash(&self, stasher: &mut Stasher) {
+        stasher.f64_canonical(self.value);
+        stasher.array_of_f32_slice_canonical(&self.samples);
+    }
+}
+
+impl Unstashable for Measurement {
+    fn unstash(unstasher: &mut Unstasher) -> Result<Self, UnstashError> {
+        let value = unstasher.f64()?;
+        let samples = unstasher.array_of_f32_vec()?;
+        Ok(Measurement {
+            value,
+            samples: samples.try_into().map_err(|_| UnstashErrorKind::BadValue)?,
+        })
+    }
+}
+
+#[test]
+fn test_canonical_floats() {
+    let positive = Measurement {
+        value: 0.0,
+        samples: [f32::NAN, 1.5],
+    };
+    let negative = Measurement {
+        value: -0.0,
+        samples: [f32::from_bits(0xffc0_1234), 1.5],
+    };
+    assert_eq!(
+        ObjectHash::from_stashable(&positive),
+        ObjectHash::from_stashable(&negative)
+    );
+    assert_ne!(
+        ObjectHash::from_stashable(&positive),
+        ObjectHash::from_stashable(&Measurement {
+            value: 0.0,
+            samples: [f32::NAN, 2.5],
+        })
+    );
+
+    // Regular floats are hashed using their exact bits
+    assert_ne!(
+        ObjectHash::with_stasher(|stasher| stasher.f64(0.0)),
+        ObjectHash::with_stasher(|stasher| stasher.f64(-0.0))
+    );
+
+    // The exact bits are serialized, and are kept by equivalent objects
+    let stash = Stash::new();
+    let handle = stash.stash(&negative);
+    let _positive_handle = stash.stash(&positive);
+    assert_eq!(stash.num_objects(), 1);
+    let unstashed = stash.unstash(&handle).unwrap();
+    assert_eq!(unstashed.value.to_bits(), (-0.0_f64).to_bits());
+    assert_eq!(unstashed.samples[0].to_bits(), 0xffc0_1234);
+
+    // Stashes that detect collisions serialize the exact bits too, but
+    // compare them canonically, including within inline objects
+    let stash = Stash::with_collision_detection();
+    let handle = stash.stash(&negative);
+    assert!(stash.try_stash(&positive).is_ok());
+    assert_eq!(stash.num_objects(), 1);
+    let unstashed = stash.unstash(&handle).unwrap();
+    assert_eq!(unstashed.value.to_bits(), (-0.0_f64).to_bits());
+    assert_eq!(unstashed.samples[0].to_bits(), 0xffc0_1234);
+    let _pair_handle = stash.stash(&(negative, 1_u8));
+    assert!(stash.try_stash(&(positive, 1_u8)).is_ok());
+    assert_eq!(stash.num_objects(), 2);
+
+    // Floats which aren't equivalent are still collisions
+    struct CollidingFloat(f64);
+    impl Stashable for CollidingFloat {
+        fn stash(&self, stasher: &mut Stasher) {
+            stasher.array_of_u8_slice(&[0; 64]);
+            if stasher.hashing() {
+                stasher.f64_canonical(0.0);
+            } else {
+                stasher.f64_canonical(self.0);
+            }
+        }
+    }
+    let _float_handle = stash.stash(&CollidingFloat(-0.0));
+    assert!(stash.try_stash(&CollidingFloat(0.0)).is_ok());
+    assert!(stash.try_stash(&CollidingFloat(1.0)).is_err());
+}
+
+#[derive(Clone, PartialEq, Debug)]
+struct Record {
+    id: u128,
+    offset: i128,
+    initial: char,
+    tags: Vec<char>,
+    uuid: [u8; 16],
+}
+
+impl Stashable for Record {
+    fn stash(&self, stasher: &mut Stasher) {
+        stasher.u128(self.id);
+        stasher.i128(self.offset);
+        stasher.char(self.initial);
+        stasher.array_of_char_slice(&self.tags);
+        stasher.byte_array(&self.uuid);
+    }
+}
+
+impl Unstashable for Record {
+    fn unstash(unstasher: &mut Unstasher) -> Result<Self, UnstashError> {
+        Ok(Record {
+            id: unstasher.u128()?,
+            offset: unstasher.i128()?,
+            initial: unstasher.char()?,
+            tags: unstasher.array_of_char_vec()?,
+            uuid: unstasher.byte_array()?,
+        })
+    }
+}
+
+impl UnstashableInplace for Record {
+    fn unstash_inplace(&mut self, unstasher: &mut InplaceUnstasher) -> Result<(), UnstashError> {
+        unstasher.u128_inplace(&mut self.id)?;
+        unstasher.i128_inplace(&mut self.offset)?;
+        unstasher.char_inplace(&mut self.initial)?;
+        unstasher.array_of_char_vec_inplace(&mut self.tags)?;
+        unstasher.byte_array_inplace(&mut self.uuid)?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_wide_primitives() {
+    let create = || Record {
+        id: u128::MAX - 1,
+        offset: i128::MIN + 2,
+        initial: 'é',
+        tags: vec!['a', '🦀'],
+        uuid: *b"0123456789abcdef",
+    };
+    let modify_id = |r: &mut Record| r.id ^= 1 << 64;
+    let modify_initial = |r: &mut Record| r.initial = 'e';
+    let modify_uuid = |r: &mut Record| r.uuid[15] = 0;
+
+    assert_eq!(test_stash_roundtrip(create, modify_id, (), ()), Ok(()));
+    assert_eq!(test_stash_roundtrip(create, modify_initial, (), ()), Ok(()));
+    assert_eq!(test_stash_roundtrip(create, modify_uuid, (), ()), Ok(()));
+    assert_eq!(
+        test_stash_roundtrip_inplace(create, modify_id, (), ()),
+        Ok(())
+    );
+    assert_eq!(
+        test_stash_roundtrip_inplace(create, modify_uuid, (), ()),
+        Ok(())
+    );
+
+    let stash = Stash::new();
+    let handle = stash.stash(&create());
+
+    // The wide types are checked like any other primitive
+    let err = stash
+        .unstash_proxy(&handle, |unstasher| {
+            unstasher.u64()?;
+            Ok(create())
+        })
+        .unwrap_err();
+    assert_eq!(err.found(), Some(ValueType::Primitive(PrimitiveType::U128)));
+
+    // Byte arrays must have the expected length
+    let err = stash
+        .unstash_proxy(&handle, |unstasher| {
+            unstasher.u128()?;
+            unstasher.i128()?;
+            unstasher.char()?;
+            unstasher.array_of_char_vec()?;
+            unstasher.byte_array::<8>()?;
+            Ok(create())
+        })
+        .unwrap_err();
+    assert_eq!(err.kind(), UnstashErrorKind::BadValue);
+
+    let Ok(Value::Object(object)) = stash.inspect(&handle) else {
+        panic!("expected an object");
+    };
+    assert_eq!(
+        object.values[2],
+        Value::Primitive(PrimitiveValue::Char('é'))
+    );
+    assert!(object.to_string().contains("'é'"));
+}
+
+#[test]
+#[cfg(target_pointer_width = "64")]
+fn test_long_lengths() {
+    use crate::valuetypes::{read_length_from, write_length_at, write_length_to};
+
+    let boundary = u32::MAX as usize;
+    for (length, prefix_size) in [
+        (0, 4),
+        (boundary - 1, 4),
+        (boundary, 12),
+        (boundary + 1, 12),
+        (1 << 40, 12),
+    ] {
+        // A placeholder between other bytes, as left by the stasher
+        let mut data = vec![0xaa, 0, 0, 0, 0, 0xbb, 0xcc];
+        write_length_at(&mut data, 1, length);
+        assert_eq!(data.len(), 3 + prefix_size);
+        assert_eq!(data[0], 0xaa);
+
+        let mut bytes = &data[1..];
+        assert_eq!(read_length_from(&mut bytes), Ok(length));
+        assert_eq!(bytes, &[0xbb, 0xcc]);
+    }
+
+    // Lengths below the boundary are written exactly as before
+    let mut data = vec![0; 4];
+    write_length_at(&mut data, 0, boundary - 1);
+    assert_eq!(data, (u32::MAX - 1).to_be_bytes());
+
+    // Truncated escaped lengths are reported as corrupted
+    let mut bytes: &[u8] = &[0xff, 0xff, 0xff, 0xff, 0, 0, 0];
+    assert_eq!(
+        read_length_from(&mut bytes).map_err(|err| err.kind()),
+        Err(UnstashErrorKind::Corrupted)
+    );
+
+    // Lengths that are known up front are written the same way
+    for length in [0, boundary - 1, boundary, 1 << 40] {
+        let mut data = vec![0xaa];
+        write_length_to(&mut data, length);
+        let mut expected = vec![0xaa, 0, 0, 0, 0];
+        write_length_at(&mut expected, 1, length);
+        assert_eq!(data, expected);
+    }
+}
+
+/// An object whose string and array have escaped lengths, which are
+/// valid even when the length would fit in 32 bits
+struct EscapedLengths;
+
+impl Stashable for EscapedLengths {
+    fn stash(&self, stasher: &mut Stasher) {
+        let escape = u32::MAX.to_be_bytes();
+        stasher.write_raw_bytes(&[ValueType::String.to_byte()]);
+        stasher.write_raw_bytes(&escape);
+        stasher.write_raw_bytes(&3_u64.to_be_bytes());
+        stasher.write_raw_bytes(b"abc");
+        stasher.write_raw_bytes(&[ValueType::Array(PrimitiveType::U16).to_byte()]);
+        stasher.write_raw_bytes(&escape);
+        stasher.write_raw_bytes(&2_u64.to_be_bytes());
+        stasher.write_raw_bytes(&[0, 1, 0, 2]);
+        stasher.i32(-1);
+    }
+}
+
+#[test]
+fn test_escaped_lengths() {
+    let stash = Stash::new();
+    let handle = stash.stash(&EscapedLengths);
+    let handle: StashHandle<i32> = stash.handle_for(handle.object_hash()).unwrap();
+
+    let value = stash.unstash_proxy(&handle, |unstasher| {
+        assert_eq!(unstasher.peek_length(), Ok(3));
+        assert_eq!(unstasher.string()?, "abc");
+        assert_eq!(unstasher.peek_length(), Ok(2));
+        assert_eq!(unstasher.array_of_u16_vec()?, vec![1, 2]);
+        unstasher.i32()
+    });
+    assert_eq!(value, Ok(-1));
+
+    let value = stash.unstash_proxy(&handle, |unstasher| {
+        unstasher.skip_value()?;
+        unstasher.skip_value()?;
+        unstasher.i32()
+    });
+    assert_eq!(value, Ok(-1));
+}
@@ -1,13 +1,17 @@
 use std::marker::PhantomData;
 
 use crate::{
-    valuetypes::PrimitiveReadWrite, ObjectHash, StashMap, StashedObject, Unstashable,
-    UnstashableInplace, ValueType,
+    valuetypes::{
+        read_length_from, type_fingerprint, PrimitiveReadWrite, INLINE_OBJECT_TAG,
+        LONG_LENGTH_ESCAPE,
+    },
+    Lazy, ObjectHash, PrimitiveType, PrimitiveValue, StashMap, Unstashable, UnstashableInplace,
+    ValueType,
 };
 
-/// Error that can happen while unstashing an object
+/// The kind of error that can happen while unstashing an object
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
-pub enum UnstashError {
+pub enum UnstashErrorKind {
     /// The next stashed value does not have the expected type
     WrongValueType,
 
@@ -23,8 +27,143 @@ pub enum UnstashError {
     /// A value was read succesfully, but it has no valid interpretation
     /// in context. This is intended mainly for client use.
     BadValue,
+
+    /// The object was stashed with a different type name than the one
+    /// it was unstashed as, or without one. See [Unstasher::type_name].
+    TypeMismatch,
+
+    /// Objects are nested too deeply to be inspected.
+    /// See [crate::Stash::inspect].
+    TooDeep,
+}
+
+/// A stashed object that was being unstashed when an error happened
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UnstashPathEntry {
+    /// The hash of the stashed object
+    pub hash: ObjectHash,
+
+    /// The label given to the object while unstashing it, if any.
+    /// See [Unstasher::label].
+    pub label: Option<&'static str>,
+}
+
+/// Error that can happen while unstashing an object, along with
+/// details about where in the stashed data it happened. Use
+/// [UnstashError::kind] to find out what went wrong.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UnstashError {
+    kind: UnstashErrorKind,
+    offset: Option<usize>,
+    expected: Option<ValueType>,
+    found: Option<ValueType>,
+    path: Vec<UnstashPathEntry>,
 }
 
+impl UnstashError {
+    /// Create a new error of the given kind without any details
+    pub fn new(kind: UnstashErrorKind) -> UnstashError {
+        UnstashError {
+            kind,
+            offset: None,
+            expected: None,
+            found: None,
+            path: Vec::new(),
+        }
+    }
+
+    /// Create a new error for a value that does not have the expected type
+    pub(crate) fn wrong_value_type(expected: ValueType, found: ValueType) -> UnstashError {
+        UnstashError {
+            expected: Some(expected),
+            found: Some(found),
+            ..UnstashError::new(UnstashErrorKind::WrongValueType)
+        }
+    }
+
+    /// Get the kind of error
+    pub fn kind(&self) -> UnstashErrorKind {
+        self.kind
+    }
+
+    /// Get the hash of the stashed object in which the error happened,
+    /// if the error happened while unstashing an object
+    pub fn object(&self) -> Option<ObjectHash> {
+        self.path.last().map(|entry| entry.hash)
+    }
+
+    /// Get the offset within the serialized bytes of the object in which
+    /// the error happened. This is the position of the value that failed
+    /// to be read, or of the first unread value if the object was not
+    /// finished.
+    pub fn offset(&self) -> Option<usize> {
+        self.offset
+    }
+
+    /// Get the type of value that was expected, if the error happened
+    /// because a value had the wrong type
+    pub fn expected(&self) -> Option<ValueType> {
+        self.expected
+    }
+
+    /// Get the type of value that was found, if the error happened
+    /// because a value had the wrong type
+    pub fn found(&self) -> Option<ValueType> {
+        self.found
+    }
+
+    /// Get the path of nested objects that were being unstashed when
+    /// the error happened, starting from the outermost object and
+    /// ending with the object in which the error happened
+    pub fn path(&self) -> &[UnstashPathEntry] {
+        &self.path
+    }
+
+    /// Record that the error passed through the given object while it
+    /// was being unstashed. The first object recorded is the one in
+    /// which the error happened, and the offset is only kept for it.
+    pub(crate) fn add_object(
+        mut self,
+        hash: ObjectHash,
+        label: Option<&'static str>,
+        offset: usize,
+    ) -> UnstashError {
+        if self.path.is_empty() {
+            self.offset = Some(offset);
+        }
+        self.path.insert(0, UnstashPathEntry { hash, label });
+        self
+    }
+}
+
+impl From<UnstashErrorKind> for UnstashError {
+    fn from(kind: UnstashErrorKind) -> UnstashError {
+        UnstashError::new(kind)
+    }
+}
+
+impl std::fmt::Display for UnstashError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.kind)?;
+        if let (Some(expected), Some(found)) = (self.expected, self.found) {
+            write!(f, " (expected {:?}, found {:?})", expected, found)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at byte {}", offset)?;
+        }
+        for (i, entry) in self.path.iter().enumerate() {
+            f.write_str(if i == 0 { " in " } else { " > " })?;
+            match entry.label {
+                Some(label) => write!(f, "{} #{}", label, entry.hash)?,
+                None => write!(f, "#{}", entry.hash)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for UnstashError {}
+
 /// Iterator over an array of primitives being unstashed
 pub struct PrimitiveIterator<'a, T> {
     data: &'a [u8],
@@ -53,7 +192,7 @@ impl<'a, T: PrimitiveReadWrite> ExactSizeIterator for PrimitiveIterator<'a, T> {
 
 /// Iterator over an array of [Unstashable] objects being unstashed
 pub struct ObjectIterator<'a, C, T> {
-    hashes: &'a [ObjectHash],
+    objects: ObjectRefs<'a>,
     stashmap: &'a StashMap,
     context: C,
     _phantom_data: PhantomData<T>,
@@ -63,34 +202,166 @@ impl<'a, C: Copy, T: Unstashable<C>> Iterator for ObjectIterator<'a, C, T> {
     type Item = Result<T, UnstashError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let Some((hash, remaining_hashes)) = self.hashes.split_first() else {
+        let Some(object) = self.objects.next() else {
             return None;
         };
-        self.hashes = remaining_hashes;
-        Some(self.stashmap.unstash(*hash, T::unstash, self.context))
+        Some(self.stashmap.unstash(object, T::unstash, self.context))
+    }
+}
+
+/// Iterator over the objects in an array of objects, each of which is
+/// either stashed separately or stored inline like a single object.
+/// Every element was already read once while reading past the array.
+#[derive(Clone, Copy)]
+pub(crate) struct ObjectRefs<'a> {
+    /// A backend positioned at the next element
+    elements: UnstasherBackend<'a>,
+    remaining: usize,
+}
+
+impl<'a> Iterator for ObjectRefs<'a> {
+    type Item = ObjectRef<'a>;
+
+    fn next(&mut self) -> Option<ObjectRef<'a>> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        // This can't fail since the element was read before
+        Some(self.elements.read_object_ref().unwrap())
     }
 }
 
+/// A reference to a single stashed object, which is either stored
+/// separately in the stashmap or inline within another object
+#[derive(Clone, Copy)]
+pub(crate) enum ObjectRef<'a> {
+    /// An object in the stashmap with the given hash
+    Stashed(ObjectHash),
+
+    /// An object with the given hash and serialized bytes, stored
+    /// inline and without any dependencies
+    Inline(ObjectHash, &'a [u8]),
+}
+
+impl<'a> ObjectRef<'a> {
+    /// Get the hash of the object
+    pub(crate) fn hash(&self) -> ObjectHash {
+        match self {
+            ObjectRef::Stashed(hash) => *hash,
+            ObjectRef::Inline(hash, _) => *hash,
+        }
+    }
+}
+
+/// A single stashed value of any type, read without knowing
+/// the Rust type of the object that stashed it
+pub(crate) enum RawValue<'a> {
+    Primitive(PrimitiveValue),
+    Array(PrimitiveType, Vec<PrimitiveValue>),
+    String(&'a str),
+    Object(ObjectRef<'a>),
+    ArrayOfObjects(ObjectRefs<'a>),
+}
+
 /// The backend for both an [Unstasher] and an [InplaceUnstasher]
 #[derive(Copy, Clone)]
 pub(crate) struct UnstasherBackend<'a> {
     bytes: &'a [u8],
     dependencies: &'a [ObjectHash],
     stashmap: &'a StashMap,
+    version: u32,
+
+    /// The fingerprint of the type name the object was stashed with, if any
+    type_fingerprint: Option<u64>,
+
+    /// The hash of the object being unstashed, for error reporting
+    hash: ObjectHash,
+
+    /// The total length of the object's serialized bytes, used
+    /// to find the offset of errors
+    total_len: usize,
+
+    /// The label given to the object being unstashed, if any
+    label: Option<&'static str>,
 }
 
 /// Private methods
 impl<'a> UnstasherBackend<'a> {
-    /// Create a new backend from a stashed object
-    pub(crate) fn from_stashed_object(
-        stashed_object: &'a StashedObject,
+    /// Create a new backend for reading the given object.
+    /// This method panics if the object is not inline and there
+    /// is no stashed object with its hash.
+    pub(crate) fn for_object(
+        object: ObjectRef<'a>,
         stashmap: &'a StashMap,
     ) -> UnstasherBackend<'a> {
-        UnstasherBackend {
-            bytes: &stashed_object.bytes,
-            dependencies: &stashed_object.dependencies,
+        let (hash, bytes, dependencies): (_, &[u8], &[ObjectHash]) = match object {
+            ObjectRef::Stashed(hash) => {
+                let stashed_object = stashmap.objects.get(&hash).unwrap();
+                (hash, &stashed_object.bytes, &stashed_object.dependencies)
+            }
+            ObjectRef::Inline(hash, bytes) => (hash, bytes, &[]),
+        };
+        let mut backend = UnstasherBackend {
+            bytes,
+            dependencies,
             stashmap,
+            version: 0,
+            type_fingerprint: None,
+            hash,
+            total_len: bytes.len(),
+            label: None,
+        };
+        // A version number can only appear first, and is read
+        // eagerly so that it can be queried at any time. If it
+        // is truncated, it is left in place to be reported as
+        // corrupted when the first value is read.
+        if let Some((&tag, rest)) = backend.bytes.split_first() {
+            if ValueType::from_byte(tag) == Ok(ValueType::Version) && rest.len() >= u32::SIZE {
+                backend.bytes = rest;
+                backend.version = u32::read_raw_bytes_from(&mut backend.bytes);
+            }
+        }
+        // Likewise for the type fingerprint, which follows the version
+        if let Some((&tag, rest)) = backend.bytes.split_first() {
+            if ValueType::from_byte(tag) == Ok(ValueType::TypeFingerprint)
+                && rest.len() >= u64::SIZE
+            {
+                backend.bytes = rest;
+                backend.type_fingerprint = Some(u64::read_raw_bytes_from(&mut backend.bytes));
+            }
         }
+        backend
+    }
+
+    /// Get the version number of the object being unstashed
+    pub(crate) fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Get the type fingerprint of the object being unstashed, if any
+    pub(crate) fn type_fingerprint(&self) -> Option<u64> {
+        self.type_fingerprint
+    }
+
+    /// Check that the object being unstashed was stashed with the
+    /// given type name
+    pub(crate) fn check_type_name(&self, name: &str) -> Result<(), UnstashError> {
+        if self.type_fingerprint != Some(type_fingerprint(name)) {
+            return Err(UnstashErrorKind::TypeMismatch.into());
+        }
+        Ok(())
+    }
+
+    /// Label the object being unstashed for error reporting
+    pub(crate) fn set_label(&mut self, label: &'static str) {
+        self.label = Some(label);
+    }
+
+    /// Record in the given error that it happened while unstashing
+    /// this object, at the current position in its serialized bytes
+    pub(crate) fn add_error_context(&self, err: UnstashError) -> UnstashError {
+        err.add_object(self.hash, self.label, self.total_len - self.bytes.len())
     }
 
     /// Have all serialized contents and dependencies been read?
@@ -99,12 +370,12 @@ impl<'a> UnstasherBackend<'a> {
     }
 
     /// Read a sequence of raw bytes
-    pub(crate) fn read_raw_bytes(&mut self, len: usize) -> Result<&[u8], UnstashError> {
+    pub(crate) fn read_raw_bytes(&mut self, len: usize) -> Result<&'a [u8], UnstashError> {
         if let Some((head, rest)) = self.bytes.split_at_checked(len) {
             self.bytes = rest;
             Ok(head)
         } else {
-            Err(UnstashError::Corrupted)
+            Err(UnstashErrorKind::Corrupted.into())
         }
     }
 
@@ -120,13 +391,16 @@ impl<'a> UnstasherBackend<'a> {
             self.bytes = rest;
             Ok(b)
         } else {
-            Err(UnstashError::OutOfData)
+            Err(UnstashErrorKind::OutOfData.into())
         }
     }
 
     /// Read the next byte without advancing past it
     fn peek_byte(&self) -> Result<u8, UnstashError> {
-        self.bytes.first().cloned().ok_or(UnstashError::OutOfData)
+        self.bytes
+            .first()
+            .cloned()
+            .ok_or(UnstashError::new(UnstashErrorKind::OutOfData))
     }
 
     /// Read a sequence of bytes without advancing
@@ -134,7 +408,7 @@ impl<'a> UnstasherBackend<'a> {
         if let Some((head, _)) = self.bytes.split_at_checked(len) {
             Ok(head)
         } else {
-            Err(UnstashError::OutOfData)
+            Err(UnstashErrorKind::OutOfData.into())
         }
     }
 
@@ -143,21 +417,57 @@ impl<'a> UnstasherBackend<'a> {
         ValueType::from_byte(self.read_byte()?)
     }
 
-    /// Read the 32-bit length at the next four bytes.
-    /// This assumes that we are in the middle of reading
-    /// a value type with a prefixed length.
+    /// Read the [ValueType] at the next byte and check that it is the
+    /// expected type
+    fn expect_value_type(&mut self, expected: ValueType) -> Result<(), UnstashError> {
+        let found = self.read_value_type()?;
+        if found != expected {
+            return Err(UnstashError::wrong_value_type(expected, found));
+        }
+        Ok(())
+    }
+
+    /// Read the length at the next four bytes, or the next twelve
+    /// if it is escaped. This assumes that we are in the middle of
+    /// reading a value type with a prefixed length.
     fn read_value_length(&mut self) -> Result<usize, UnstashError> {
-        if self.remaining_len() < u32::SIZE {
-            return Err(UnstashError::Corrupted);
+        read_length_from(&mut self.bytes)
+    }
+
+    /// Read a reference to the next object, which is either a dependency
+    /// or an object stored inline
+    fn read_object_ref(&mut self) -> Result<ObjectRef<'a>, UnstashError> {
+        if self.peek_byte()? == INLINE_OBJECT_TAG {
+            self.read_byte()?;
+            let hash = self.read_raw_bytes(ObjectHash::SIZE)?;
+            let hash = ObjectHash::from_be_bytes(hash.try_into().unwrap());
+            let len = self.read_value_length()?;
+            let bytes = self.read_raw_bytes(len)?;
+            return Ok(ObjectRef::Inline(hash, bytes));
+        }
+        self.expect_value_type(ValueType::StashedObject)?;
+        Ok(ObjectRef::Stashed(self.read_dependency()?))
+    }
+
+    /// Read an array of objects, reading past every element so that
+    /// they can then be iterated over without failing
+    fn read_array_of_object_refs(&mut self) -> Result<ObjectRefs<'a>, UnstashError> {
+        self.expect_value_type(ValueType::ArrayOfObjects)?;
+        let len = self.read_value_length()?;
+        let elements = *self;
+        for _ in 0..len {
+            self.read_object_ref()?;
         }
-        let len = u32::read_raw_bytes_from(&mut self.bytes);
-        Ok(len as usize)
+        Ok(ObjectRefs {
+            elements,
+            remaining: len,
+        })
     }
 
     /// Read the hash of the next dependency
     fn read_dependency(&mut self) -> Result<ObjectHash, UnstashError> {
         let Some((hash, remaining_hashes)) = self.dependencies.split_first() else {
-            return Err(UnstashError::Corrupted);
+            return Err(UnstashErrorKind::Corrupted.into());
         };
         self.dependencies = remaining_hashes;
         Ok(*hash)
@@ -183,13 +493,113 @@ impl<'a> UnstasherBackend<'a> {
         result
     }
 
+    /// Read the next value, whatever its type
+    pub(crate) fn read_raw_value(&mut self) -> Result<RawValue<'a>, UnstashError> {
+        let original = *self;
+        self.reset_on_error(
+            |unstasher, _| match unstasher.read_value_type()? {
+                ValueType::Primitive(prim_type) => Ok(RawValue::Primitive(
+                    PrimitiveValue::read_raw_bytes_from(prim_type, &mut unstasher.bytes)?,
+                )),
+                ValueType::Array(prim_type) => {
+                    let len = unstasher.read_value_length()?;
+                    let num_bytes = len
+                        .checked_mul(prim_type.size())
+                        .ok_or(UnstashError::new(UnstashErrorKind::Corrupted))?;
+                    if unstasher.remaining_len() < num_bytes {
+                        return Err(UnstashErrorKind::Corrupted.into());
+                    }
+                    let mut values = Vec::with_capacity(len);
+                    for _ in 0..len {
+                        values.push(PrimitiveValue::read_raw_bytes_from(
+                            prim_type,
+                            &mut unstasher.bytes,
+                        )?);
+                    }
+                    Ok(RawValue::Array(prim_type, values))
+                }
+                ValueType::String => {
+                    let len = unstasher.read_value_length()?;
+                    let Some((slice, rest)) = unstasher.bytes.split_at_checked(len) else {
+                        return Err(UnstashErrorKind::Corrupted.into());
+                    };
+                    unstasher.bytes = rest;
+                    let s = std::str::from_utf8(slice)
+                        .map_err(|_| UnstashError::new(UnstashErrorKind::Corrupted))?;
+                    Ok(RawValue::String(s))
+                }
+                ValueType::StashedObject => {
+                    // Re-read the tag, which may be that of an inline object
+                    *unstasher = original;
+                    Ok(RawValue::Object(unstasher.read_object_ref()?))
+                }
+                ValueType::ArrayOfObjects => {
+                    *unstasher = original;
+                    Ok(RawValue::ArrayOfObjects(
+                        unstasher.read_array_of_object_refs()?,
+                    ))
+                }
+                ValueType::Version | ValueType::TypeFingerprint => {
+                    Err(UnstashErrorKind::Corrupted.into())
+                }
+            },
+            (),
+        )
+    }
+
+    /// Skip over the next value, whatever its type, without decoding
+    /// primitive arrays element by element or validating strings
+    pub(crate) fn skip_raw_value(&mut self) -> Result<(), UnstashError> {
+        self.reset_on_error(
+            |unstasher, _| match unstasher.peek_byte()? {
+                INLINE_OBJECT_TAG => unstasher.read_object_ref().map(|_| ()),
+                _ => match unstasher.read_value_type()? {
+                    ValueType::Primitive(prim_type) => unstasher.skip_bytes(prim_type.size()),
+                    ValueType::Array(prim_type) => {
+                        let len = unstasher.read_value_length()?;
+                        let num_bytes = len
+                            .checked_mul(prim_type.size())
+                            .ok_or(UnstashError::new(UnstashErrorKind::Corrupted))?;
+                        unstasher.skip_bytes(num_bytes)
+                    }
+                    ValueType::String => {
+                        let len = unstasher.read_value_length()?;
+                        unstasher.skip_bytes(len)
+                    }
+                    ValueType::StashedObject => unstasher.read_dependency().map(|_| ()),
+                    ValueType::ArrayOfObjects => {
+                        let len = unstasher.read_value_length()?;
+                        for _ in 0..len {
+                            unstasher.read_object_ref()?;
+                        }
+                        Ok(())
+                    }
+                    ValueType::Version | ValueType::TypeFingerprint => {
+                        Err(UnstashErrorKind::Corrupted.into())
+                    }
+                },
+            },
+            (),
+        )
+    }
+
+    /// Advance past the given number of bytes
+    fn skip_bytes(&mut self, num_bytes: usize) -> Result<(), UnstashError> {
+        let Some((_, rest)) = self.bytes.split_at_checked(num_bytes) else {
+            return Err(UnstashErrorKind::Corrupted.into());
+        };
+        self.bytes = rest;
+        Ok(())
+    }
+
     /// Read a single primitive, checking for its type tag first and then
     /// reading its value
     fn read_primitive<T: 'static + PrimitiveReadWrite>(&mut self) -> Result<T, UnstashError> {
         self.reset_on_error(
             |unstasher, _| {
-                if unstasher.read_value_type()? != ValueType::Primitive(T::TYPE) {
-                    return Err(UnstashError::WrongValueType);
+                unstasher.expect_value_type(ValueType::Primitive(T::TYPE))?;
+                if unstasher.remaining_len() < T::SIZE || !T::is_valid(unstasher.bytes) {
+                    return Err(UnstashErrorKind::Corrupted.into());
                 }
                 let x = T::read_raw_bytes_from(&mut unstasher.bytes);
                 Ok(x)
@@ -211,16 +621,20 @@ impl<'a> UnstasherBackend<'a> {
     ) -> Result<PrimitiveIterator<'a, T>, UnstashError> {
         self.reset_on_error(
             |unstasher, _| {
-                if unstasher.read_value_type()? != ValueType::Array(T::TYPE) {
-                    return Err(UnstashError::WrongValueType);
-                }
+                unstasher.expect_value_type(ValueType::Array(T::TYPE))?;
                 let len = unstasher.read_value_length()?;
-                let num_bytes = len * T::SIZE;
+                let num_bytes = len
+                    .checked_mul(T::SIZE)
+                    .ok_or(UnstashError::new(UnstashErrorKind::Corrupted))?;
                 if unstasher.remaining_len() < num_bytes {
-                    return Err(UnstashError::Corrupted);
+                    return Err(UnstashErrorKind::Corrupted.into());
+                }
+                let data = &unstasher.bytes[..num_bytes];
+                if !data.chunks_exact(T::SIZE).all(T::is_valid) {
+                    return Err(UnstashErrorKind::Corrupted.into());
                 }
                 let iterator = PrimitiveIterator {
-                    data: &unstasher.bytes[..num_bytes],
+                    data,
                     _phantom_data: PhantomData,
                 };
                 unstasher.bytes = &unstasher.bytes[num_bytes..];
@@ -230,6 +644,17 @@ impl<'a> UnstasherBackend<'a> {
         )
     }
 
+    /// Read an array of u8 values which must have exactly N elements
+    fn read_byte_array<const N: usize>(&mut self) -> Result<[u8; N], UnstashError> {
+        self.reset_on_error(
+            |unstasher, _| {
+                let iterator = unstasher.read_primitive_array_iter::<u8>()?;
+                <[u8; N]>::try_from(iterator.data).map_err(|_| UnstashErrorKind::BadValue.into())
+            },
+            (),
+        )
+    }
+
     /// Read an array of [Unstashable] objects into a vector
     fn read_array_of_object_vec<C: Copy, T: 'static + Unstashable<C>>(
         &mut self,
@@ -245,18 +670,9 @@ impl<'a> UnstasherBackend<'a> {
     ) -> Result<ObjectIterator<'a, C, T>, UnstashError> {
         self.reset_on_error(
             |unstasher, context| {
-                if unstasher.read_value_type()? != ValueType::ArrayOfObjects {
-                    return Err(UnstashError::WrongValueType);
-                }
-                let len = unstasher.read_value_length()?;
-
-                let Some((hashes, remaining_hashes)) = unstasher.dependencies.split_at_checked(len)
-                else {
-                    return Err(UnstashError::Corrupted);
-                };
-                unstasher.dependencies = remaining_hashes;
+                let objects = unstasher.read_array_of_object_refs()?;
                 let iter = ObjectIterator {
-                    hashes,
+                    objects,
                     stashmap: unstasher.stashmap,
                     context: context,
                     _phantom_data: PhantomData,
@@ -279,18 +695,32 @@ impl<'a> UnstasherBackend<'a> {
     ) -> Result<(), UnstashError> {
         self.reset_on_error(
             |unstasher, context| {
-                if unstasher.read_value_type()? != ValueType::ArrayOfObjects {
-                    return Err(UnstashError::WrongValueType);
+                for object in unstasher.read_array_of_object_refs()? {
+                    unstasher.stashmap.unstash(object, &mut f, context)?;
                 }
-                let len = unstasher.read_value_length()?;
+                Ok(())
+            },
+            context,
+        )
+    }
 
-                let Some((hashes, remaining_hashes)) = unstasher.dependencies.split_at_checked(len)
-                else {
-                    return Err(UnstashError::Corrupted);
-                };
-                unstasher.dependencies = remaining_hashes;
-                for hash in hashes {
-                    unstasher.stashmap.unstash(*hash, &mut f, context)?;
+    /// Read an array of objects via a given function that receives an
+    /// [InplaceUnstasher] with the given phase for each object
+    fn read_array_of_object_proxies_inplace<
+        Context: Copy,
+        F: FnMut(&mut InplaceUnstasher<Context>) -> Result<(), UnstashError>,
+    >(
+        &mut self,
+        mut f: F,
+        phase: InplaceUnstashPhase,
+        context: Context,
+    ) -> Result<(), UnstashError> {
+        self.reset_on_error(
+            |unstasher, context| {
+                for object in unstasher.read_array_of_object_refs()? {
+                    unstasher
+                        .stashmap
+                        .unstash_inplace(object, phase, &mut f, context)?;
                 }
                 Ok(())
             },
@@ -307,13 +737,9 @@ impl<'a> UnstasherBackend<'a> {
     ) -> Result<(), UnstashError> {
         self.reset_on_error(
             |unstasher, context| {
-                if unstasher.read_value_type()? != ValueType::StashedObject {
-                    return Err(UnstashError::WrongValueType);
-                }
-
-                let hash = unstasher.read_dependency()?;
+                let object_ref = unstasher.read_object_ref()?;
                 unstasher.stashmap.unstash_inplace(
-                    hash,
+                    object_ref,
                     phase,
                     |unstasher| object.unstash_inplace(unstasher),
                     context,
@@ -334,12 +760,8 @@ impl<'a> UnstasherBackend<'a> {
     {
         self.reset_on_error(
             |unstasher, context| {
-                if unstasher.read_value_type()? != ValueType::StashedObject {
-                    return Err(UnstashError::WrongValueType);
-                }
-
-                let hash = unstasher.read_dependency()?;
-                unstasher.stashmap.unstash(hash, f, context)
+                let object_ref = unstasher.read_object_ref()?;
+                unstasher.stashmap.unstash(object_ref, f, context)
             },
             context,
         )
@@ -357,28 +779,50 @@ impl<'a> UnstasherBackend<'a> {
     {
         self.reset_on_error(
             |unstasher, context| {
-                if unstasher.read_value_type()? != ValueType::StashedObject {
-                    return Err(UnstashError::WrongValueType);
-                }
+                let object_ref = unstasher.read_object_ref()?;
+                unstasher
+                    .stashmap
+                    .unstash_inplace(object_ref, phase, f, context)
+            },
+            context,
+        )
+    }
 
-                let hash = unstasher.read_dependency()?;
-                unstasher.stashmap.unstash_inplace(hash, phase, f, context)
+    /// Read a single object lazily, getting a handle to it without
+    /// unstashing it. Objects which are stored inline have no handle
+    /// of their own and are unstashed right away instead.
+    fn lazy<Context: Copy, T: 'static + Unstashable<Context>>(
+        &mut self,
+        context: Context,
+    ) -> Result<Lazy<T>, UnstashError> {
+        self.reset_on_error(
+            |unstasher, context| match unstasher.read_object_ref()? {
+                ObjectRef::Stashed(hash) => Ok(Lazy::from_handle(unstasher.stashmap.handle(hash))),
+                object_ref => Ok(Lazy::new(unstasher.stashmap.unstash(
+                    object_ref,
+                    T::unstash,
+                    context,
+                )?)),
             },
             context,
         )
     }
 
+    /// Read the hash of a single object without unstashing it
+    fn object_hash(&mut self) -> Result<ObjectHash, UnstashError> {
+        self.reset_on_error(|unstasher, _| Ok(unstasher.read_object_ref()?.hash()), ())
+    }
+
     /// Read a single string
     fn string(&mut self) -> Result<String, UnstashError> {
         self.reset_on_error(
             |unstasher, _| {
-                if unstasher.read_value_type()? != ValueType::String {
-                    return Err(UnstashError::WrongValueType);
-                }
+                unstasher.expect_value_type(ValueType::String)?;
                 let len = unstasher.read_value_length()?;
 
                 let slice = unstasher.read_raw_bytes(len)?;
-                let str_slice = std::str::from_utf8(slice).map_err(|_| UnstashError::Corrupted)?;
+                let str_slice = std::str::from_utf8(slice)
+                    .map_err(|_| UnstashError::new(UnstashErrorKind::Corrupted))?;
                 Ok(str_slice.to_string())
             },
             (),
@@ -393,15 +837,18 @@ impl<'a> UnstasherBackend<'a> {
     /// If the next type is an array, get the number of items
     /// If the next type is a string, get its length in bytes
     fn peek_length(&self) -> Result<usize, UnstashError> {
-        let bytes = self.peek_bytes(5)?;
+        let mut bytes = self.peek_bytes(5)?;
         let the_type = ValueType::from_byte(bytes[0])?;
         match the_type {
             ValueType::Array(_) => (),
             ValueType::String => (),
             ValueType::ArrayOfObjects => (),
-            _ => return Err(UnstashError::WrongValueType),
+            _ => return Err(UnstashErrorKind::WrongValueType.into()),
         }
-        Ok(u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]) as usize)
+        if u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]) == LONG_LENGTH_ESCAPE {
+            bytes = self.peek_bytes(5 + u64::SIZE)?;
+        }
+        read_length_from(&mut &bytes[1..])
     }
 
     /// Returns true iff there is no more data to read
@@ -427,6 +874,11 @@ impl<'a, Context> Unstasher<'a, Context> {
     pub(crate) fn backend(&self) -> &UnstasherBackend<'a> {
         &self.backend
     }
+
+    /// Read the hash of a single object without unstashing it
+    pub(crate) fn object_hash(&mut self) -> Result<ObjectHash, UnstashError> {
+        self.backend.object_hash()
+    }
 }
 
 impl<'a, Context: Copy> Unstasher<'a, Context> {
@@ -495,6 +947,21 @@ impl<'a, Context: Copy> Unstasher<'a, Context> {
         self.backend.read_primitive()
     }
 
+    /// Read a single u128 value
+    pub fn u128(&mut self) -> Result<u128, UnstashError> {
+        self.backend.read_primitive()
+    }
+
+    /// Read a single i128 value
+    pub fn i128(&mut self) -> Result<i128, UnstashError> {
+        self.backend.read_primitive()
+    }
+
+    /// Read a single char value
+    pub fn char(&mut self) -> Result<char, UnstashError> {
+        self.backend.read_primitive()
+    }
+
     /// Read an array of u8 values into a Vec
     pub fn array_of_u8_vec(&mut self) -> Result<Vec<u8>, UnstashError> {
         self.backend.read_primitive_array_vec()
@@ -545,6 +1012,21 @@ impl<'a, Context: Copy> Unstasher<'a, Context> {
         self.backend.read_primitive_array_vec()
     }
 
+    /// Read an array of u128 values into a Vec
+    pub fn array_of_u128_vec(&mut self) -> Result<Vec<u128>, UnstashError> {
+        self.backend.read_primitive_array_vec()
+    }
+
+    /// Read an array of i128 values into a Vec
+    pub fn array_of_i128_vec(&mut self) -> Result<Vec<i128>, UnstashError> {
+        self.backend.read_primitive_array_vec()
+    }
+
+    /// Read an array of char values into a Vec
+    pub fn array_of_char_vec(&mut self) -> Result<Vec<char>, UnstashError> {
+        self.backend.read_primitive_array_vec()
+    }
+
     /// Read an array of i8 values via an iterator
     pub fn array_of_i8_iter(&mut self) -> Result<PrimitiveIterator<'a, i8>, UnstashError> {
         self.backend.read_primitive_array_iter()
@@ -595,6 +1077,29 @@ impl<'a, Context: Copy> Unstasher<'a, Context> {
         self.backend.read_primitive_array_iter()
     }
 
+    /// Read an array of u128 values via an iterator
+    pub fn array_of_u128_iter(&mut self) -> Result<PrimitiveIterator<'a, u128>, UnstashError> {
+        self.backend.read_primitive_array_iter()
+    }
+
+    /// Read an array of i128 values via an iterator
+    pub fn array_of_i128_iter(&mut self) -> Result<PrimitiveIterator<'a, i128>, UnstashError> {
+        self.backend.read_primitive_array_iter()
+    }
+
+    /// Read an array of char values via an iterator
+    pub fn array_of_char_iter(&mut self) -> Result<PrimitiveIterator<'a, char>, UnstashError> {
+        self.backend.read_primitive_array_iter()
+    }
+
+    /// Read a fixed-size blob of bytes as written by
+    /// [crate::Stasher::byte_array]. An error of kind
+    /// [UnstashErrorKind::BadValue] is returned if the stashed
+    /// array of u8 values does not have exactly N elements.
+    pub fn byte_array<const N: usize>(&mut self) -> Result<[u8; N], UnstashError> {
+        self.backend.read_byte_array()
+    }
+
     /// Read an array of [Unstashable] objects into a vector
     pub fn array_of_objects_vec<T: 'static + Unstashable<Context>>(
         &mut self,
@@ -659,6 +1164,13 @@ impl<'a, Context: Copy> Unstasher<'a, Context> {
         self.backend.object_proxy(T::unstash, context)
     }
 
+    /// Read a single object lazily. See [Lazy].
+    pub(crate) fn lazy<T: 'static + Unstashable<Context>>(
+        &mut self,
+    ) -> Result<Lazy<T>, UnstashError> {
+        self.backend.lazy(self.context)
+    }
+
     /// Read a single [UnstashableInplace] object
     pub fn object_inplace<T: UnstashableInplace<Context>>(
         &mut self,
@@ -744,6 +1256,59 @@ impl<'a, Context: Copy> Unstasher<'a, Context> {
         self.backend.is_empty()
     }
 
+    /// Label the object being unstashed, such as with the name of its
+    /// type. If an error happens while unstashing the object or any
+    /// object nested within it, the label appears in the error's
+    /// [UnstashError::path].
+    pub fn label(&mut self, label: &'static str) {
+        self.backend.set_label(label);
+    }
+
+    /// Get the version number that the object was stashed with using
+    /// [crate::Stasher::version], or 0 if it was stashed without one
+    pub fn version(&self) -> u32 {
+        self.backend.version()
+    }
+
+    /// Check that the object was stashed with the given type name using
+    /// [crate::Stasher::type_name], and return an error of kind
+    /// [UnstashErrorKind::TypeMismatch] if it was stashed with a
+    /// different type name or without one.
+    pub fn type_name(&self, name: &str) -> Result<(), UnstashError> {
+        self.backend.check_type_name(name)
+    }
+
+    /// Read and discard the next value, whatever its type, along
+    /// with any objects it refers to. This allows values which are
+    /// no longer needed to be skipped over in older stashed data.
+    pub fn skip_value(&mut self) -> Result<(), UnstashError> {
+        self.backend.skip_raw_value()
+    }
+
+    /// Read an optional trailing value using the given function,
+    /// or return None if there is no data left. This allows values
+    /// that were added to the end of an object to be read from
+    /// older stashed data which doesn't contain them.
+    pub fn optional<T, F>(&mut self, f: F) -> Result<Option<T>, UnstashError>
+    where
+        F: FnOnce(&mut Self) -> Result<T, UnstashError>,
+    {
+        if self.backend.is_empty() {
+            return Ok(None);
+        }
+        f(self).map(Some)
+    }
+
+    /// Read an optional trailing value using the given function,
+    /// or return the given default if there is no data left.
+    /// See [Self::optional].
+    pub fn optional_or<T, F>(&mut self, default: T, f: F) -> Result<T, UnstashError>
+    where
+        F: FnOnce(&mut Self) -> Result<T, UnstashError>,
+    {
+        Ok(self.optional(f)?.unwrap_or(default))
+    }
+
     pub fn context(&self) -> Context {
         self.context
     }
@@ -903,6 +1468,24 @@ impl<'a, Context: Copy> InplaceUnstasher<'a, Context> {
         self.read_primitive_inplace(x)
     }
 
+    /// Read a single u128 value. The reference is only written
+    /// to during the Write phase.
+    pub fn u128_inplace(&mut self, x: &mut u128) -> Result<(), UnstashError> {
+        self.read_primitive_inplace(x)
+    }
+
+    /// Read a single i128 value. The reference is only written
+    /// to during the Write phase.
+    pub fn i128_inplace(&mut self, x: &mut i128) -> Result<(), UnstashError> {
+        self.read_primitive_inplace(x)
+    }
+
+    /// Read a single char value. The reference is only written
+    /// to during the Write phase.
+    pub fn char_inplace(&mut self, x: &mut char) -> Result<(), UnstashError> {
+        self.read_primitive_inplace(x)
+    }
+
     /// Read a single bool value directly.
     /// Lasting modifications to data structures should only be made
     /// when [Self::time_to_write] returns `true`
@@ -994,6 +1577,27 @@ impl<'a, Context: Copy> InplaceUnstasher<'a, Context> {
         self.backend.read_primitive()
     }
 
+    /// Read a single u128 value directly.
+    /// Lasting modifications to data structures should only be made
+    /// when [Self::time_to_write] returns `true`
+    pub fn u128_always(&mut self) -> Result<u128, UnstashError> {
+        self.backend.read_primitive()
+    }
+
+    /// Read a single i128 value directly.
+    /// Lasting modifications to data structures should only be made
+    /// when [Self::time_to_write] returns `true`
+    pub fn i128_always(&mut self) -> Result<i128, UnstashError> {
+        self.backend.read_primitive()
+    }
+
+    /// Read a single char value directly.
+    /// Lasting modifications to data structures should only be made
+    /// when [Self::time_to_write] returns `true`
+    pub fn char_always(&mut self) -> Result<char, UnstashError> {
+        self.backend.read_primitive()
+    }
+
     /// Read an array of u8 values into a Vec. The reference is only written
     /// to during the Write phase. Existing contents are completely overwritten.
     pub fn array_of_u8_vec_inplace(&mut self, x: &mut Vec<u8>) -> Result<(), UnstashError> {
@@ -1054,6 +1658,44 @@ impl<'a, Context: Copy> InplaceUnstasher<'a, Context> {
         self.read_primitive_array_vec_inplace(x)
     }
 
+    /// Read an array of u128 values into a Vec. The reference is only written
+    /// to during the Write phase. Existing contents are completely overwritten.
+    pub fn array_of_u128_vec_inplace(&mut self, x: &mut Vec<u128>) -> Result<(), UnstashError> {
+        self.read_primitive_array_vec_inplace(x)
+    }
+
+    /// Read an array of i128 values into a Vec. The reference is only written
+    /// to during the Write phase. Existing contents are completely overwritten.
+    pub fn array_of_i128_vec_inplace(&mut self, x: &mut Vec<i128>) -> Result<(), UnstashError> {
+        self.read_primitive_array_vec_inplace(x)
+    }
+
+    /// Read an array of char values into a Vec. The reference is only written
+    /// to during the Write phase. Existing contents are completely overwritten.
+    pub fn array_of_char_vec_inplace(&mut self, x: &mut Vec<char>) -> Result<(), UnstashError> {
+        self.read_primitive_array_vec_inplace(x)
+    }
+
+    /// Read a fixed-size blob of bytes. The reference is only written to
+    /// during the Write phase. See [Unstasher::byte_array].
+    pub fn byte_array_inplace<const N: usize>(
+        &mut self,
+        x: &mut [u8; N],
+    ) -> Result<(), UnstashError> {
+        let bytes = self.backend.read_byte_array()?;
+        if self.phase == InplaceUnstashPhase::Write {
+            *x = bytes;
+        }
+        Ok(())
+    }
+
+    /// Read a fixed-size blob of bytes directly. See [Unstasher::byte_array].
+    /// Lasting modifications to data structures should only be made
+    /// when [Self::time_to_write] returns `true`
+    pub fn byte_array_always<const N: usize>(&mut self) -> Result<[u8; N], UnstashError> {
+        self.backend.read_byte_array()
+    }
+
     /// Read an array of [Unstashable] objects into a Vec. The reference is
     /// only written to during the Write phase. Existing contents are completely
     /// overwritten.
@@ -1150,6 +1792,27 @@ impl<'a, Context: Copy> InplaceUnstasher<'a, Context> {
         self.backend.read_primitive_array_iter()
     }
 
+    /// Read an array of u128 values via an iterator.
+    /// Lasting modifications to data structures should only be made
+    /// when [Self::time_to_write] returns `true`
+    pub fn array_of_u128_iter(&mut self) -> Result<PrimitiveIterator<'a, u128>, UnstashError> {
+        self.backend.read_primitive_array_iter()
+    }
+
+    /// Read an array of i128 values via an iterator.
+    /// Lasting modifications to data structures should only be made
+    /// when [Self::time_to_write] returns `true`
+    pub fn array_of_i128_iter(&mut self) -> Result<PrimitiveIterator<'a, i128>, UnstashError> {
+        self.backend.read_primitive_array_iter()
+    }
+
+    /// Read an array of char values via an iterator.
+    /// Lasting modifications to data structures should only be made
+    /// when [Self::time_to_write] returns `true`
+    pub fn array_of_char_iter(&mut self) -> Result<PrimitiveIterator<'a, char>, UnstashError> {
+        self.backend.read_primitive_array_iter()
+    }
+
     /// Read an array of objects and visit each with the given function that receives
     /// an [Unstasher] instance. This can be used to interface with more general kinds
     /// of containers and data structures at the cost of needing to know more about
@@ -1180,6 +1843,32 @@ impl<'a, Context: Copy> InplaceUnstasher<'a, Context> {
         self.backend.read_array_of_object_proxies(f, context)
     }
 
+    /// Read an array of objects and visit each with the given function that receives
+    /// an [InplaceUnstasher] instance with the same phase as the current one. This
+    /// allows existing elements of a container to be unstashed in place. Elements
+    /// that don't exist yet can be created using [Self::unstash_always].
+    ///
+    /// As with [Self::array_of_proxy_objects], data should always be read, but
+    /// modifications should only be made when [Self::time_to_write] returns `true`.
+    pub fn array_of_proxy_objects_inplace<F>(&mut self, f: F) -> Result<(), UnstashError>
+    where
+        F: FnMut(&mut InplaceUnstasher<Context>) -> Result<(), UnstashError>,
+    {
+        self.array_of_proxy_objects_inplace_with_context(f, self.context)
+    }
+
+    pub fn array_of_proxy_objects_inplace_with_context<OtherContext: Copy, F>(
+        &mut self,
+        f: F,
+        context: OtherContext,
+    ) -> Result<(), UnstashError>
+    where
+        F: FnMut(&mut InplaceUnstasher<OtherContext>) -> Result<(), UnstashError>,
+    {
+        self.backend
+            .read_array_of_object_proxies_inplace(f, self.phase, context)
+    }
+
     /// Read a string. The reference is only written to during the Write phase.
     /// Existing contents are completely overwritten.
     pub fn string_inplace(&mut self, x: &mut String) -> Result<(), UnstashError> {
@@ -1233,6 +1922,25 @@ impl<'a, Context: Copy> InplaceUnstasher<'a, Context> {
         self.backend.object_proxy(T::unstash, context)
     }
 
+    /// Read a single object lazily during both the validation and
+    /// write phases. See [Lazy].
+    pub(crate) fn lazy_always<T: 'static + Unstashable<Context>>(
+        &mut self,
+    ) -> Result<Lazy<T>, UnstashError> {
+        self.backend.lazy(self.context)
+    }
+
+    /// Create a new [Unstashable] object from the remaining contents of the
+    /// current object, as if [Unstashable::unstash] had been called instead,
+    /// during both the validation and write phases. Lasting changes should
+    /// only be made when [Self::time_to_write] is true.
+    pub fn unstash_always<T: Unstashable<Context>>(&mut self) -> Result<T, UnstashError> {
+        let mut unstasher = Unstasher::new(self.backend, self.context);
+        let object = T::unstash(&mut unstasher)?;
+        self.backend = unstasher.backend;
+        Ok(object)
+    }
+
     /// Read an object which is [UnstashableInplace]. The given reference is
     /// itself unstashed in place using the same phase as the current object.
     pub fn object_inplace<T: UnstashableInplace<Context>>(
@@ -1322,6 +2030,59 @@ impl<'a, Context: Copy> InplaceUnstasher<'a, Context> {
         self.backend.is_empty()
     }
 
+    /// Label the object being unstashed, such as with the name of its
+    /// type. If an error happens while unstashing the object or any
+    /// object nested within it, the label appears in the error's
+    /// [UnstashError::path].
+    pub fn label(&mut self, label: &'static str) {
+        self.backend.set_label(label);
+    }
+
+    /// Get the version number that the object was stashed with using
+    /// [crate::Stasher::version], or 0 if it was stashed without one
+    pub fn version(&self) -> u32 {
+        self.backend.version()
+    }
+
+    /// Check that the object was stashed with the given type name using
+    /// [crate::Stasher::type_name], and return an error of kind
+    /// [UnstashErrorKind::TypeMismatch] if it was stashed with a
+    /// different type name or without one.
+    pub fn type_name(&self, name: &str) -> Result<(), UnstashError> {
+        self.backend.check_type_name(name)
+    }
+
+    /// Read and discard the next value, whatever its type, along
+    /// with any objects it refers to. This allows values which are
+    /// no longer needed to be skipped over in older stashed data.
+    pub fn skip_value(&mut self) -> Result<(), UnstashError> {
+        self.backend.skip_raw_value()
+    }
+
+    /// Read an optional trailing value using the given function,
+    /// or return None if there is no data left. This allows values
+    /// that were added to the end of an object to be read from
+    /// older stashed data which doesn't contain them.
+    pub fn optional<T, F>(&mut self, f: F) -> Result<Option<T>, UnstashError>
+    where
+        F: FnOnce(&mut Self) -> Result<T, UnstashError>,
+    {
+        if self.backend.is_empty() {
+            return Ok(None);
+        }
+        f(self).map(Some)
+    }
+
+    /// Read an optional trailing value using the given function,
+    /// or return the given default if there is no data left.
+    /// See [Self::optional].
+    pub fn optional_or<T, F>(&mut self, default: T, f: F) -> Result<T, UnstashError>
+    where
+        F: FnOnce(&mut Self) -> Result<T, UnstashError>,
+    {
+        Ok(self.optional(f)?.unwrap_or(default))
+    }
+
     pub fn context(&self) -> Context {
         self.context
     }
@@ -1,7 +1,18 @@
-use crate::{stasher::Stasher, UnstashError};
+use crate::{stasher::Stasher, UnstashError, UnstashErrorKind};
 
-/// Enum for the set of primitive fixed-size types that are supported
-#[derive(PartialEq, Eq, Debug)]
+/// Enum for the set of primitive fixed-size types that are supported.
+///
+/// Every primitive is stashed as its big-endian bytes, so floating
+/// point numbers are stashed and hashed as their exact bits by default.
+/// This means that `0.0` and `-0.0`, as well as NaNs with different
+/// payloads, produce different hashes. Floats which are stashed using
+/// the `_canonical` methods of [crate::Stasher], such as
+/// [crate::Stasher::f32_canonical], are hashed as if every NaN were the
+/// quiet NaN with no payload (`0x7fc00000` or `0x7ff8000000000000`) and
+/// `-0.0` were `0.0`. Their exact bits are still serialized, and
+/// stashes which detect collisions compare them in the same canonical
+/// form as they are hashed.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum PrimitiveType {
     Bool,
     U8,
@@ -14,10 +25,15 @@ pub enum PrimitiveType {
     I64,
     F32,
     F64,
+    U128,
+    I128,
+
+    /// A unicode scalar value, stashed as its 32-bit code point
+    Char,
 }
 
 /// Enum for set the of value types that are supported
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub enum ValueType {
     /// A fixed-size primitive, e.g. boolean, integer, or floating point number
     Primitive(PrimitiveType),
@@ -33,11 +49,60 @@ pub enum ValueType {
 
     /// An array of objects elsewhere in the stash
     ArrayOfObjects,
+
+    /// The version number of an object, which may only appear
+    /// before all other values. See [crate::Stasher::version].
+    Version,
+
+    /// The fingerprint of an object's type name, which may only appear
+    /// first or right after the version number. See
+    /// [crate::Stasher::type_name].
+    TypeFingerprint,
+}
+
+/// A single primitive value of any of the supported primitive types,
+/// for working with stashed data without knowing its Rust type
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PrimitiveValue {
+    Bool(bool),
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+    U128(u128),
+    I128(i128),
+    Char(char),
 }
 
 impl PrimitiveType {
+    /// Returns the number of bytes occupied by a serialized value of this type
+    pub(crate) fn size(&self) -> usize {
+        match self {
+            PrimitiveType::Bool => bool::SIZE,
+            PrimitiveType::U8 => u8::SIZE,
+            PrimitiveType::I8 => i8::SIZE,
+            PrimitiveType::U16 => u16::SIZE,
+            PrimitiveType::I16 => i16::SIZE,
+            PrimitiveType::U32 => u32::SIZE,
+            PrimitiveType::I32 => i32::SIZE,
+            PrimitiveType::U64 => u64::SIZE,
+            PrimitiveType::I64 => i64::SIZE,
+            PrimitiveType::F32 => f32::SIZE,
+            PrimitiveType::F64 => f64::SIZE,
+            PrimitiveType::U128 => u128::SIZE,
+            PrimitiveType::I128 => i128::SIZE,
+            PrimitiveType::Char => char::SIZE,
+        }
+    }
+
     /// Returns an integer with value 0xF or less, used to uniquely tag each primitive type
-    fn to_nibble(&self) -> u8 {
+    fn to_nibble(self) -> u8 {
         match self {
             PrimitiveType::Bool => 0x01,
             PrimitiveType::U8 => 0x02,
@@ -50,6 +115,9 @@ impl PrimitiveType {
             PrimitiveType::I64 => 0x09,
             PrimitiveType::F32 => 0x0A,
             PrimitiveType::F64 => 0x0B,
+            PrimitiveType::U128 => 0x0C,
+            PrimitiveType::I128 => 0x0D,
+            PrimitiveType::Char => 0x0E,
         }
     }
 
@@ -67,20 +135,107 @@ impl PrimitiveType {
             0x09 => Ok(PrimitiveType::I64),
             0x0A => Ok(PrimitiveType::F32),
             0x0B => Ok(PrimitiveType::F64),
-            _ => Err(UnstashError::Corrupted),
+            0x0C => Ok(PrimitiveType::U128),
+            0x0D => Ok(PrimitiveType::I128),
+            0x0E => Ok(PrimitiveType::Char),
+            _ => Err(UnstashErrorKind::Corrupted.into()),
+        }
+    }
+}
+
+impl PrimitiveValue {
+    /// Get the type of the value
+    pub fn primitive_type(&self) -> PrimitiveType {
+        match self {
+            PrimitiveValue::Bool(_) => PrimitiveType::Bool,
+            PrimitiveValue::U8(_) => PrimitiveType::U8,
+            PrimitiveValue::I8(_) => PrimitiveType::I8,
+            PrimitiveValue::U16(_) => PrimitiveType::U16,
+            PrimitiveValue::I16(_) => PrimitiveType::I16,
+            PrimitiveValue::U32(_) => PrimitiveType::U32,
+            PrimitiveValue::I32(_) => PrimitiveType::I32,
+            PrimitiveValue::U64(_) => PrimitiveType::U64,
+            PrimitiveValue::I64(_) => PrimitiveType::I64,
+            PrimitiveValue::F32(_) => PrimitiveType::F32,
+            PrimitiveValue::F64(_) => PrimitiveType::F64,
+            PrimitiveValue::U128(_) => PrimitiveType::U128,
+            PrimitiveValue::I128(_) => PrimitiveType::I128,
+            PrimitiveValue::Char(_) => PrimitiveType::Char,
+        }
+    }
+
+    /// Returns true if both values have the same type and the same
+    /// serialized representation. Unlike `==`, this considers NaNs
+    /// with the same bits to be identical.
+    pub fn identical(&self, other: &PrimitiveValue) -> bool {
+        match (self, other) {
+            (PrimitiveValue::F32(a), PrimitiveValue::F32(b)) => a.to_bits() == b.to_bits(),
+            (PrimitiveValue::F64(a), PrimitiveValue::F64(b)) => a.to_bits() == b.to_bits(),
+            _ => self == other,
+        }
+    }
+
+    /// Read a value of the given type from the byte slice, moving it forward
+    pub(crate) fn read_raw_bytes_from(
+        prim_type: PrimitiveType,
+        bytes: &mut &[u8],
+    ) -> Result<PrimitiveValue, UnstashError> {
+        if bytes.len() < prim_type.size()
+            || (prim_type == PrimitiveType::Char && !char::is_valid(bytes))
+        {
+            return Err(UnstashErrorKind::Corrupted.into());
+        }
+        Ok(match prim_type {
+            PrimitiveType::Bool => PrimitiveValue::Bool(bool::read_raw_bytes_from(bytes)),
+            PrimitiveType::U8 => PrimitiveValue::U8(u8::read_raw_bytes_from(bytes)),
+            PrimitiveType::I8 => PrimitiveValue::I8(i8::read_raw_bytes_from(bytes)),
+            PrimitiveType::U16 => PrimitiveValue::U16(u16::read_raw_bytes_from(bytes)),
+            PrimitiveType::I16 => PrimitiveValue::I16(i16::read_raw_bytes_from(bytes)),
+            PrimitiveType::U32 => PrimitiveValue::U32(u32::read_raw_bytes_from(bytes)),
+            PrimitiveType::I32 => PrimitiveValue::I32(i32::read_raw_bytes_from(bytes)),
+            PrimitiveType::U64 => PrimitiveValue::U64(u64::read_raw_bytes_from(bytes)),
+            PrimitiveType::I64 => PrimitiveValue::I64(i64::read_raw_bytes_from(bytes)),
+            PrimitiveType::F32 => PrimitiveValue::F32(f32::read_raw_bytes_from(bytes)),
+            PrimitiveType::F64 => PrimitiveValue::F64(f64::read_raw_bytes_from(bytes)),
+            PrimitiveType::U128 => PrimitiveValue::U128(u128::read_raw_bytes_from(bytes)),
+            PrimitiveType::I128 => PrimitiveValue::I128(i128::read_raw_bytes_from(bytes)),
+            PrimitiveType::Char => PrimitiveValue::Char(char::read_raw_bytes_from(bytes)),
+        })
+    }
+}
+
+impl std::fmt::Display for PrimitiveValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrimitiveValue::Bool(x) => write!(f, "{}", x),
+            PrimitiveValue::U8(x) => write!(f, "{}_u8", x),
+            PrimitiveValue::I8(x) => write!(f, "{}_i8", x),
+            PrimitiveValue::U16(x) => write!(f, "{}_u16", x),
+            PrimitiveValue::I16(x) => write!(f, "{}_i16", x),
+            PrimitiveValue::U32(x) => write!(f, "{}_u32", x),
+            PrimitiveValue::I32(x) => write!(f, "{}_i32", x),
+            PrimitiveValue::U64(x) => write!(f, "{}_u64", x),
+            PrimitiveValue::I64(x) => write!(f, "{}_i64", x),
+            PrimitiveValue::F32(x) => write!(f, "{:?}_f32", x),
+            PrimitiveValue::F64(x) => write!(f, "{:?}_f64", x),
+            PrimitiveValue::U128(x) => write!(f, "{}_u128", x),
+            PrimitiveValue::I128(x) => write!(f, "{}_i128", x),
+            PrimitiveValue::Char(x) => write!(f, "{:?}", x),
         }
     }
 }
 
 impl ValueType {
     /// Returns an integer used to uniquely tag each value type
-    pub(crate) fn to_byte(&self) -> u8 {
+    pub(crate) fn to_byte(self) -> u8 {
         match self {
             ValueType::Primitive(prim_type) => 0x00 | prim_type.to_nibble(),
             ValueType::Array(prim_type) => 0x10 | prim_type.to_nibble(),
             ValueType::String => 0x20,
             ValueType::StashedObject => 0x30,
             ValueType::ArrayOfObjects => 0x40,
+            ValueType::Version => 0x50,
+            ValueType::TypeFingerprint => 0x60,
         }
     }
 
@@ -92,11 +247,177 @@ impl ValueType {
             0x00 => Ok(ValueType::Primitive(PrimitiveType::from_nibble(lo_nibble)?)),
             0x10 => Ok(ValueType::Array(PrimitiveType::from_nibble(lo_nibble)?)),
             0x20 => Ok(ValueType::String),
+            // Includes objects stored inline, see INLINE_OBJECT_TAG
             0x30 => Ok(ValueType::StashedObject),
             0x40 => Ok(ValueType::ArrayOfObjects),
-            _ => Err(UnstashError::Corrupted),
+            0x50 => Ok(ValueType::Version),
+            0x60 => Ok(ValueType::TypeFingerprint),
+            _ => Err(UnstashErrorKind::Corrupted.into()),
+        }
+    }
+}
+
+/// The tag of a small object which is stored inline, followed by its hash,
+/// its 32-bit length and its serialized bytes, instead of being stashed
+/// separately as a dependency. Inline objects have no dependencies and are
+/// reported as [ValueType::StashedObject], since they are unstashed the same.
+pub(crate) const INLINE_OBJECT_TAG: u8 = 0x31;
+
+/// Get the stable 64-bit fingerprint of a type name, as stashed
+/// by [crate::Stasher::type_name]
+pub(crate) fn type_fingerprint(name: &str) -> u64 {
+    seahash::hash(name.as_bytes())
+}
+
+/// The 32-bit length prefix of a sequence which signals that the actual
+/// length follows as a 64-bit integer, because it is at least 2^32 - 1.
+/// Shorter sequences only use the 32-bit prefix, so that their hashes and
+/// serialized contents are unaffected.
+pub(crate) const LONG_LENGTH_ESCAPE: u32 = u32::MAX;
+
+/// Write the length prefix of a sequence whose length is known up front
+/// to the end of the serialized bytes, escaping it if needed
+pub(crate) fn write_length_to(data: &mut Vec<u8>, length: usize) {
+    match u32::try_from(length) {
+        Ok(length) if length != LONG_LENGTH_ESCAPE => data.extend_from_slice(&length.to_be_bytes()),
+        _ => {
+            data.extend_from_slice(&LONG_LENGTH_ESCAPE.to_be_bytes());
+            data.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+}
+
+/// Write the length prefix of a sequence at the given position in the
+/// serialized bytes, which must hold a 32-bit placeholder. If the length
+/// needs to be escaped, its 64-bit value is inserted after the placeholder,
+/// which moves everything after it.
+pub(crate) fn write_length_at(data: &mut Vec<u8>, position: usize, length: usize) {
+    match u32::try_from(length) {
+        Ok(length) if length != LONG_LENGTH_ESCAPE => {
+            data[position..position + u32::SIZE].copy_from_slice(&length.to_be_bytes());
+        }
+        _ => {
+            data[position..position + u32::SIZE].copy_from_slice(&LONG_LENGTH_ESCAPE.to_be_bytes());
+            let end = position + u32::SIZE;
+            data.splice(end..end, (length as u64).to_be_bytes());
+        }
+    }
+}
+
+/// Read the length prefix of a sequence from the byte slice, moving it
+/// forward. See [LONG_LENGTH_ESCAPE].
+pub(crate) fn read_length_from(bytes: &mut &[u8]) -> Result<usize, UnstashError> {
+    if bytes.len() < u32::SIZE {
+        return Err(UnstashErrorKind::Corrupted.into());
+    }
+    let length = u32::read_raw_bytes_from(bytes);
+    if length != LONG_LENGTH_ESCAPE {
+        return Ok(length as usize);
+    }
+    if bytes.len() < u64::SIZE {
+        return Err(UnstashErrorKind::Corrupted.into());
+    }
+    usize::try_from(u64::read_raw_bytes_from(bytes)).map_err(|_| UnstashErrorKind::Corrupted.into())
+}
+
+/// Helper trait for floating point primitives which can be canonicalized
+/// for hashing. See [PrimitiveType].
+pub(crate) trait CanonicalFloat: PrimitiveReadWrite + Copy {
+    /// Get the canonical form of the value, which is the quiet NaN with
+    /// no payload for any NaN, `0.0` for `-0.0`, and the value itself
+    /// for everything else
+    fn canonical(self) -> Self;
+
+    /// Do the values have the same canonical form?
+    fn canonically_eq(self, other: Self) -> bool;
+}
+
+impl CanonicalFloat for f32 {
+    fn canonical(self) -> f32 {
+        if self.is_nan() {
+            f32::from_bits(0x7fc0_0000)
+        } else if self == 0.0 {
+            0.0
+        } else {
+            self
+        }
+    }
+
+    fn canonically_eq(self, other: f32) -> bool {
+        self.canonical().to_bits() == other.canonical().to_bits()
+    }
+}
+
+impl CanonicalFloat for f64 {
+    fn canonical(self) -> f64 {
+        if self.is_nan() {
+            f64::from_bits(0x7ff8_0000_0000_0000)
+        } else if self == 0.0 {
+            0.0
+        } else {
+            self
+        }
+    }
+
+    fn canonically_eq(self, other: f64) -> bool {
+        self.canonical().to_bits() == other.canonical().to_bits()
+    }
+}
+
+/// A run of consecutive floats written by the `_canonical` methods of
+/// [Stasher], located within an object's serialized bytes so that
+/// collision detection can compare them in their canonical form
+#[derive(Clone, Copy)]
+pub(crate) struct CanonicalFloats {
+    /// The offset of the first float's bytes
+    pub(crate) offset: usize,
+
+    /// The number of floats
+    pub(crate) count: usize,
+
+    /// Either [PrimitiveType::F32] or [PrimitiveType::F64]
+    pub(crate) float_type: PrimitiveType,
+}
+
+impl CanonicalFloats {
+    /// The offset just past the last float's bytes
+    fn end(&self) -> usize {
+        self.offset + self.count * self.float_type.size()
+    }
+}
+
+/// Compare the floats in two byte slices of the same length canonically
+fn floats_canonically_eq<T: CanonicalFloat>(a: &[u8], b: &[u8]) -> bool {
+    a.chunks_exact(T::SIZE)
+        .zip(b.chunks_exact(T::SIZE))
+        .all(|(mut x, mut y)| {
+            T::read_raw_bytes_from(&mut x).canonically_eq(T::read_raw_bytes_from(&mut y))
+        })
+}
+
+/// Compare the serialized bytes of two objects, where the given runs of
+/// floats, which must be in order, are compared in their canonical form
+/// and all other bytes must be identical
+pub(crate) fn bytes_canonically_eq(a: &[u8], b: &[u8], floats: &[CanonicalFloats]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut position = 0;
+    for floats in floats {
+        let (start, end) = (floats.offset, floats.end());
+        if a[position..start] != b[position..start] {
+            return false;
         }
+        let equal = match floats.float_type {
+            PrimitiveType::F32 => floats_canonically_eq::<f32>(&a[start..end], &b[start..end]),
+            _ => floats_canonically_eq::<f64>(&a[start..end], &b[start..end]),
+        };
+        if !equal {
+            return false;
+        }
+        position = end;
     }
+    a[position..] == b[position..]
 }
 
 /// Helper trait for serializing primitives directly
@@ -111,8 +432,16 @@ pub(crate) trait PrimitiveReadWrite {
     fn write_raw_bytes_to<Context>(&self, stasher: &mut Stasher<Context>);
 
     /// Read self from the byte slice, moving it forward.
-    /// This method may panic if there are fewer than Self::SIZE bytes remaining
+    /// This method may panic if there are fewer than Self::SIZE bytes remaining,
+    /// or if they do not hold a valid value according to [Self::is_valid]
     fn read_raw_bytes_from(bytes: &mut &[u8]) -> Self;
+
+    /// Returns true if the first Self::SIZE bytes of the byte slice hold
+    /// a valid value. This is only false for types which don't allow every
+    /// bit pattern, and may panic if there are fewer than Self::SIZE bytes.
+    fn is_valid(_bytes: &[u8]) -> bool {
+        true
+    }
 }
 
 /// Macro for implementing the PrimitiveReadWrite helper trait for a given
@@ -146,6 +475,8 @@ impl_primitive_read_write!(u64, 8, PrimitiveType::U64);
 impl_primitive_read_write!(i64, 8, PrimitiveType::I64);
 impl_primitive_read_write!(f32, 4, PrimitiveType::F32);
 impl_primitive_read_write!(f64, 8, PrimitiveType::F64);
+impl_primitive_read_write!(u128, 16, PrimitiveType::U128);
+impl_primitive_read_write!(i128, 16, PrimitiveType::I128);
 
 /// Explicit implementation of PrimitiveReadWrite for bool,
 /// which does not have from_be_bytes() / to_be_bytes()
@@ -163,3 +494,23 @@ impl PrimitiveReadWrite for bool {
         *byte == 1
     }
 }
+
+/// Explicit implementation of PrimitiveReadWrite for char, which is
+/// stashed as a u32 code point and which not every u32 is valid for
+impl PrimitiveReadWrite for char {
+    const SIZE: usize = 4;
+    const TYPE: PrimitiveType = PrimitiveType::Char;
+
+    fn write_raw_bytes_to<Context>(&self, stasher: &mut Stasher<Context>) {
+        u32::from(*self).write_raw_bytes_to(stasher);
+    }
+
+    fn read_raw_bytes_from(bytes: &mut &[u8]) -> char {
+        char::from_u32(u32::read_raw_bytes_from(bytes)).unwrap()
+    }
+
+    fn is_valid(bytes: &[u8]) -> bool {
+        let mut bytes = bytes;
+        char::from_u32(u32::read_raw_bytes_from(&mut bytes)).is_some()
+    }
+}